@@ -1,9 +1,36 @@
--- create regions table
-CREATE TABLE regions (id INT PRIMARY KEY, name VARCHAR(50));
+-- create regions table, a region only known from its orders has no name yet
+CREATE TABLE regions (
+    id INT PRIMARY KEY,
+    name VARCHAR(50),
+    parent_id INT REFERENCES regions (id) CHECK (parent_id <> id),
+    created_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+    updated_at TIMESTAMPTZ NOT NULL DEFAULT now()
+);
+-- create gifts table
+CREATE TABLE gifts (
+    id SERIAL PRIMARY KEY,
+    name VARCHAR(50) NOT NULL UNIQUE,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT now()
+);
 -- create orders table
 CREATE TABLE orders (
     id INT PRIMARY KEY,
-    region_id INT,
-    gift_name VARCHAR(50),
-    quantity INT
-);
\ No newline at end of file
+    region_id INT NOT NULL REFERENCES regions (id),
+    gift_id INT NOT NULL REFERENCES gifts (id),
+    quantity INT NOT NULL CHECK (quantity >= 0),
+    created_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+    updated_at TIMESTAMPTZ NOT NULL DEFAULT now()
+);
+-- create packets table
+CREATE TABLE packets (
+    id TEXT PRIMARY KEY,
+    saved_at TIMESTAMPTZ NOT NULL,
+    expires_at TIMESTAMPTZ
+);
+-- ad-hoc /13/sql queries run as this role, it can only read the tables
+CREATE ROLE sql_reader NOLOGIN;
+GRANT sql_reader TO CURRENT_USER;
+GRANT USAGE ON SCHEMA public TO sql_reader;
+GRANT SELECT ON orders, gifts, regions TO sql_reader;
+-- the resets recreate the tables
+ALTER DEFAULT PRIVILEGES IN SCHEMA public GRANT SELECT ON TABLES TO sql_reader;
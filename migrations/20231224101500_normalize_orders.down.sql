@@ -0,0 +1,15 @@
+-- orders keep their gift name again, placeholder regions stay as unnamed rows
+ALTER TABLE orders ADD COLUMN gift_name VARCHAR(50);
+UPDATE orders SET gift_name = gifts.name FROM gifts WHERE gifts.id = orders.gift_id;
+ALTER TABLE orders
+    DROP COLUMN gift_id,
+    DROP COLUMN created_at,
+    DROP COLUMN updated_at,
+    DROP CONSTRAINT orders_region_id_fkey,
+    DROP CONSTRAINT orders_quantity_check,
+    ALTER COLUMN region_id DROP NOT NULL,
+    ALTER COLUMN quantity DROP NOT NULL;
+ALTER TABLE regions
+    DROP COLUMN created_at,
+    DROP COLUMN updated_at;
+DROP TABLE gifts;
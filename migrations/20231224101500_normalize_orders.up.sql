@@ -0,0 +1,29 @@
+-- gift names move to their own table, orders point at a gift and a region
+CREATE TABLE gifts (
+    id SERIAL PRIMARY KEY,
+    name VARCHAR(50) NOT NULL UNIQUE,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT now()
+);
+-- orders missing a field can't be kept under the new constraints
+DELETE FROM orders
+WHERE region_id IS NULL OR gift_name IS NULL OR quantity IS NULL OR quantity < 0;
+INSERT INTO gifts (name)
+SELECT DISTINCT gift_name FROM orders ORDER BY gift_name;
+-- a region only known from its orders is kept without a name
+INSERT INTO regions (id)
+SELECT DISTINCT region_id FROM orders
+ON CONFLICT (id) DO NOTHING;
+ALTER TABLE regions
+    ADD COLUMN created_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+    ADD COLUMN updated_at TIMESTAMPTZ NOT NULL DEFAULT now();
+ALTER TABLE orders ADD COLUMN gift_id INT REFERENCES gifts (id);
+UPDATE orders SET gift_id = gifts.id FROM gifts WHERE gifts.name = orders.gift_name;
+ALTER TABLE orders
+    DROP COLUMN gift_name,
+    ALTER COLUMN gift_id SET NOT NULL,
+    ALTER COLUMN region_id SET NOT NULL,
+    ALTER COLUMN quantity SET NOT NULL,
+    ADD CONSTRAINT orders_region_id_fkey FOREIGN KEY (region_id) REFERENCES regions (id),
+    ADD CONSTRAINT orders_quantity_check CHECK (quantity >= 0),
+    ADD COLUMN created_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+    ADD COLUMN updated_at TIMESTAMPTZ NOT NULL DEFAULT now();
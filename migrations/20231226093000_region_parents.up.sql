@@ -0,0 +1,3 @@
+-- regions may be part of another one, e.g. a city of a state
+ALTER TABLE regions
+    ADD COLUMN parent_id INT REFERENCES regions (id) CHECK (parent_id <> id);
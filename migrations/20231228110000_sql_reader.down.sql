@@ -0,0 +1,4 @@
+-- the role itself is kept, other databases of the cluster may use it
+ALTER DEFAULT PRIVILEGES IN SCHEMA public REVOKE SELECT ON TABLES FROM sql_reader;
+REVOKE SELECT ON orders, gifts, regions FROM sql_reader;
+REVOKE USAGE ON SCHEMA public FROM sql_reader;
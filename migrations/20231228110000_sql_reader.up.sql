@@ -0,0 +1,14 @@
+-- ad-hoc /13/sql queries run as this role, it can only read the tables
+DO $$
+BEGIN
+    CREATE ROLE sql_reader NOLOGIN;
+EXCEPTION WHEN duplicate_object OR unique_violation THEN
+    -- roles are shared by every database of the cluster
+    NULL;
+END
+$$;
+GRANT sql_reader TO CURRENT_USER;
+GRANT USAGE ON SCHEMA public TO sql_reader;
+GRANT SELECT ON orders, gifts, regions TO sql_reader;
+-- the resets recreate the tables
+ALTER DEFAULT PRIVILEGES IN SCHEMA public GRANT SELECT ON TABLES TO sql_reader;
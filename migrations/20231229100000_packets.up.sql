@@ -0,0 +1,7 @@
+-- day 12 packets when PACKET_STORE=postgres, the store used to create the
+-- table on first use so it may already be there
+CREATE TABLE IF NOT EXISTS packets (
+    id TEXT PRIMARY KEY,
+    saved_at TIMESTAMPTZ NOT NULL,
+    expires_at TIMESTAMPTZ
+);
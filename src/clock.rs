@@ -0,0 +1,59 @@
+use std::sync::{Arc, RwLock};
+
+use chrono::{DateTime, Duration, Utc};
+
+pub type SharedClock = Arc<dyn Clock>;
+
+/// Where handlers get the current time from, so time based endpoints can
+/// be driven by a test clock instead of waiting.
+pub trait Clock: Send + Sync {
+    fn now(&self) -> DateTime<Utc>;
+
+    /// The manual clock behind this one, when it can be moved by hand.
+    fn as_manual(&self) -> Option<&ManualClock> {
+        None
+    }
+}
+
+/// The wall clock.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> DateTime<Utc> {
+        Utc::now()
+    }
+}
+
+/// A clock that only moves when it is told to.
+#[derive(Debug)]
+pub struct ManualClock {
+    now: RwLock<DateTime<Utc>>,
+}
+
+impl ManualClock {
+    pub fn new(now: DateTime<Utc>) -> Self {
+        Self {
+            now: RwLock::new(now),
+        }
+    }
+
+    /// Moves the clock forward by `by` and returns the new time, `None`
+    /// leaving the clock where it was when that is past the last date chrono
+    /// can represent.
+    pub fn advance(&self, by: Duration) -> Option<DateTime<Utc>> {
+        let mut now = self.now.write().unwrap_or_else(|e| e.into_inner());
+        *now = now.checked_add_signed(by)?;
+        Some(*now)
+    }
+}
+
+impl Clock for ManualClock {
+    fn now(&self) -> DateTime<Utc> {
+        *self.now.read().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn as_manual(&self) -> Option<&ManualClock> {
+        Some(self)
+    }
+}
@@ -1,4 +1,4 @@
-#[derive(clap::Parser, Clone, Debug)]
+#[derive(clap::Parser, Clone)]
 pub struct Config {
     #[clap(long, env)]
     pub database_url: String,
@@ -8,4 +8,94 @@ pub struct Config {
 
     #[clap(long, env, default_value = "8000")]
     pub port: u16,
+
+    // how the day 7 recipe cookie is protected
+    #[clap(long, env, value_enum, default_value_t = CookieFormat::Plain)]
+    pub cookie_format: CookieFormat,
+
+    // comma separated secrets, the first one is used for new cookies
+    // and the rest are still accepted so keys can be rotated
+    #[clap(long, env, value_delimiter = ',')]
+    pub cookie_keys: Vec<String>,
+
+    #[clap(long, env, default_value = "https://pokeapi.co/api/v2")]
+    pub pokeapi_base_url: String,
+
+    // serve the day 8 pokemons from a directory of json files instead of the PokéAPI
+    #[clap(long, env)]
+    pub pokedex_fixtures: Option<std::path::PathBuf>,
+
+    #[clap(long, env, default_value = "3600")]
+    pub pokedex_cache_ttl_secs: u64,
+
+    // where uploaded day 11 assets are kept, the files in `assets` stay read only
+    #[clap(long, env, default_value = "asset_store")]
+    pub asset_store_dir: std::path::PathBuf,
+
+    // bearer token for uploading and deleting assets, uploads are off without it
+    #[clap(long, env)]
+    pub asset_upload_token: Option<String>,
+
+    // where the day 12 packet timestamps are kept
+    #[clap(long, env, value_enum, default_value_t = PacketStoreKind::Memory)]
+    pub packet_store: PacketStoreKind,
+
+    // only used by the disk packet store
+    #[clap(long, env, default_value = "packet_store")]
+    pub packet_store_dir: std::path::PathBuf,
+
+    #[clap(long, env, default_value = "60")]
+    pub packet_sweep_interval_secs: u64,
+
+    // run on a manual clock that only moves through /12/clock/advance
+    #[clap(long, env)]
+    pub test_mode: bool,
+
+    // bearer token for the admin endpoints
+    #[clap(long, env)]
+    pub admin_token: Option<String>,
+}
+
+// written by hand so the secrets never end up in the logs
+impl std::fmt::Debug for Config {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        const REDACTED: &str = "<redacted>";
+        let redacted = |secret: &Option<String>| secret.as_ref().map(|_| REDACTED);
+        f.debug_struct("Config")
+            .field("database_url", &REDACTED)
+            .field("geocoding_api_key", &REDACTED)
+            .field("port", &self.port)
+            .field("cookie_format", &self.cookie_format)
+            .field("cookie_keys", &vec![REDACTED; self.cookie_keys.len()])
+            .field("pokeapi_base_url", &self.pokeapi_base_url)
+            .field("pokedex_fixtures", &self.pokedex_fixtures)
+            .field("pokedex_cache_ttl_secs", &self.pokedex_cache_ttl_secs)
+            .field("asset_store_dir", &self.asset_store_dir)
+            .field("asset_upload_token", &redacted(&self.asset_upload_token))
+            .field("packet_store", &self.packet_store)
+            .field("packet_store_dir", &self.packet_store_dir)
+            .field(
+                "packet_sweep_interval_secs",
+                &self.packet_sweep_interval_secs,
+            )
+            .field("test_mode", &self.test_mode)
+            .field("admin_token", &redacted(&self.admin_token))
+            .finish()
+    }
+}
+
+#[derive(clap::ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum CookieFormat {
+    #[default]
+    Plain,
+    Signed,
+    Encrypted,
+}
+
+#[derive(clap::ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum PacketStoreKind {
+    #[default]
+    Memory,
+    Postgres,
+    Disk,
 }
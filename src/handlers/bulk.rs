@@ -0,0 +1,478 @@
+//! Bulk loading and dumping of the day 13 orders and day 18 regions: CSV and
+//! NDJSON bodies are parsed as they stream in and fed to Postgres with `COPY`,
+//! exports stream straight out of `COPY ... TO STDOUT`.
+
+use std::{
+    io::{self, BufRead, BufReader, Read},
+    ops::DerefMut,
+    sync::Arc,
+};
+
+use axum::{
+    body::Body,
+    http::{header, HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
+    Json,
+};
+use bytes::Bytes;
+use futures::{stream, StreamExt, TryStreamExt};
+use parquet::{
+    data_type::{ByteArray, ByteArrayType, Int32Type},
+    file::{properties::WriterProperties, writer::SerializedFileWriter},
+    schema::parser::parse_message_type,
+};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use sqlx::{postgres::PgCopyIn, PgConnection};
+use tokio::sync::{mpsc, oneshot};
+use tokio_util::io::{StreamReader, SyncIoBridge};
+
+use super::day13::{FieldError, OnConflict};
+
+/// Line errors kept for the report, the rest are only counted.
+const MAX_REPORTED_ERRORS: usize = 100;
+/// Size of the CSV chunks handed to `COPY`.
+const COPY_CHUNK_SIZE: usize = 64 * 1024;
+const CHUNKS_IN_FLIGHT: usize = 8;
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum BulkFormat {
+    #[default]
+    Csv,
+    Ndjson,
+    /// Export only, a Parquet file can't be read before its footer arrives.
+    Parquet,
+}
+
+impl BulkFormat {
+    fn content_type(self) -> &'static str {
+        match self {
+            BulkFormat::Csv => "text/csv; charset=utf-8",
+            BulkFormat::Ndjson => "application/x-ndjson",
+            BulkFormat::Parquet => "application/vnd.apache.parquet",
+        }
+    }
+
+    fn extension(self) -> &'static str {
+        match self {
+            BulkFormat::Csv => "csv",
+            BulkFormat::Ndjson => "ndjson",
+            BulkFormat::Parquet => "parquet",
+        }
+    }
+}
+
+#[derive(Debug, Default, Deserialize)]
+pub struct ImportQuery {
+    /// Taken from the `Content-Type` when missing.
+    pub format: Option<BulkFormat>,
+    /// Only validate the rows and report what is wrong with them.
+    #[serde(default)]
+    pub dry_run: bool,
+    #[serde(default)]
+    pub on_conflict: OnConflict,
+}
+
+impl ImportQuery {
+    pub fn format(&self, headers: &HeaderMap) -> Result<BulkFormat, StatusCode> {
+        let format = self.format.unwrap_or_else(|| {
+            let content_type = headers
+                .get(header::CONTENT_TYPE)
+                .and_then(|value| value.to_str().ok())
+                .unwrap_or_default();
+            match content_type.split(';').next().unwrap_or_default().trim() {
+                "application/x-ndjson" | "application/ndjson" | "application/jsonl" => {
+                    BulkFormat::Ndjson
+                }
+                _ => BulkFormat::Csv,
+            }
+        });
+        if format == BulkFormat::Parquet {
+            tracing::error!("parquet imports are not supported");
+            return Err(StatusCode::UNSUPPORTED_MEDIA_TYPE);
+        }
+        Ok(format)
+    }
+}
+
+#[derive(Debug, Default, Deserialize)]
+pub struct ExportTableQuery {
+    #[serde(default)]
+    pub format: BulkFormat,
+}
+
+/// A row of an import, checked on its own and written to a staging table.
+pub trait ImportRow: DeserializeOwned + Send + 'static {
+    /// Everything that would make the row break a table constraint.
+    fn problems(&self) -> Vec<FieldError>;
+
+    /// The row's fields in the order of the staging table columns that
+    /// follow `line`, empty for NULL.
+    fn record(&self) -> Vec<String>;
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct LineError {
+    pub line: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub field: Option<String>,
+    pub message: String,
+}
+
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ImportReport {
+    pub valid: u64,
+    pub invalid: u64,
+    /// The first invalid rows by line number.
+    pub errors: Vec<LineError>,
+}
+
+impl ImportReport {
+    fn reject(&mut self, line: u64, field: Option<String>, message: String) {
+        self.invalid += 1;
+        if self.errors.len() < MAX_REPORTED_ERRORS {
+            self.errors.push(LineError {
+                line,
+                field,
+                message,
+            });
+        }
+    }
+}
+
+impl IntoResponse for ImportReport {
+    /// A report of invalid rows, answered with a 422 as nothing was imported.
+    fn into_response(self) -> Response {
+        (StatusCode::UNPROCESSABLE_ENTITY, Json(self)).into_response()
+    }
+}
+
+fn copy_writer() -> csv::Writer<Vec<u8>> {
+    csv::WriterBuilder::new()
+        .has_headers(false)
+        .from_writer(vec![])
+}
+
+/// Collects valid rows as staging table CSV and passes it on in chunks.
+struct CopyBuffer {
+    writer: csv::Writer<Vec<u8>>,
+    chunks: Option<mpsc::Sender<Vec<u8>>>,
+}
+
+impl CopyBuffer {
+    fn push(&mut self, line: u64, record: Vec<String>) -> io::Result<()> {
+        if self.chunks.is_none() {
+            return Ok(());
+        }
+        self.writer
+            .write_record(std::iter::once(line.to_string()).chain(record))?;
+        if self.writer.get_ref().len() >= COPY_CHUNK_SIZE {
+            self.flush()?;
+        }
+        Ok(())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        let Some(chunks) = &self.chunks else {
+            return Ok(());
+        };
+        let chunk = std::mem::replace(&mut self.writer, copy_writer())
+            .into_inner()
+            .map_err(|e| e.into_error())?;
+        if chunk.is_empty() {
+            return Ok(());
+        }
+        chunks
+            .blocking_send(chunk)
+            .map_err(|_| io::Error::other("the copy stopped reading"))
+    }
+
+    /// Stops passing rows on, the import is rejected anyway.
+    fn discard(&mut self) {
+        self.chunks = None;
+    }
+}
+
+fn check_row<R: ImportRow>(
+    report: &mut ImportReport,
+    copy: &mut CopyBuffer,
+    line: u64,
+    row: R,
+) -> io::Result<()> {
+    let problems = row.problems();
+    if problems.is_empty() {
+        report.valid += 1;
+        return copy.push(line, row.record());
+    }
+    copy.discard();
+    for problem in problems {
+        report.reject(line, Some(problem.field.to_string()), problem.message);
+    }
+    Ok(())
+}
+
+/// Reads every row of `reader`, runs on a blocking thread.
+fn parse_rows<R: ImportRow>(
+    reader: impl Read,
+    format: BulkFormat,
+    chunks: Option<mpsc::Sender<Vec<u8>>>,
+) -> io::Result<ImportReport> {
+    let mut report = ImportReport::default();
+    let mut copy = CopyBuffer {
+        writer: copy_writer(),
+        chunks,
+    };
+    match format {
+        BulkFormat::Csv => {
+            let mut reader = csv::Reader::from_reader(reader);
+            let headers = reader.headers()?.clone();
+            for record in reader.records() {
+                let record = match record {
+                    Ok(record) => record,
+                    Err(e) if e.is_io_error() => return Err(e.into()),
+                    Err(e) => {
+                        let line = e.position().map(|p| p.line()).unwrap_or_default();
+                        copy.discard();
+                        report.reject(line, None, e.to_string());
+                        continue;
+                    }
+                };
+                let line = record.position().map(|p| p.line()).unwrap_or_default();
+                match record.deserialize::<R>(Some(&headers)) {
+                    Ok(row) => check_row(&mut report, &mut copy, line, row)?,
+                    Err(e) => {
+                        copy.discard();
+                        report.reject(line, None, e.to_string());
+                    }
+                }
+            }
+        }
+        BulkFormat::Ndjson | BulkFormat::Parquet => {
+            for (index, text) in BufReader::new(reader).lines().enumerate() {
+                let text = text?;
+                let line = index as u64 + 1;
+                if text.trim().is_empty() {
+                    continue;
+                }
+                match serde_json::from_str::<R>(&text) {
+                    Ok(row) => check_row(&mut report, &mut copy, line, row)?,
+                    Err(e) => {
+                        copy.discard();
+                        report.reject(line, None, e.to_string());
+                    }
+                }
+            }
+        }
+    }
+    copy.flush()?;
+    Ok(report)
+}
+
+/// Validates every row of the streamed `body`. With a `copy`, the rows go
+/// into it while parsing and the copy is finished when they were all
+/// valid or aborted otherwise.
+pub async fn import<R, C>(
+    body: Body,
+    format: BulkFormat,
+    mut copy: Option<PgCopyIn<C>>,
+) -> Result<ImportReport, StatusCode>
+where
+    R: ImportRow,
+    C: DerefMut<Target = PgConnection>,
+{
+    let stream = body.into_data_stream().map_err(io::Error::other);
+    let reader = SyncIoBridge::new(StreamReader::new(stream));
+    let (sender, mut chunks) = mpsc::channel(CHUNKS_IN_FLIGHT);
+    let sender = copy.is_some().then_some(sender);
+    let parser = tokio::task::spawn_blocking(move || parse_rows::<R>(reader, format, sender));
+
+    while let Some(chunk) = chunks.recv().await {
+        if let Some(copy) = copy.as_mut() {
+            copy.send(chunk).await.map_err(|e| {
+                tracing::error!("error copying rows to the database {e}");
+                StatusCode::INTERNAL_SERVER_ERROR
+            })?;
+        }
+    }
+    let report = parser
+        .await
+        .map_err(|e| {
+            tracing::error!("error joining the import parser {e}");
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?
+        .map_err(|e| {
+            tracing::error!("error reading the import body {e}");
+            StatusCode::BAD_REQUEST
+        })?;
+
+    if let Some(copy) = copy {
+        let copied = if report.invalid == 0 {
+            copy.finish().await.map(|_| ())
+        } else {
+            copy.abort("invalid rows in the import").await
+        };
+        copied.map_err(|e| {
+            tracing::error!("error ending the copy {e}");
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?;
+    }
+    Ok(report)
+}
+
+fn download(name: &str, format: BulkFormat, body: Body) -> Response {
+    (
+        [
+            (header::CONTENT_TYPE, format.content_type().to_string()),
+            (
+                header::CONTENT_DISPOSITION,
+                format!("attachment; filename=\"{name}.{}\"", format.extension()),
+            ),
+        ],
+        body,
+    )
+        .into_response()
+}
+
+/// Undoes the text format escaping of a `COPY ... TO STDOUT` row.
+fn unescape_copy_text(row: &[u8]) -> Vec<u8> {
+    let mut unescaped = Vec::with_capacity(row.len());
+    let mut bytes = row.iter();
+    while let Some(&byte) = bytes.next() {
+        if byte != b'\\' {
+            unescaped.push(byte);
+            continue;
+        }
+        match bytes.next() {
+            Some(b'n') => unescaped.push(b'\n'),
+            Some(b'r') => unescaped.push(b'\r'),
+            Some(b't') => unescaped.push(b'\t'),
+            Some(&other) => unescaped.push(other),
+            None => unescaped.push(b'\\'),
+        }
+    }
+    unescaped
+}
+
+/// Streams the rows of `select` as CSV with a header or as one JSON object
+/// per line.
+pub async fn copy_out<C>(
+    mut connection: C,
+    name: &str,
+    format: BulkFormat,
+    select: &str,
+) -> Result<Response, StatusCode>
+where
+    C: DerefMut<Target = PgConnection> + Send + 'static,
+{
+    let statement = match format {
+        BulkFormat::Csv => format!("COPY ({select}) TO STDOUT WITH (FORMAT csv, HEADER)"),
+        // the JSON of every row comes as a text value, one row per message
+        _ => format!("COPY (SELECT row_to_json(t) FROM ({select}) t) TO STDOUT"),
+    };
+
+    // the copy borrows its connection, so a task owns both and hands the
+    // rows over while the response streams them
+    let (started, start) = oneshot::channel();
+    let (sender, receiver) = mpsc::channel(CHUNKS_IN_FLIGHT);
+    tokio::spawn(async move {
+        let mut rows = match connection.copy_out_raw(&statement).await {
+            Ok(rows) => {
+                let _ = started.send(Ok(()));
+                rows
+            }
+            Err(e) => {
+                let _ = started.send(Err(e));
+                return;
+            }
+        };
+        while let Some(row) = rows.next().await {
+            let row = row.map_err(io::Error::other).map(|row| match format {
+                BulkFormat::Csv => row,
+                _ => Bytes::from(unescape_copy_text(&row)),
+            });
+            if sender.send(row).await.is_err() {
+                // the client went away
+                break;
+            }
+        }
+    });
+    start
+        .await
+        .map_err(|e| {
+            tracing::error!("error waiting for the {name} export {e}");
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?
+        .map_err(|e| {
+            tracing::error!("error starting the {name} export {e}");
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?;
+    let rows = stream::unfold(receiver, |mut receiver| async move {
+        receiver.recv().await.map(|row| (row, receiver))
+    });
+    Ok(download(name, format, Body::from_stream(rows)))
+}
+
+/// A whole column of a Parquet export.
+pub enum ParquetColumn {
+    Int32(Vec<i32>),
+    OptionalInt32(Vec<Option<i32>>),
+    Utf8(Vec<String>),
+}
+
+fn definition_levels<T>(values: &[Option<T>]) -> Vec<i16> {
+    values
+        .iter()
+        .map(|value| i16::from(value.is_some()))
+        .collect()
+}
+
+/// Writes `columns`, in the order of the `schema` message, as a single row
+/// group Parquet file.
+pub fn parquet_response(
+    name: &str,
+    schema: &str,
+    columns: Vec<ParquetColumn>,
+) -> Result<Response, StatusCode> {
+    let write = || -> parquet::errors::Result<Vec<u8>> {
+        let schema = Arc::new(parse_message_type(schema)?);
+        let properties = Arc::new(WriterProperties::builder().build());
+        let mut writer = SerializedFileWriter::new(vec![], schema, properties)?;
+        let mut row_group = writer.next_row_group()?;
+        for column in columns {
+            let Some(mut writer) = row_group.next_column()? else {
+                break;
+            };
+            match column {
+                ParquetColumn::Int32(values) => {
+                    writer
+                        .typed::<Int32Type>()
+                        .write_batch(&values, None, None)?;
+                }
+                ParquetColumn::OptionalInt32(values) => {
+                    let levels = definition_levels(&values);
+                    let present: Vec<i32> = values.into_iter().flatten().collect();
+                    writer
+                        .typed::<Int32Type>()
+                        .write_batch(&present, Some(&levels), None)?;
+                }
+                ParquetColumn::Utf8(values) => {
+                    let values: Vec<ByteArray> = values
+                        .iter()
+                        .map(|value| ByteArray::from(value.as_str()))
+                        .collect();
+                    writer
+                        .typed::<ByteArrayType>()
+                        .write_batch(&values, None, None)?;
+                }
+            }
+            writer.close()?;
+        }
+        row_group.close()?;
+        writer.into_inner()
+    };
+    let file = write().map_err(|e| {
+        tracing::error!("error writing the {name} parquet file {e}");
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+    Ok(download(name, BulkFormat::Parquet, Body::from(file)))
+}
@@ -1,48 +1,1251 @@
+use std::{
+    collections::{BTreeMap, HashSet},
+    io::{BufReader, Cursor, Read, Seek, SeekFrom},
+    path::{Path as FsPath, PathBuf},
+    sync::Arc,
+};
+
 use axum::{
-    extract::Multipart,
-    http::StatusCode,
+    body::Bytes,
+    extract::{DefaultBodyLimit, Multipart, Path, Query, State},
+    http::{header, HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
     routing::{get, post},
-    Router,
+    Json, Router,
+};
+use axum_extra::{
+    headers::{authorization::Bearer, Authorization},
+    TypedHeader,
 };
-use image::{GenericImageView, Rgba};
-use tower_http::services::ServeDir;
+use image::{
+    imageops::FilterType, DynamicImage, GenericImageView, ImageFormat, ImageOutputFormat, Rgba,
+};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use tokio::{fs, io::AsyncWriteExt, sync::RwLock};
+
+use super::check_bearer;
+use crate::clock::{SharedClock, SystemClock};
+
+const DEFAULT_CLUSTERS: usize = 5;
+const MAX_CLUSTERS: usize = 16;
+const MAX_KMEANS_ITERATIONS: usize = 20;
+// k-means runs on a sample of the pixels so big images stay cheap
+const MAX_KMEANS_SAMPLES: usize = 10_000;
+const MAX_PREDICATES: usize = 32;
+const MAX_OPERATIONS: usize = 16;
+const MAX_DIMENSION: u32 = 4096;
+const MAX_BLUR_SIGMA: f32 = 50.0;
+const MAX_THUMBNAIL_SIZE: u32 = 1024;
+// Multipart uploads, each field is spooled to disk as it streams in.
+const MAX_FIELD_SIZE: u64 = 10 * 1024 * 1024;
+const MAX_UPLOAD_SIZE: u64 = 25 * 1024 * 1024;
+const MAX_UPLOAD_FIELDS: usize = 16;
+const SNIFF_LEN: usize = 64;
 
-pub fn router() -> Router {
+// Types an upload may be served as, nothing a browser would run (html, svg,
+// scripts) so an asset can't act as a page of the app
+const ALLOWED_CONTENT_TYPES: &[&str] = &[
+    "application/octet-stream",
+    "image/avif",
+    "image/bmp",
+    "image/gif",
+    "image/jpeg",
+    "image/png",
+    "image/tiff",
+    "image/webp",
+    "image/x-icon",
+    "text/plain",
+];
+
+pub fn router(assets: SharedAssetStore) -> Router {
     Router::new()
         .route("/11/health", get(|| async { StatusCode::OK }))
         .route("/11/red_pixels", post(red_pixels))
-        .nest_service("/11/assets", ServeDir::new("assets"))
+        .route("/11/analyze", post(analyze))
+        .route("/11/transform", post(transform))
+        .route("/11/assets", get(list_assets))
+        // static assets can be nested, uploads are always a single name
+        .route(
+            "/11/assets/*name",
+            get(get_asset).put(put_asset).delete(delete_asset),
+        )
+        .layer(DefaultBodyLimit::max(MAX_UPLOAD_SIZE as usize))
+        .with_state(assets)
+}
+
+#[derive(Debug, Serialize, Clone, PartialEq, Eq)]
+struct RedPixelsResult {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    file_name: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    content_type: Option<String>,
+    size: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    red_pixels: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+/// Counts the red pixels of every `image` field. A single image answers
+/// with the bare count like it always did, several get a json list with a
+/// result or an error for each of them.
+async fn red_pixels(mut multipart: Multipart) -> Result<Response, StatusCode> {
+    let images: Vec<SpooledField> = spool_fields(&mut multipart)
+        .await?
+        .into_iter()
+        .filter(|field| field.name == "image")
+        .collect();
+    let count = |field: &SpooledField| {
+        field.decode().map(|(_, img)| {
+            img.pixels()
+                .filter(|(_, _, pixel)| ColorPredicate::RedDominant.matches(pixel))
+                .count()
+        })
+    };
+
+    match images.as_slice() {
+        [] => Ok("0".into_response()),
+        [image] => Ok(count(image)?.to_string().into_response()),
+        images => Ok(Json(
+            images
+                .iter()
+                .map(|image| {
+                    let counted = count(image);
+                    RedPixelsResult {
+                        file_name: image.file_name.clone(),
+                        content_type: image.format.map(|f| f.to_mime_type().to_string()),
+                        size: image.size,
+                        error: counted
+                            .as_ref()
+                            .err()
+                            .map(|status| status.canonical_reason().unwrap_or("error").to_string()),
+                        red_pixels: counted.ok(),
+                    }
+                })
+                .collect::<Vec<_>>(),
+        )
+        .into_response()),
+    }
+}
+
+/// A multipart field written to an anonymous temp file as it arrives.
+struct SpooledField {
+    name: String,
+    file_name: Option<String>,
+    size: u64,
+    /// Sniffed from the first bytes, whatever the client said the type was.
+    format: Option<ImageFormat>,
+    file: std::fs::File,
+}
+
+impl SpooledField {
+    fn bytes(&self) -> Result<Vec<u8>, StatusCode> {
+        let mut data = Vec::with_capacity(self.size as usize);
+        (&self.file)
+            .seek(SeekFrom::Start(0))
+            .and_then(|_| (&self.file).read_to_end(&mut data))
+            .map_err(|e| {
+                tracing::error!("error while reading back field {} {e}", self.name);
+                StatusCode::INTERNAL_SERVER_ERROR
+            })?;
+        Ok(data)
+    }
+
+    fn decode(&self) -> Result<(ImageFormat, DynamicImage), StatusCode> {
+        let format = self.format.ok_or_else(|| {
+            tracing::error!("field {} is not a supported image", self.name);
+            StatusCode::UNSUPPORTED_MEDIA_TYPE
+        })?;
+        (&self.file).seek(SeekFrom::Start(0)).map_err(|e| {
+            tracing::error!("error while reading back field {} {e}", self.name);
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?;
+        let img = image::io::Reader::with_format(BufReader::new(&self.file), format)
+            .decode()
+            .map_err(|e| {
+                tracing::error!("error while loading image {e}");
+                StatusCode::BAD_REQUEST
+            })?;
+        Ok((format, img))
+    }
+}
+
+fn sniff_image_format(head: &[u8]) -> Option<ImageFormat> {
+    image::guess_format(head).ok().filter(|format| {
+        matches!(
+            format,
+            ImageFormat::Png | ImageFormat::Jpeg | ImageFormat::Gif | ImageFormat::WebP
+        )
+    })
+}
+
+/// Streams every field of the request to its own temp file, so nothing is
+/// held in memory and the size limits are checked chunk by chunk.
+async fn spool_fields(multipart: &mut Multipart) -> Result<Vec<SpooledField>, StatusCode> {
+    let mut fields = vec![];
+    let mut total = 0u64;
+    while let Some(mut field) = multipart.next_field().await.map_err(|e| {
+        tracing::error!("error while reading multipart field {e}");
+        e.status()
+    })? {
+        if fields.len() == MAX_UPLOAD_FIELDS {
+            tracing::error!("more than {MAX_UPLOAD_FIELDS} fields in the request");
+            return Err(StatusCode::PAYLOAD_TOO_LARGE);
+        }
+        let name = field.name().unwrap_or("").to_string();
+        let file_name = field.file_name().map(str::to_string);
+        let file = tempfile::tempfile().map_err(|e| {
+            tracing::error!("error while creating a temp file {e}");
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?;
+        let mut file = fs::File::from_std(file);
+        let mut head = Vec::with_capacity(SNIFF_LEN);
+        let mut size = 0u64;
+        while let Some(chunk) = field.chunk().await.map_err(|e| {
+            tracing::error!("error while reading field {name} {e}");
+            e.status()
+        })? {
+            size += chunk.len() as u64;
+            total += chunk.len() as u64;
+            if size > MAX_FIELD_SIZE || total > MAX_UPLOAD_SIZE {
+                tracing::error!("field {name} is over the upload size limits");
+                return Err(StatusCode::PAYLOAD_TOO_LARGE);
+            }
+            let missing = SNIFF_LEN.saturating_sub(head.len()).min(chunk.len());
+            head.extend_from_slice(&chunk[..missing]);
+            file.write_all(&chunk).await.map_err(|e| {
+                tracing::error!("error while writing field {name} to disk {e}");
+                StatusCode::INTERNAL_SERVER_ERROR
+            })?;
+        }
+        file.flush().await.map_err(|e| {
+            tracing::error!("error while writing field {name} to disk {e}");
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?;
+        fields.push(SpooledField {
+            name,
+            file_name,
+            size,
+            format: sniff_image_format(&head),
+            file: file.into_std().await,
+        });
+    }
+    Ok(fields)
+}
+
+/// A test run against every pixel of the image, alpha is ignored.
+#[derive(Debug, Deserialize, Clone, PartialEq)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+enum ColorPredicate {
+    RedDominant,
+    GreenDominant,
+    BlueDominant,
+    /// Hue in degrees, saturation and value in `0..=1`, all bounds inclusive.
+    /// A hue range with `min > max` wraps around 360, e.g. `[330, 30]` for reds.
+    Hsv {
+        #[serde(default = "full_hue")]
+        hue: (f64, f64),
+        #[serde(default = "full_unit")]
+        saturation: (f64, f64),
+        #[serde(default = "full_unit")]
+        value: (f64, f64),
+    },
+}
+
+fn full_hue() -> (f64, f64) {
+    (0.0, 360.0)
+}
+
+fn full_unit() -> (f64, f64) {
+    (0.0, 1.0)
+}
+
+impl ColorPredicate {
+    fn matches(&self, &Rgba([r, g, b, _]): &Rgba<u8>) -> bool {
+        let (r, g, b) = (r as u32, g as u32, b as u32);
+        match self {
+            ColorPredicate::RedDominant => r > g + b,
+            ColorPredicate::GreenDominant => g > r + b,
+            ColorPredicate::BlueDominant => b > r + g,
+            ColorPredicate::Hsv {
+                hue,
+                saturation,
+                value,
+            } => {
+                let (h, s, v) = rgb_to_hsv(r as u8, g as u8, b as u8);
+                let in_hue = if hue.0 <= hue.1 {
+                    hue.0 <= h && h <= hue.1
+                } else {
+                    h >= hue.0 || h <= hue.1
+                };
+                in_hue && saturation.0 <= s && s <= saturation.1 && value.0 <= v && v <= value.1
+            }
+        }
+    }
+}
+
+fn rgb_to_hsv(r: u8, g: u8, b: u8) -> (f64, f64, f64) {
+    let (r, g, b) = (r as f64 / 255.0, g as f64 / 255.0, b as f64 / 255.0);
+    let max = r.max(g).max(b);
+    let delta = max - r.min(g).min(b);
+    let hue = if delta == 0.0 {
+        0.0
+    } else if max == r {
+        60.0 * ((g - b) / delta).rem_euclid(6.0)
+    } else if max == g {
+        60.0 * ((b - r) / delta + 2.0)
+    } else {
+        60.0 * ((r - g) / delta + 4.0)
+    };
+    let saturation = if max == 0.0 { 0.0 } else { delta / max };
+    (hue, saturation, max)
+}
+
+#[derive(Debug, Deserialize, Clone, PartialEq)]
+struct NamedPredicate {
+    name: String,
+    #[serde(flatten)]
+    predicate: ColorPredicate,
+}
+
+/// Sent as an optional `options` json field next to the `image` field.
+#[derive(Debug, Deserialize, Clone, PartialEq)]
+struct AnalyzeOptions {
+    #[serde(default = "default_clusters")]
+    clusters: usize,
+    #[serde(default = "default_predicates")]
+    predicates: Vec<NamedPredicate>,
+}
+
+fn default_clusters() -> usize {
+    DEFAULT_CLUSTERS
+}
+
+fn default_predicates() -> Vec<NamedPredicate> {
+    [
+        ("red", ColorPredicate::RedDominant),
+        ("green", ColorPredicate::GreenDominant),
+        ("blue", ColorPredicate::BlueDominant),
+    ]
+    .into_iter()
+    .map(|(name, predicate)| NamedPredicate {
+        name: name.to_string(),
+        predicate,
+    })
+    .collect()
+}
+
+impl Default for AnalyzeOptions {
+    fn default() -> Self {
+        Self {
+            clusters: default_clusters(),
+            predicates: default_predicates(),
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Clone, PartialEq)]
+struct ImageMetadata {
+    format: String,
+    width: u32,
+    height: u32,
+    pixels: u64,
+    color_type: String,
+    has_alpha: bool,
+}
+
+#[derive(Debug, Serialize, Clone, PartialEq)]
+struct Histogram {
+    red: Vec<u64>,
+    green: Vec<u64>,
+    blue: Vec<u64>,
+    alpha: Vec<u64>,
+}
+
+#[derive(Debug, Serialize, Clone, PartialEq)]
+struct DominantColor {
+    hex: String,
+    rgb: [u8; 3],
+    share: f64,
+}
+
+#[derive(Debug, Serialize, Clone, PartialEq)]
+struct AnalyzeResult {
+    metadata: ImageMetadata,
+    histogram: Histogram,
+    dominant_colors: Vec<DominantColor>,
+    predicates: BTreeMap<String, u64>,
+}
+
+/// Picks the `image` field and the optional json field named `options_field`.
+async fn read_upload(
+    multipart: &mut Multipart,
+    options_field: &str,
+) -> Result<(SpooledField, Option<Vec<u8>>), StatusCode> {
+    let mut image = None;
+    let mut options = None;
+    for field in spool_fields(multipart).await? {
+        if field.name == "image" {
+            image = Some(field);
+        } else if field.name == options_field {
+            options = Some(field.bytes()?);
+        }
+    }
+    let image = image.ok_or_else(|| {
+        tracing::error!("no image field in the request");
+        StatusCode::BAD_REQUEST
+    })?;
+    Ok((image, options))
+}
+
+fn load_image(data: &[u8]) -> Result<(ImageFormat, DynamicImage), StatusCode> {
+    let format = sniff_image_format(data).ok_or_else(|| {
+        tracing::error!("not a supported image");
+        StatusCode::UNSUPPORTED_MEDIA_TYPE
+    })?;
+    let img = image::load_from_memory_with_format(data, format).map_err(|e| {
+        tracing::error!("error while loading image {e}");
+        StatusCode::BAD_REQUEST
+    })?;
+    Ok((format, img))
+}
+
+/// Runs cpu bound image work, decoding included, off the async workers.
+async fn run_blocking<T: Send + 'static>(
+    work: impl FnOnce() -> Result<T, StatusCode> + Send + 'static,
+) -> Result<T, StatusCode> {
+    tokio::task::spawn_blocking(work).await.map_err(|e| {
+        tracing::error!("error running the image work {e}");
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?
+}
+
+async fn analyze(mut multipart: Multipart) -> Result<Json<AnalyzeResult>, StatusCode> {
+    let (image, options) = read_upload(&mut multipart, "options").await?;
+    let options: AnalyzeOptions = match options {
+        Some(data) => serde_json::from_slice(&data).map_err(|e| {
+            tracing::error!("error while parsing the options {e}");
+            StatusCode::BAD_REQUEST
+        })?,
+        None => AnalyzeOptions::default(),
+    };
+    if options.clusters == 0 || options.clusters > MAX_CLUSTERS {
+        tracing::error!("clusters must be between 1 and {MAX_CLUSTERS}");
+        return Err(StatusCode::BAD_REQUEST);
+    }
+    if options.predicates.len() > MAX_PREDICATES {
+        tracing::error!("too many predicates");
+        return Err(StatusCode::PAYLOAD_TOO_LARGE);
+    }
+    run_blocking(move || {
+        let (format, img) = image.decode()?;
+
+        let (width, height) = img.dimensions();
+        Ok(Json(AnalyzeResult {
+            metadata: ImageMetadata {
+                format: format!("{format:?}").to_lowercase(),
+                width,
+                height,
+                pixels: width as u64 * height as u64,
+                color_type: format!("{:?}", img.color()),
+                has_alpha: img.color().has_alpha(),
+            },
+            histogram: histogram(&img),
+            dominant_colors: dominant_colors(&img, options.clusters),
+            predicates: options
+                .predicates
+                .iter()
+                .map(|p| {
+                    let count = img
+                        .pixels()
+                        .filter(|(_, _, pixel)| p.predicate.matches(pixel))
+                        .count();
+                    (p.name.clone(), count as u64)
+                })
+                .collect(),
+        }))
+    })
+    .await
+}
+
+/// One step of a `/11/transform` pipeline.
+#[derive(Debug, Deserialize, Clone, PartialEq)]
+#[serde(tag = "op", rename_all = "snake_case")]
+enum Operation {
+    /// Fits the image inside `width`x`height` keeping its aspect ratio,
+    /// unless `exact` is set.
+    Resize {
+        width: u32,
+        height: u32,
+        #[serde(default)]
+        exact: bool,
+    },
+    Crop {
+        x: u32,
+        y: u32,
+        width: u32,
+        height: u32,
+    },
+    /// Clockwise, only quarter turns are supported.
+    Rotate {
+        degrees: u32,
+    },
+    Grayscale,
+    Blur {
+        sigma: f32,
+    },
+    /// Pixels with a luma of at least `level` become white, the rest black.
+    Threshold {
+        level: u8,
+    },
+    /// Paints the pixels matched by `predicate` with `color`, blended by its
+    /// alpha, so it shows what `/11/red_pixels` counts by default.
+    RedMask {
+        #[serde(default = "default_mask_predicate")]
+        predicate: ColorPredicate,
+        #[serde(default = "default_mask_color")]
+        color: [u8; 4],
+    },
+}
+
+fn default_mask_predicate() -> ColorPredicate {
+    ColorPredicate::RedDominant
+}
+
+fn default_mask_color() -> [u8; 4] {
+    [0, 255, 0, 255]
+}
+
+/// WebP can be read but not written without libwebp.
+#[derive(Debug, Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+enum OutputFormat {
+    #[default]
+    Png,
+    Jpeg,
+    Gif,
+    Bmp,
+}
+
+#[derive(Debug, Deserialize, Clone, PartialEq)]
+struct TransformRequest {
+    operations: Vec<Operation>,
+    #[serde(default)]
+    format: OutputFormat,
+    #[serde(default = "default_jpeg_quality")]
+    quality: u8,
+}
+
+fn default_jpeg_quality() -> u8 {
+    85
+}
+
+impl Operation {
+    fn apply(&self, img: DynamicImage) -> Result<DynamicImage, StatusCode> {
+        match self {
+            Operation::Resize {
+                width,
+                height,
+                exact,
+            } => {
+                if *width == 0 || *height == 0 || *width > MAX_DIMENSION || *height > MAX_DIMENSION
+                {
+                    tracing::error!("resize must be between 1 and {MAX_DIMENSION} pixels");
+                    return Err(StatusCode::BAD_REQUEST);
+                }
+                let filter = FilterType::Lanczos3;
+                Ok(if *exact {
+                    img.resize_exact(*width, *height, filter)
+                } else {
+                    img.resize(*width, *height, filter)
+                })
+            }
+            Operation::Crop {
+                x,
+                y,
+                width,
+                height,
+            } => {
+                let (img_width, img_height) = img.dimensions();
+                let fits = x
+                    .checked_add(*width)
+                    .zip(y.checked_add(*height))
+                    .is_some_and(|(right, bottom)| right <= img_width && bottom <= img_height);
+                if *width == 0 || *height == 0 || !fits {
+                    tracing::error!("crop is outside of the {img_width}x{img_height} image");
+                    return Err(StatusCode::BAD_REQUEST);
+                }
+                Ok(img.crop_imm(*x, *y, *width, *height))
+            }
+            Operation::Rotate { degrees } => match degrees % 360 {
+                0 => Ok(img),
+                90 => Ok(img.rotate90()),
+                180 => Ok(img.rotate180()),
+                270 => Ok(img.rotate270()),
+                _ => {
+                    tracing::error!("can only rotate by quarter turns, got {degrees}");
+                    Err(StatusCode::BAD_REQUEST)
+                }
+            },
+            Operation::Grayscale => Ok(img.grayscale()),
+            Operation::Blur { sigma } => {
+                if !(0.0..=MAX_BLUR_SIGMA).contains(sigma) {
+                    tracing::error!("blur sigma must be between 0 and {MAX_BLUR_SIGMA}");
+                    return Err(StatusCode::BAD_REQUEST);
+                }
+                Ok(img.blur(*sigma))
+            }
+            Operation::Threshold { level } => {
+                let mut luma = img.to_luma8();
+                luma.pixels_mut()
+                    .for_each(|p| p.0[0] = if p.0[0] >= *level { 255 } else { 0 });
+                Ok(DynamicImage::ImageLuma8(luma))
+            }
+            Operation::RedMask { predicate, color } => {
+                let mut rgba = img.to_rgba8();
+                let alpha = color[3] as u32;
+                for pixel in rgba.pixels_mut().filter(|p| predicate.matches(p)) {
+                    for (channel, overlay) in pixel.0.iter_mut().zip(&color[..3]) {
+                        *channel = ((*overlay as u32 * alpha + *channel as u32 * (255 - alpha))
+                            / 255) as u8;
+                    }
+                }
+                Ok(DynamicImage::ImageRgba8(rgba))
+            }
+        }
+    }
+}
+
+async fn transform(mut multipart: Multipart) -> Result<impl IntoResponse, StatusCode> {
+    let (image, request) = read_upload(&mut multipart, "pipeline").await?;
+    let request: TransformRequest = request
+        .ok_or_else(|| {
+            tracing::error!("no pipeline field in the request");
+            StatusCode::BAD_REQUEST
+        })
+        .and_then(|data| {
+            serde_json::from_slice(&data).map_err(|e| {
+                tracing::error!("error while parsing the pipeline {e}");
+                StatusCode::BAD_REQUEST
+            })
+        })?;
+    if request.operations.len() > MAX_OPERATIONS {
+        tracing::error!("too many operations");
+        return Err(StatusCode::PAYLOAD_TOO_LARGE);
+    }
+    let (format, data) = run_blocking(move || {
+        let (_, mut img) = image.decode()?;
+        for operation in &request.operations {
+            img = operation.apply(img)?;
+        }
+
+        let (output, format) = match request.format {
+            OutputFormat::Png => (ImageOutputFormat::Png, ImageFormat::Png),
+            // jpeg has no alpha channel
+            OutputFormat::Jpeg => {
+                img = DynamicImage::ImageRgb8(img.to_rgb8());
+                (
+                    ImageOutputFormat::Jpeg(request.quality.clamp(1, 100)),
+                    ImageFormat::Jpeg,
+                )
+            }
+            OutputFormat::Gif => (ImageOutputFormat::Gif, ImageFormat::Gif),
+            // bmp only takes 8 bit channels
+            OutputFormat::Bmp => {
+                img = DynamicImage::ImageRgba8(img.to_rgba8());
+                (ImageOutputFormat::Bmp, ImageFormat::Bmp)
+            }
+        };
+        let mut data = vec![];
+        img.write_to(&mut Cursor::new(&mut data), output)
+            .map_err(|e| {
+                tracing::error!("error while encoding the image {e}");
+                StatusCode::INTERNAL_SERVER_ERROR
+            })?;
+        Ok((format, data))
+    })
+    .await?;
+    Ok(([(header::CONTENT_TYPE, format.to_mime_type())], data))
+}
+
+fn histogram(img: &DynamicImage) -> Histogram {
+    let mut channels = [[0u64; 256]; 4];
+    for (_, _, Rgba(pixel)) in img.pixels() {
+        for (channel, value) in channels.iter_mut().zip(pixel) {
+            channel[value as usize] += 1;
+        }
+    }
+    let [red, green, blue, alpha] = channels.map(Vec::from);
+    Histogram {
+        red,
+        green,
+        blue,
+        alpha,
+    }
+}
+
+/// Clusters the pixel colors with k-means and returns the centroids,
+/// most common first. Each starting centroid is the sample farthest from
+/// the ones picked so far, so the same image always gives the same answer.
+fn dominant_colors(img: &DynamicImage, clusters: usize) -> Vec<DominantColor> {
+    let (width, height) = img.dimensions();
+    let total = width as usize * height as usize;
+    let step = total.div_ceil(MAX_KMEANS_SAMPLES).max(1);
+    let samples: Vec<[f64; 3]> = img
+        .pixels()
+        .step_by(step)
+        .map(|(_, _, Rgba([r, g, b, _]))| [r as f64, g as f64, b as f64])
+        .collect();
+    if samples.is_empty() {
+        return vec![];
+    }
+
+    let mut centroids = vec![samples[0]];
+    while centroids.len() < clusters {
+        let farthest = samples
+            .iter()
+            .map(|sample| distance(&centroids[nearest_centroid(&centroids, sample)], sample))
+            .enumerate()
+            .max_by(|a, b| a.1.total_cmp(&b.1).then(b.0.cmp(&a.0)))
+            .map(|(i, _)| samples[i])
+            .unwrap_or(samples[0]);
+        centroids.push(farthest);
+    }
+    let mut assignments = vec![0; samples.len()];
+    for _ in 0..MAX_KMEANS_ITERATIONS {
+        let mut changed = false;
+        for (sample, assignment) in samples.iter().zip(assignments.iter_mut()) {
+            let nearest = nearest_centroid(&centroids, sample);
+            changed |= *assignment != nearest;
+            *assignment = nearest;
+        }
+
+        let mut sums = vec![([0.0; 3], 0usize); centroids.len()];
+        for (sample, &assignment) in samples.iter().zip(&assignments) {
+            let (sum, count) = &mut sums[assignment];
+            sum.iter_mut().zip(sample).for_each(|(s, v)| *s += v);
+            *count += 1;
+        }
+        for (centroid, (sum, count)) in centroids.iter_mut().zip(sums) {
+            if count > 0 {
+                *centroid = sum.map(|s| s / count as f64);
+            }
+        }
+        if !changed {
+            break;
+        }
+    }
+
+    let mut counts = vec![0usize; centroids.len()];
+    for &assignment in &assignments {
+        counts[assignment] += 1;
+    }
+    // identical starting samples end up as clusters with the same color
+    let mut merged: BTreeMap<[u8; 3], usize> = BTreeMap::new();
+    for (centroid, count) in centroids.iter().zip(counts) {
+        if count > 0 {
+            *merged.entry(centroid.map(|c| c.round() as u8)).or_default() += count;
+        }
+    }
+    let mut colors: Vec<([u8; 3], usize)> = merged.into_iter().collect();
+    colors.sort_by_key(|&(_, count)| std::cmp::Reverse(count));
+    colors
+        .into_iter()
+        .map(|(rgb @ [r, g, b], count)| DominantColor {
+            hex: format!("#{r:02x}{g:02x}{b:02x}"),
+            rgb,
+            share: count as f64 / samples.len() as f64,
+        })
+        .collect()
+}
+
+fn nearest_centroid(centroids: &[[f64; 3]], sample: &[f64; 3]) -> usize {
+    centroids
+        .iter()
+        .map(|c| {
+            c.iter()
+                .zip(sample)
+                .map(|(a, b)| (a - b).powi(2))
+                .sum::<f64>()
+        })
+        .enumerate()
+        .min_by(|a, b| a.1.total_cmp(&b.1))
+        .map(|(i, _)| i)
+        .unwrap_or(0)
+}
+
+fn distance(a: &[f64; 3], b: &[f64; 3]) -> f64 {
+    a.iter().zip(b).map(|(a, b)| (a - b).powi(2)).sum()
+}
+
+pub type SharedAssetStore = Arc<AssetStore>;
+
+/// Uploaded assets, stored on disk by the SHA-256 of their content, with
+/// the files of a read-only static directory served underneath them.
+pub struct AssetStore {
+    static_dir: PathBuf,
+    store_dir: PathBuf,
+    upload_token: Option<String>,
+    clock: SharedClock,
+    // name -> every uploaded version, oldest first
+    index: RwLock<BTreeMap<String, Vec<AssetVersion>>>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+struct AssetVersion {
+    version: u32,
+    sha256: String,
+    size: u64,
+    content_type: String,
+    uploaded_at: u64,
+}
+
+#[derive(Debug, Serialize, Clone, PartialEq, Eq)]
+struct AssetInfo {
+    name: String,
+    etag: String,
+    size: u64,
+    content_type: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    version: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    versions: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    uploaded_at: Option<u64>,
+}
+
+impl AssetInfo {
+    fn uploaded(name: &str, versions: &[AssetVersion]) -> Option<Self> {
+        let latest = versions.last()?;
+        Some(Self {
+            name: name.to_string(),
+            etag: etag(&latest.sha256),
+            size: latest.size,
+            content_type: latest.content_type.clone(),
+            version: Some(latest.version),
+            versions: Some(versions.len()),
+            uploaded_at: Some(latest.uploaded_at),
+        })
+    }
+}
+
+impl AssetStore {
+    /// `upload_token` is the bearer token needed to upload or delete,
+    /// without one the store is read only.
+    pub fn open(
+        static_dir: impl Into<PathBuf>,
+        store_dir: impl Into<PathBuf>,
+        upload_token: Option<String>,
+    ) -> std::io::Result<Self> {
+        let store_dir = store_dir.into();
+        std::fs::create_dir_all(store_dir.join("objects"))?;
+        std::fs::create_dir_all(store_dir.join("thumbnails"))?;
+        let index = match std::fs::read(store_dir.join("index.json")) {
+            Ok(data) => serde_json::from_slice(&data)?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => BTreeMap::new(),
+            Err(e) => return Err(e),
+        };
+        Ok(Self {
+            static_dir: static_dir.into(),
+            store_dir,
+            upload_token,
+            clock: Arc::new(SystemClock),
+            index: RwLock::new(index),
+        })
+    }
+
+    pub fn with_clock(mut self, clock: SharedClock) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    fn object_path(&self, sha256: &str) -> PathBuf {
+        self.store_dir
+            .join("objects")
+            .join(&sha256[..2])
+            .join(&sha256[2..])
+    }
+
+    fn thumbnail_path(&self, sha256: &str, size: u32) -> PathBuf {
+        self.store_dir
+            .join("thumbnails")
+            .join(format!("{sha256}-{size}.png"))
+    }
+
+    // Written next to the target and renamed so readers never see half a file.
+    async fn write_atomic(&self, path: &FsPath, data: &[u8]) -> std::io::Result<()> {
+        let tmp = self.store_dir.join(format!(".tmp-{}", ulid::Ulid::new()));
+        fs::write(&tmp, data).await?;
+        fs::rename(&tmp, path).await
+    }
+
+    async fn save_index(&self, index: &BTreeMap<String, Vec<AssetVersion>>) -> std::io::Result<()> {
+        let data = serde_json::to_vec_pretty(index)?;
+        self.write_atomic(&self.store_dir.join("index.json"), &data)
+            .await
+    }
+}
+
+fn etag(sha256: &str) -> String {
+    format!("\"{sha256}\"")
 }
 
-async fn red_pixels(mut multipart: Multipart) -> Result<String, StatusCode> {
-    while let Some(field) = multipart.next_field().await.unwrap() {
-        let name = field.name().unwrap_or("");
-        let content_type = field.content_type().unwrap_or("");
+fn validate_asset_name(name: &str) -> Result<(), StatusCode> {
+    let valid = !name.is_empty()
+        && name.len() <= 255
+        && !name.starts_with('.')
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.'));
+    if !valid {
+        tracing::error!("invalid asset name {name:?}");
+        return Err(StatusCode::BAD_REQUEST);
+    }
+    Ok(())
+}
+
+/// A path below the static assets, every segment a valid asset name.
+fn validate_asset_path(path: &str) -> Result<(), StatusCode> {
+    path.split('/').try_for_each(validate_asset_name)
+}
+
+/// The bare media type of a `Content-Type`, if uploads may use it.
+fn allowed_content_type(content_type: &str) -> Option<String> {
+    let essence = content_type
+        .split(';')
+        .next()
+        .unwrap_or_default()
+        .trim()
+        .to_ascii_lowercase();
+    ALLOWED_CONTENT_TYPES
+        .contains(&essence.as_str())
+        .then_some(essence)
+}
+
+fn guess_content_type(name: &str, data: &[u8]) -> String {
+    image::guess_format(data)
+        .or_else(|_| ImageFormat::from_path(name))
+        .map(|format| format.to_mime_type().to_string())
+        .unwrap_or_else(|_| "application/octet-stream".to_string())
+}
+
+#[derive(Debug, Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+struct AssetQuery {
+    version: Option<u32>,
+    /// Longest side of a png thumbnail, only for images.
+    thumbnail: Option<u32>,
+}
+
+async fn list_assets(
+    State(store): State<SharedAssetStore>,
+) -> Result<Json<Vec<AssetInfo>>, StatusCode> {
+    let mut assets: BTreeMap<String, AssetInfo> = store
+        .index
+        .read()
+        .await
+        .iter()
+        .filter_map(|(name, versions)| Some((name.clone(), AssetInfo::uploaded(name, versions)?)))
+        .collect();
+
+    let mut entries = fs::read_dir(&store.static_dir).await.map_err(|e| {
+        tracing::error!("error while listing the static assets {e}");
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+    while let Some(entry) = entries.next_entry().await.map_err(|e| {
+        tracing::error!("error while listing the static assets {e}");
+        StatusCode::INTERNAL_SERVER_ERROR
+    })? {
+        let name = entry.file_name().to_string_lossy().to_string();
+        if assets.contains_key(&name) || validate_asset_name(&name).is_err() {
+            continue;
+        }
+        let Ok(data) = fs::read(entry.path()).await else {
+            continue;
+        };
+        assets.insert(
+            name.clone(),
+            AssetInfo {
+                etag: etag(&hex(&Sha256::digest(&data))),
+                size: data.len() as u64,
+                content_type: guess_content_type(&name, &data),
+                version: None,
+                versions: None,
+                uploaded_at: None,
+                name,
+            },
+        );
+    }
+    Ok(Json(assets.into_values().collect()))
+}
 
-        if name == "image" && content_type == "image/png" {
-            let data = field
-                .bytes()
+async fn get_asset(
+    State(store): State<SharedAssetStore>,
+    Path(name): Path<String>,
+    Query(query): Query<AssetQuery>,
+    headers: HeaderMap,
+) -> Result<Response, StatusCode> {
+    validate_asset_path(&name)?;
+    let uploaded = {
+        let index = store.index.read().await;
+        match (index.get(&name), query.version) {
+            (Some(versions), Some(version)) => Some(
+                versions
+                    .iter()
+                    .find(|v| v.version == version)
+                    .cloned()
+                    .ok_or(StatusCode::NOT_FOUND)?,
+            ),
+            (Some(versions), None) => versions.last().cloned(),
+            (None, _) => None,
+        }
+    };
+    let (sha256, content_type, data) = match uploaded {
+        Some(version) => {
+            let data = fs::read(store.object_path(&version.sha256))
                 .await
                 .map_err(|e| {
-                    tracing::error!("error getting bytes of the image {e}");
-                    StatusCode::BAD_REQUEST
-                })?
-                .to_vec();
-            let red_pixels = image::load_from_memory(data.as_slice())
-                .map_err(|e| {
-                    tracing::error!("error while loading image {e}");
-                    StatusCode::BAD_REQUEST
-                })?
-                .pixels()
-                .fold(0, |red_pixels, (_, _, Rgba([r, g, b, _]))| {
-                    if r as u32 > g as u32 + b as u32 {
-                        red_pixels + 1
-                    } else {
-                        red_pixels
-                    }
-                });
-            return Ok(format!("{red_pixels}"));
+                    tracing::error!("error while reading asset {name} {e}");
+                    StatusCode::INTERNAL_SERVER_ERROR
+                })?;
+            // versions stored before uploads were checked may claim anything
+            let content_type = allowed_content_type(&version.content_type)
+                .unwrap_or_else(|| "application/octet-stream".to_string());
+            (version.sha256, content_type, data)
+        }
+        None if query.version.is_some() => return Err(StatusCode::NOT_FOUND),
+        None => {
+            let data = fs::read(store.static_dir.join(&name)).await.map_err(|e| {
+                tracing::error!("error while reading static asset {name} {e}");
+                StatusCode::NOT_FOUND
+            })?;
+            let content_type = guess_content_type(&name, &data);
+            (hex(&Sha256::digest(&data)), content_type, data)
+        }
+    };
+
+    let (etag, content_type, data) = match query.thumbnail {
+        Some(size) => {
+            let data = thumbnail(&store, &sha256, data, size).await?;
+            (
+                format!("\"{sha256}-thumbnail-{size}\""),
+                ImageFormat::Png.to_mime_type().to_string(),
+                data,
+            )
+        }
+        None => (etag(&sha256), content_type, data),
+    };
+    Ok(conditional_response(&headers, etag, content_type, data))
+}
+
+async fn thumbnail(
+    store: &AssetStore,
+    sha256: &str,
+    data: Vec<u8>,
+    size: u32,
+) -> Result<Vec<u8>, StatusCode> {
+    if size == 0 || size > MAX_THUMBNAIL_SIZE {
+        tracing::error!("thumbnail size must be between 1 and {MAX_THUMBNAIL_SIZE}");
+        return Err(StatusCode::BAD_REQUEST);
+    }
+    let path = store.thumbnail_path(sha256, size);
+    if let Ok(data) = fs::read(&path).await {
+        return Ok(data);
+    }
+    let thumbnail = run_blocking(move || {
+        let (_, img) = load_image(&data)?;
+        let mut thumbnail = vec![];
+        img.thumbnail(size, size)
+            .write_to(&mut Cursor::new(&mut thumbnail), ImageOutputFormat::Png)
+            .map_err(|e| {
+                tracing::error!("error while encoding the thumbnail {e}");
+                StatusCode::INTERNAL_SERVER_ERROR
+            })?;
+        Ok(thumbnail)
+    })
+    .await?;
+    // the thumbnail can always be made again, so a failed write is not fatal
+    if let Err(e) = store.write_atomic(&path, &thumbnail).await {
+        tracing::error!("error while caching the thumbnail {e}");
+    }
+    Ok(thumbnail)
+}
+
+/// Answers `If-None-Match` with a 304 and a single `Range` with a 206,
+/// anything else gets the whole body.
+fn conditional_response(
+    headers: &HeaderMap,
+    etag: String,
+    content_type: String,
+    data: Vec<u8>,
+) -> Response {
+    let if_none_match = headers
+        .get(header::IF_NONE_MATCH)
+        .and_then(|v| v.to_str().ok());
+    if if_none_match.is_some_and(|v| {
+        v.split(',')
+            .map(|tag| tag.trim().trim_start_matches("W/"))
+            .any(|tag| tag == "*" || tag == etag)
+    }) {
+        return (StatusCode::NOT_MODIFIED, [(header::ETAG, etag)]).into_response();
+    }
+
+    let common = [
+        (header::ETAG, etag),
+        (header::CONTENT_TYPE, content_type),
+        (header::ACCEPT_RANGES, "bytes".to_string()),
+        (header::X_CONTENT_TYPE_OPTIONS, "nosniff".to_string()),
+    ];
+    let len = data.len() as u64;
+    match headers
+        .get(header::RANGE)
+        .and_then(|v| v.to_str().ok())
+        .map(|v| parse_range(v, len))
+    {
+        Some(Ok(Some((start, end)))) => (
+            StatusCode::PARTIAL_CONTENT,
+            common,
+            [(header::CONTENT_RANGE, format!("bytes {start}-{end}/{len}"))],
+            data[start as usize..=end as usize].to_vec(),
+        )
+            .into_response(),
+        Some(Err(())) => (
+            StatusCode::RANGE_NOT_SATISFIABLE,
+            [(header::CONTENT_RANGE, format!("bytes */{len}"))],
+        )
+            .into_response(),
+        Some(Ok(None)) | None => (common, data).into_response(),
+    }
+}
+
+/// Parses a single `bytes=` range into inclusive offsets. Ranges this does
+/// not understand, like several at once, give `Ok(None)` so the whole body
+/// is sent, and ranges past the end give `Err`.
+fn parse_range(range: &str, len: u64) -> Result<Option<(u64, u64)>, ()> {
+    let Some((start, end)) = range
+        .strip_prefix("bytes=")
+        .filter(|r| !r.contains(','))
+        .and_then(|r| r.trim().split_once('-'))
+    else {
+        return Ok(None);
+    };
+    let (start, end) = match (start.parse::<u64>(), end.parse::<u64>()) {
+        (Ok(start), Ok(end)) if start <= end => (start, end.min(len.saturating_sub(1))),
+        (Ok(start), Err(_)) if end.is_empty() => (start, len.saturating_sub(1)),
+        // the last `end` bytes
+        (Err(_), Ok(end)) if start.is_empty() && end > 0 => {
+            (len.saturating_sub(end), len.saturating_sub(1))
+        }
+        _ => return Ok(None),
+    };
+    if start >= len {
+        return Err(());
+    }
+    Ok(Some((start, end)))
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+async fn put_asset(
+    State(store): State<SharedAssetStore>,
+    Path(name): Path<String>,
+    authorization: Option<TypedHeader<Authorization<Bearer>>>,
+    headers: HeaderMap,
+    data: Bytes,
+) -> Result<(StatusCode, Json<AssetInfo>), StatusCode> {
+    check_bearer(store.upload_token.as_deref(), authorization)?;
+    validate_asset_name(&name)?;
+    let sha256 = hex(&Sha256::digest(&data));
+    let content_type = match headers.get(header::CONTENT_TYPE) {
+        Some(content_type) => content_type
+            .to_str()
+            .ok()
+            .and_then(allowed_content_type)
+            .ok_or_else(|| {
+                tracing::error!("asset {name} can't be served as {content_type:?}");
+                StatusCode::UNSUPPORTED_MEDIA_TYPE
+            })?,
+        None => guess_content_type(&name, &data),
+    };
+
+    let mut index = store.index.write().await;
+    let latest = index.get(&name).and_then(|versions| versions.last());
+    // uploading the same content again does not make a new version
+    if latest.is_some_and(|v| v.sha256 == sha256 && v.content_type == content_type) {
+        let info =
+            AssetInfo::uploaded(&name, &index[&name]).ok_or(StatusCode::INTERNAL_SERVER_ERROR)?;
+        return Ok((StatusCode::OK, Json(info)));
+    }
+    let version = latest.map_or(1, |v| v.version + 1);
+
+    let path = store.object_path(&sha256);
+    if fs::metadata(&path).await.is_err() {
+        let write = async {
+            fs::create_dir_all(path.parent().unwrap_or(&store.store_dir)).await?;
+            store.write_atomic(&path, &data).await
+        };
+        write.await.map_err(|e| {
+            tracing::error!("error while storing asset {name} {e}");
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?;
+    }
+    let versions = index.entry(name.clone()).or_default();
+    versions.push(AssetVersion {
+        version,
+        sha256,
+        size: data.len() as u64,
+        content_type,
+        uploaded_at: store.clock.now().timestamp().max(0) as u64,
+    });
+    let info = AssetInfo::uploaded(&name, versions).ok_or(StatusCode::INTERNAL_SERVER_ERROR)?;
+    store.save_index(&index).await.map_err(|e| {
+        tracing::error!("error while saving the asset index {e}");
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+    Ok((StatusCode::CREATED, Json(info)))
+}
+
+async fn delete_asset(
+    State(store): State<SharedAssetStore>,
+    Path(name): Path<String>,
+    authorization: Option<TypedHeader<Authorization<Bearer>>>,
+) -> Result<StatusCode, StatusCode> {
+    check_bearer(store.upload_token.as_deref(), authorization)?;
+    validate_asset_name(&name)?;
+    let mut index = store.index.write().await;
+    let removed = index.remove(&name).ok_or_else(|| {
+        tracing::error!("no uploaded asset named {name}");
+        StatusCode::NOT_FOUND
+    })?;
+    store.save_index(&index).await.map_err(|e| {
+        tracing::error!("error while saving the asset index {e}");
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+
+    // content is shared between names and versions, only drop what is unused now
+    let in_use: HashSet<&str> = index
+        .values()
+        .flatten()
+        .map(|v| v.sha256.as_str())
+        .collect();
+    let unused: HashSet<&str> = removed
+        .iter()
+        .map(|v| v.sha256.as_str())
+        .filter(|sha256| !in_use.contains(sha256))
+        .collect();
+    for sha256 in unused {
+        if let Err(e) = fs::remove_file(store.object_path(sha256)).await {
+            tracing::error!("error while removing asset content {sha256} {e}");
+        }
+        if let Ok(mut thumbnails) = fs::read_dir(store.store_dir.join("thumbnails")).await {
+            while let Ok(Some(entry)) = thumbnails.next_entry().await {
+                if entry.file_name().to_string_lossy().starts_with(sha256) {
+                    let _ = fs::remove_file(entry.path()).await;
+                }
+            }
         }
     }
-    Ok("0".to_string())
+    Ok(StatusCode::NO_CONTENT)
 }
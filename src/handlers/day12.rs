@@ -1,91 +1,999 @@
 use std::{
-    collections::HashMap,
-    sync::{Arc, RwLock},
-    time::Instant,
+    collections::{BTreeMap, BTreeSet, HashMap},
+    fmt,
+    path::{Path as FsPath, PathBuf},
+    sync::{Arc, Mutex, RwLock},
+    time::{Duration, SystemTime},
 };
 
+use async_trait::async_trait;
 use axum::{
-    extract::{Path, State},
+    extract::{Path, Query, State},
     http::StatusCode,
+    response::{IntoResponse, Response},
     routing::{get, post},
     Json, Router,
 };
-use chrono::{DateTime, Datelike, Utc, Weekday};
+use axum_extra::{
+    headers::{authorization::Bearer, Authorization},
+    TypedHeader,
+};
+use chrono::{DateTime, Datelike, Timelike, Utc, Weekday};
+use chrono_tz::Tz;
 use serde::{Deserialize, Serialize};
-use std::convert::Into;
-use ulid::Ulid;
+use sqlx::PgPool;
+use tokio::task::JoinHandle;
+use ulid::{Generator, Ulid};
 use uuid::Uuid;
 
-type SharedState = Arc<RwLock<PacketState>>;
+use super::check_bearer;
+use crate::{clock::SharedClock, config::PacketStoreKind};
+
+// Limits for the ULID and UUID toolkit.
+const MAX_IDS: usize = 10_000;
+const MAX_GENERATED: usize = 1_000;
+const MAX_DATE_PREDICATES: usize = 64;
+
+pub type SharedPacketStore = Arc<dyn PacketStore>;
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct Packet {
+    pub id: String,
+    pub saved_at: DateTime<Utc>,
+    pub expires_at: Option<DateTime<Utc>>,
+}
+
+impl Packet {
+    fn is_live(&self, now: DateTime<Utc>) -> bool {
+        match self.expires_at {
+            Some(expires_at) => expires_at > now,
+            None => true,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct PacketStoreError(String);
+
+impl fmt::Display for PacketStoreError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "packet store failure: {}", self.0)
+    }
+}
+
+impl From<PacketStoreError> for StatusCode {
+    fn from(_: PacketStoreError) -> Self {
+        StatusCode::INTERNAL_SERVER_ERROR
+    }
+}
+
+/// Where saved packets live. Expired packets are never returned, `now` is
+/// passed in so the stores do not read the time themselves.
+#[async_trait]
+pub trait PacketStore: Send + Sync {
+    async fn save(&self, packet: Packet) -> Result<(), PacketStoreError>;
+    async fn load(&self, id: &str, now: DateTime<Utc>) -> Result<Option<Packet>, PacketStoreError>;
+    /// Whether a live packet was there to delete.
+    async fn delete(&self, id: &str, now: DateTime<Utc>) -> Result<bool, PacketStoreError>;
+    async fn list(&self, now: DateTime<Utc>) -> Result<Vec<Packet>, PacketStoreError>;
+    /// Drops the expired packets and returns how many there were.
+    async fn sweep(&self, now: DateTime<Utc>) -> Result<u64, PacketStoreError>;
+}
+
+/// Packets kept in process, gone on restart.
+#[derive(Default)]
+pub struct MemoryPacketStore {
+    packets: RwLock<HashMap<String, Packet>>,
+}
+
+impl MemoryPacketStore {
+    fn read(
+        &self,
+    ) -> Result<std::sync::RwLockReadGuard<'_, HashMap<String, Packet>>, PacketStoreError> {
+        self.packets
+            .read()
+            .map_err(|e| PacketStoreError(format!("error while getting read lock {e}")))
+    }
 
-#[derive(Default, Clone)]
-struct PacketState {
-    packet_saved_at: HashMap<String, Instant>,
+    fn write(
+        &self,
+    ) -> Result<std::sync::RwLockWriteGuard<'_, HashMap<String, Packet>>, PacketStoreError> {
+        self.packets
+            .write()
+            .map_err(|e| PacketStoreError(format!("error while getting write lock {e}")))
+    }
 }
-pub fn router() -> Router {
-    let packet_state = Arc::new(RwLock::new(PacketState {
-        packet_saved_at: HashMap::new(),
-    }));
 
-    Router::new()
+#[async_trait]
+impl PacketStore for MemoryPacketStore {
+    async fn save(&self, packet: Packet) -> Result<(), PacketStoreError> {
+        self.write()?.insert(packet.id.clone(), packet);
+        Ok(())
+    }
+
+    async fn load(&self, id: &str, now: DateTime<Utc>) -> Result<Option<Packet>, PacketStoreError> {
+        Ok(self.read()?.get(id).filter(|p| p.is_live(now)).cloned())
+    }
+
+    async fn delete(&self, id: &str, now: DateTime<Utc>) -> Result<bool, PacketStoreError> {
+        Ok(self.write()?.remove(id).is_some_and(|p| p.is_live(now)))
+    }
+
+    async fn list(&self, now: DateTime<Utc>) -> Result<Vec<Packet>, PacketStoreError> {
+        let mut packets: Vec<Packet> = self
+            .read()?
+            .values()
+            .filter(|p| p.is_live(now))
+            .cloned()
+            .collect();
+        packets.sort_by(|a, b| (a.saved_at, &a.id).cmp(&(b.saved_at, &b.id)));
+        Ok(packets)
+    }
+
+    async fn sweep(&self, now: DateTime<Utc>) -> Result<u64, PacketStoreError> {
+        let mut packets = self.write()?;
+        let before = packets.len();
+        packets.retain(|_, p| p.is_live(now));
+        Ok((before - packets.len()) as u64)
+    }
+}
+
+/// The in memory store, written to a json file after every change.
+pub struct DiskPacketStore {
+    path: PathBuf,
+    memory: MemoryPacketStore,
+    // one writer at a time so an older snapshot never lands last
+    persist: tokio::sync::Mutex<()>,
+}
+
+impl DiskPacketStore {
+    pub fn open(dir: &FsPath) -> std::io::Result<Self> {
+        std::fs::create_dir_all(dir)?;
+        let path = dir.join("packets.json");
+        let packets = match std::fs::read(&path) {
+            Ok(data) => serde_json::from_slice(&data)?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => HashMap::new(),
+            Err(e) => return Err(e),
+        };
+        Ok(Self {
+            path,
+            memory: MemoryPacketStore {
+                packets: RwLock::new(packets),
+            },
+            persist: tokio::sync::Mutex::new(()),
+        })
+    }
+
+    async fn persist(&self) -> Result<(), PacketStoreError> {
+        let _guard = self.persist.lock().await;
+        let data = serde_json::to_vec(&*self.memory.read()?)
+            .map_err(|e| PacketStoreError(format!("error while serializing packets {e}")))?;
+        // written aside and renamed so a crash never leaves half a file
+        let tmp = self.path.with_extension("json.tmp");
+        tokio::fs::write(&tmp, data)
+            .await
+            .map_err(|e| PacketStoreError(format!("error while writing packets {e}")))?;
+        tokio::fs::rename(&tmp, &self.path)
+            .await
+            .map_err(|e| PacketStoreError(format!("error while replacing packets {e}")))
+    }
+}
+
+#[async_trait]
+impl PacketStore for DiskPacketStore {
+    async fn save(&self, packet: Packet) -> Result<(), PacketStoreError> {
+        self.memory.save(packet).await?;
+        self.persist().await
+    }
+
+    async fn load(&self, id: &str, now: DateTime<Utc>) -> Result<Option<Packet>, PacketStoreError> {
+        self.memory.load(id, now).await
+    }
+
+    async fn delete(&self, id: &str, now: DateTime<Utc>) -> Result<bool, PacketStoreError> {
+        let deleted = self.memory.delete(id, now).await?;
+        self.persist().await?;
+        Ok(deleted)
+    }
+
+    async fn list(&self, now: DateTime<Utc>) -> Result<Vec<Packet>, PacketStoreError> {
+        self.memory.list(now).await
+    }
+
+    async fn sweep(&self, now: DateTime<Utc>) -> Result<u64, PacketStoreError> {
+        let swept = self.memory.sweep(now).await?;
+        if swept > 0 {
+            self.persist().await?;
+        }
+        Ok(swept)
+    }
+}
+
+/// Keeps the packets in the `packets` table the migrations create.
+pub struct PgPacketStore {
+    pool: PgPool,
+}
+
+impl PgPacketStore {
+    pub fn new(pool: PgPool) -> Self {
+        Self { pool }
+    }
+}
+
+fn db_error(e: sqlx::Error) -> PacketStoreError {
+    PacketStoreError(format!("database error {e}"))
+}
+
+#[async_trait]
+impl PacketStore for PgPacketStore {
+    async fn save(&self, packet: Packet) -> Result<(), PacketStoreError> {
+        sqlx::query!(
+            r#"
+            INSERT INTO packets (id, saved_at, expires_at) VALUES ($1, $2, $3)
+            ON CONFLICT (id) DO UPDATE
+            SET saved_at = EXCLUDED.saved_at, expires_at = EXCLUDED.expires_at
+            "#,
+            packet.id,
+            packet.saved_at,
+            packet.expires_at
+        )
+        .execute(&self.pool)
+        .await
+        .map_err(db_error)?;
+        Ok(())
+    }
+
+    async fn load(&self, id: &str, now: DateTime<Utc>) -> Result<Option<Packet>, PacketStoreError> {
+        sqlx::query_as!(
+            Packet,
+            r#"
+            SELECT id, saved_at, expires_at FROM packets
+            WHERE id = $1 AND (expires_at IS NULL OR expires_at > $2)
+            "#,
+            id,
+            now
+        )
+        .fetch_optional(&self.pool)
+        .await
+        .map_err(db_error)
+    }
+
+    async fn delete(&self, id: &str, now: DateTime<Utc>) -> Result<bool, PacketStoreError> {
+        let live = self.load(id, now).await?.is_some();
+        sqlx::query!("DELETE FROM packets WHERE id = $1", id)
+            .execute(&self.pool)
+            .await
+            .map_err(db_error)?;
+        Ok(live)
+    }
+
+    async fn list(&self, now: DateTime<Utc>) -> Result<Vec<Packet>, PacketStoreError> {
+        sqlx::query_as!(
+            Packet,
+            r#"
+            SELECT id, saved_at, expires_at FROM packets
+            WHERE expires_at IS NULL OR expires_at > $1
+            ORDER BY saved_at, id
+            "#,
+            now
+        )
+        .fetch_all(&self.pool)
+        .await
+        .map_err(db_error)
+    }
+
+    async fn sweep(&self, now: DateTime<Utc>) -> Result<u64, PacketStoreError> {
+        sqlx::query!("DELETE FROM packets WHERE expires_at <= $1", now)
+            .execute(&self.pool)
+            .await
+            .map(|result| result.rows_affected())
+            .map_err(db_error)
+    }
+}
+
+pub fn packet_store(
+    kind: PacketStoreKind,
+    pool: &PgPool,
+    dir: &FsPath,
+) -> std::io::Result<SharedPacketStore> {
+    Ok(match kind {
+        PacketStoreKind::Memory => Arc::new(MemoryPacketStore::default()),
+        PacketStoreKind::Postgres => Arc::new(PgPacketStore::new(pool.clone())),
+        PacketStoreKind::Disk => Arc::new(DiskPacketStore::open(dir)?),
+    })
+}
+
+/// Drops expired packets from `store` every `every` in the background.
+pub fn spawn_packet_sweeper(
+    store: SharedPacketStore,
+    clock: SharedClock,
+    every: Duration,
+) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(every);
+        loop {
+            interval.tick().await;
+            match store.sweep(clock.now()).await {
+                Ok(0) => {}
+                Ok(swept) => tracing::info!("swept {swept} expired packets"),
+                Err(e) => tracing::error!("error while sweeping packets {e}"),
+            }
+        }
+    })
+}
+
+#[derive(Clone)]
+struct Day12State {
+    store: SharedPacketStore,
+    clock: SharedClock,
+    admin_token: Option<String>,
+    ulid_generator: Arc<Mutex<Generator>>,
+}
+
+/// `/12/clock/advance` is only there when `clock` is a manual clock, and
+/// needs `admin_token` as a bearer token.
+pub fn router(
+    packet_store: SharedPacketStore,
+    clock: SharedClock,
+    admin_token: Option<String>,
+) -> Router {
+    let mut router = Router::new();
+    if clock.as_manual().is_some() {
+        router = router.route("/12/clock/advance", post(advance_clock));
+    }
+    router
         .route("/12/health", get(|| async { StatusCode::OK }))
-        .route("/12/save/:packet_id", post(save_packet_id))
+        .route(
+            "/12/save/:packet_id",
+            post(save_packet_id).delete(delete_packet_id),
+        )
         .route("/12/load/:packet_id", get(load_packet_id))
+        .route("/12/packets", get(list_packets))
         .route("/12/ulids", post(ulids_to_uuids))
+        .route("/12/ulids/decode", post(decode_ids))
+        .route("/12/ulids/sort", post(sort_ulids))
+        .route("/12/ulids/group", post(group_ulids))
+        .route("/12/ulids/stats", post(ulids_stats))
+        .route("/12/ulids/generate", get(generate_ulids))
         .route("/12/ulids/:weekday", post(ulids_weekday))
-        .with_state(packet_state)
+        .route("/12/uuids", post(uuids_to_ulids))
+        .route("/12/uuids/generate", get(generate_uuids))
+        .with_state(Day12State {
+            store: packet_store,
+            clock,
+            admin_token,
+            ulid_generator: Arc::new(Mutex::new(Generator::new())),
+        })
+}
+
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq)]
+struct AdvanceOptions {
+    seconds: u64,
+}
+
+async fn advance_clock(
+    State(state): State<Day12State>,
+    authorization: Option<TypedHeader<Authorization<Bearer>>>,
+    Query(options): Query<AdvanceOptions>,
+) -> Result<String, StatusCode> {
+    check_bearer(state.admin_token.as_deref(), authorization)?;
+    let clock = state.clock.as_manual().ok_or(StatusCode::NOT_FOUND)?;
+    let by = chrono::Duration::from_std(Duration::from_secs(options.seconds)).map_err(|e| {
+        tracing::error!("can't advance the clock by {} seconds {e}", options.seconds);
+        StatusCode::BAD_REQUEST
+    })?;
+    let now = clock.advance(by).ok_or_else(|| {
+        tracing::error!(
+            "advancing the clock by {} seconds overflows",
+            options.seconds
+        );
+        StatusCode::BAD_REQUEST
+    })?;
+    Ok(now.to_rfc3339())
+}
+
+#[derive(Debug, Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+struct SaveOptions {
+    /// Seconds until the packet is forgotten, kept forever without it.
+    ttl: Option<u64>,
 }
 
 async fn save_packet_id(
-    State(packet_extension): State<SharedState>,
+    State(state): State<Day12State>,
     Path(packet_id): Path<String>,
+    Query(options): Query<SaveOptions>,
 ) -> Result<(), StatusCode> {
-    packet_extension
-        .write()
+    let saved_at = state.clock.now();
+    let expires_at = options
+        .ttl
+        .map(|ttl| {
+            chrono::Duration::from_std(Duration::from_secs(ttl))
+                .ok()
+                .and_then(|ttl| saved_at.checked_add_signed(ttl))
+                .ok_or_else(|| {
+                    tracing::error!("ttl {ttl} is too long");
+                    StatusCode::BAD_REQUEST
+                })
+        })
+        .transpose()?;
+    state
+        .store
+        .save(Packet {
+            id: packet_id,
+            saved_at,
+            expires_at,
+        })
+        .await
         .map_err(|e| {
-            tracing::error!("error while getting write lock {e}");
-            StatusCode::INTERNAL_SERVER_ERROR
-        })?
-        .packet_saved_at
-        .insert(packet_id, Instant::now());
-    Ok(())
+            tracing::error!("error while saving packet {e}");
+            e.into()
+        })
 }
 
 async fn load_packet_id(
-    State(packet_extension): State<SharedState>,
+    State(state): State<Day12State>,
     Path(packet_id): Path<String>,
 ) -> Result<String, StatusCode> {
-    let db = &packet_extension
-        .read()
+    let now = state.clock.now();
+    let packet = state
+        .store
+        .load(&packet_id, now)
+        .await
         .map_err(|e| {
-            tracing::error!("error while getting read lock {e}");
-            StatusCode::INTERNAL_SERVER_ERROR
+            tracing::error!("error while loading packet {e}");
+            StatusCode::from(e)
         })?
-        .packet_saved_at;
+        .ok_or(StatusCode::NOT_FOUND)?;
+    let duration_since_saved = (now - packet.saved_at).num_seconds();
+    Ok(format!("{duration_since_saved}"))
+}
 
-    if let Some(value) = db.get(&packet_id) {
-        let duration_since_saved = value.elapsed().as_secs();
-        return Ok(format!("{duration_since_saved}"));
+async fn delete_packet_id(
+    State(state): State<Day12State>,
+    Path(packet_id): Path<String>,
+) -> Result<StatusCode, StatusCode> {
+    let deleted = state
+        .store
+        .delete(&packet_id, state.clock.now())
+        .await
+        .map_err(|e| {
+            tracing::error!("error while deleting packet {e}");
+            StatusCode::from(e)
+        })?;
+    if deleted {
+        Ok(StatusCode::NO_CONTENT)
+    } else {
+        Err(StatusCode::NOT_FOUND)
     }
-    Err(StatusCode::NOT_FOUND)
 }
 
-async fn ulids_to_uuids(Json(ulids): Json<Vec<String>>) -> Json<Vec<String>> {
-    // Convert all the ULIDs to UUIDs
-    let uuids: Vec<String> = ulids
-        .iter()
-        .filter_map(|ulid| {
-            if let Ok(ulid) = Ulid::from_string(ulid) {
-                let uuid: Uuid = ulid.into();
-                Some(uuid.to_string())
+#[derive(Debug, Serialize, Clone, PartialEq, Eq)]
+struct PacketInfo {
+    id: String,
+    age: i64,
+    saved_at: DateTime<Utc>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    expires_at: Option<DateTime<Utc>>,
+}
+
+async fn list_packets(
+    State(state): State<Day12State>,
+) -> Result<Json<Vec<PacketInfo>>, StatusCode> {
+    let now = state.clock.now();
+    let packets = state.store.list(now).await.map_err(|e| {
+        tracing::error!("error while listing packets {e}");
+        StatusCode::from(e)
+    })?;
+    Ok(Json(
+        packets
+            .into_iter()
+            .map(|packet| PacketInfo {
+                age: (now - packet.saved_at).num_seconds(),
+                id: packet.id,
+                saved_at: packet.saved_at,
+                expires_at: packet.expires_at,
+            })
+            .collect(),
+    ))
+}
+
+/// An input that is not a valid id, reported with its position.
+#[derive(Debug, Serialize, Clone, PartialEq, Eq)]
+struct InvalidItem {
+    index: usize,
+    input: String,
+    error: String,
+}
+
+/// Why a list of ids was turned down.
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum IdsRejection {
+    TooMany,
+    /// Every invalid input of the request, answered with a 400.
+    Invalid(Vec<InvalidItem>),
+}
+
+impl IntoResponse for IdsRejection {
+    fn into_response(self) -> Response {
+        match self {
+            IdsRejection::TooMany => StatusCode::PAYLOAD_TOO_LARGE.into_response(),
+            IdsRejection::Invalid(errors) => (
+                StatusCode::BAD_REQUEST,
+                Json(serde_json::json!({ "errors": errors })),
+            )
+                .into_response(),
+        }
+    }
+}
+
+/// Canonical 26 character Crockford base32, lowercase is accepted. The
+/// first character only carries 3 bits so anything past `7` would overflow.
+fn parse_ulid(input: &str) -> Result<Ulid, String> {
+    if input.len() != ulid::ULID_LEN {
+        return Err(format!(
+            "expected {} characters, got {}",
+            ulid::ULID_LEN,
+            input.len()
+        ));
+    }
+    if !input.starts_with(|c: char| ('0'..='7').contains(&c)) {
+        return Err("the timestamp overflows 48 bits".to_string());
+    }
+    Ulid::from_string(input).map_err(|e| e.to_string())
+}
+
+/// Only the hyphenated form, so a ULID is never mistaken for a UUID.
+fn parse_uuid(input: &str) -> Result<Uuid, String> {
+    if input.len() != 36 {
+        return Err(format!(
+            "expected a hyphenated uuid of 36 characters, got {}",
+            input.len()
+        ));
+    }
+    Uuid::try_parse(input).map_err(|e| e.to_string())
+}
+
+/// Parses all of `inputs` or reports every one that failed.
+fn parse_all<T>(
+    inputs: &[String],
+    parse: impl Fn(&str) -> Result<T, String>,
+) -> Result<Vec<T>, IdsRejection> {
+    if inputs.len() > MAX_IDS {
+        tracing::error!("more than {MAX_IDS} ids in the request");
+        return Err(IdsRejection::TooMany);
+    }
+    let mut parsed = Vec::with_capacity(inputs.len());
+    let mut errors = vec![];
+    for (index, input) in inputs.iter().enumerate() {
+        match parse(input) {
+            Ok(value) => parsed.push(value),
+            Err(error) => errors.push(InvalidItem {
+                index,
+                input: input.clone(),
+                error,
+            }),
+        }
+    }
+    if !errors.is_empty() {
+        tracing::error!("{} invalid ids in the request", errors.len());
+        return Err(IdsRejection::Invalid(errors));
+    }
+    Ok(parsed)
+}
+
+fn ulid_datetime(ulid: &Ulid) -> DateTime<Utc> {
+    ulid.datetime().into()
+}
+
+/// The UUIDs of the ULIDs, in reverse order like the challenge wants.
+async fn ulids_to_uuids(Json(ulids): Json<Vec<String>>) -> Result<Json<Vec<String>>, IdsRejection> {
+    let uuids = parse_all(&ulids, parse_ulid)?
+        .into_iter()
+        .map(|ulid| Uuid::from(ulid).to_string())
+        .rev()
+        .collect();
+    Ok(Json(uuids))
+}
+
+/// The ULIDs of the UUIDs, in the order they were sent.
+async fn uuids_to_ulids(Json(uuids): Json<Vec<String>>) -> Result<Json<Vec<String>>, IdsRejection> {
+    let ulids = parse_all(&uuids, parse_uuid)?
+        .into_iter()
+        .map(|uuid| Ulid::from(uuid).to_string())
+        .collect();
+    Ok(Json(ulids))
+}
+
+#[derive(Debug, Serialize, Clone, PartialEq, Eq)]
+#[serde(untagged)]
+enum DecodedId {
+    Valid {
+        input: String,
+        ulid: String,
+        uuid: String,
+        timestamp_ms: u64,
+        datetime: DateTime<Utc>,
+        /// The low 80 bits, as hex.
+        randomness: String,
+    },
+    Invalid {
+        input: String,
+        error: String,
+    },
+}
+
+/// Splits ULIDs, or UUIDs holding one, into their timestamp and randomness.
+/// A UUIDv7 keeps its unix milliseconds in the same 48 bits, so it decodes
+/// to its creation time too.
+async fn decode_ids(Json(inputs): Json<Vec<String>>) -> Result<Json<Vec<DecodedId>>, StatusCode> {
+    if inputs.len() > MAX_IDS {
+        tracing::error!("more than {MAX_IDS} ids in the request");
+        return Err(StatusCode::PAYLOAD_TOO_LARGE);
+    }
+    Ok(Json(
+        inputs
+            .into_iter()
+            .map(|input| {
+                let parsed = parse_ulid(&input).or_else(|ulid_error| {
+                    parse_uuid(&input).map(Ulid::from).map_err(|uuid_error| {
+                        format!("not a ulid ({ulid_error}) nor a uuid ({uuid_error})")
+                    })
+                });
+                match parsed {
+                    Ok(ulid) => DecodedId::Valid {
+                        ulid: ulid.to_string(),
+                        uuid: Uuid::from(ulid).to_string(),
+                        timestamp_ms: ulid.timestamp_ms(),
+                        datetime: ulid_datetime(&ulid),
+                        randomness: format!("{:020x}", ulid.random()),
+                        input,
+                    },
+                    Err(error) => DecodedId::Invalid { input, error },
+                }
+            })
+            .collect(),
+    ))
+}
+
+#[derive(Debug, Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+enum SortOrder {
+    #[default]
+    Asc,
+    Desc,
+}
+
+#[derive(Debug, Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+struct SortOptions {
+    #[serde(default)]
+    order: SortOrder,
+}
+
+/// Orders ULIDs by their timestamp, ties broken by their randomness.
+async fn sort_ulids(
+    Query(options): Query<SortOptions>,
+    Json(ulids): Json<Vec<String>>,
+) -> Result<Json<Vec<String>>, IdsRejection> {
+    let mut ulids = parse_all(&ulids, parse_ulid)?;
+    ulids.sort();
+    if options.order == SortOrder::Desc {
+        ulids.reverse();
+    }
+    Ok(Json(ulids.iter().map(Ulid::to_string).collect()))
+}
+
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+enum GroupBy {
+    Year,
+    Month,
+    Day,
+    Hour,
+}
+
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq)]
+struct GroupOptions {
+    by: GroupBy,
+}
+
+/// Buckets ULIDs by the UTC year, month, day or hour of their timestamp,
+/// each bucket sorted.
+async fn group_ulids(
+    Query(options): Query<GroupOptions>,
+    Json(ulids): Json<Vec<String>>,
+) -> Result<Json<BTreeMap<String, Vec<String>>>, IdsRejection> {
+    let mut ulids = parse_all(&ulids, parse_ulid)?;
+    ulids.sort();
+    let format = match options.by {
+        GroupBy::Year => "%Y",
+        GroupBy::Month => "%Y-%m",
+        GroupBy::Day => "%Y-%m-%d",
+        GroupBy::Hour => "%Y-%m-%dT%H",
+    };
+    let mut groups: BTreeMap<String, Vec<String>> = BTreeMap::new();
+    for ulid in ulids {
+        groups
+            .entry(ulid_datetime(&ulid).format(format).to_string())
+            .or_default()
+            .push(ulid.to_string());
+    }
+    Ok(Json(groups))
+}
+
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq)]
+struct GenerateUlidsOptions {
+    #[serde(default = "default_generate_count")]
+    count: usize,
+    /// Keeps increasing within the same millisecond, across requests too.
+    #[serde(default)]
+    monotonic: bool,
+}
+
+fn default_generate_count() -> usize {
+    1
+}
+
+fn check_generate_count(count: usize) -> Result<(), StatusCode> {
+    if count == 0 || count > MAX_GENERATED {
+        tracing::error!("can generate between 1 and {MAX_GENERATED} ids, not {count}");
+        return Err(StatusCode::BAD_REQUEST);
+    }
+    Ok(())
+}
+
+async fn generate_ulids(
+    State(state): State<Day12State>,
+    Query(options): Query<GenerateUlidsOptions>,
+) -> Result<Json<Vec<String>>, StatusCode> {
+    check_generate_count(options.count)?;
+    let now = SystemTime::from(state.clock.now());
+    let ulids = if options.monotonic {
+        let mut generator = state.ulid_generator.lock().map_err(|e| {
+            tracing::error!("error while getting the ulid generator lock {e}");
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?;
+        (0..options.count)
+            .map(|_| generator.generate_from_datetime(now))
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|e| {
+                tracing::error!("error while generating ulids {e}");
+                StatusCode::SERVICE_UNAVAILABLE
+            })?
+    } else {
+        (0..options.count)
+            .map(|_| Ulid::from_datetime(now))
+            .collect()
+    };
+    Ok(Json(ulids.iter().map(Ulid::to_string).collect()))
+}
+
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq)]
+struct GenerateUuidsOptions {
+    #[serde(default = "default_generate_count")]
+    count: usize,
+    #[serde(default = "default_uuid_version")]
+    version: u8,
+}
+
+fn default_uuid_version() -> u8 {
+    4
+}
+
+async fn generate_uuids(
+    State(state): State<Day12State>,
+    Query(options): Query<GenerateUuidsOptions>,
+) -> Result<Json<Vec<String>>, StatusCode> {
+    check_generate_count(options.count)?;
+    let millis = state.clock.now().timestamp_millis().max(0) as u64;
+    let uuids = (0..options.count).map(|_| match options.version {
+        4 => Ok(uuid::Builder::from_random_bytes(rand::random()).into_uuid()),
+        7 => Ok(uuid::Builder::from_unix_timestamp_millis(millis, &rand::random()).into_uuid()),
+        version => {
+            tracing::error!("can only generate version 4 or 7 uuids, not {version}");
+            Err(StatusCode::BAD_REQUEST)
+        }
+    });
+    Ok(Json(
+        uuids
+            .map(|uuid| uuid.map(|uuid| uuid.to_string()))
+            .collect::<Result<_, _>>()?,
+    ))
+}
+
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "lowercase")]
+enum Bucket {
+    Year,
+    Month,
+    Day,
+    Weekday,
+    Hour,
+}
+
+impl Bucket {
+    const ALL: [Bucket; 5] = [
+        Bucket::Year,
+        Bucket::Month,
+        Bucket::Day,
+        Bucket::Weekday,
+        Bucket::Hour,
+    ];
+
+    fn name(self) -> &'static str {
+        match self {
+            Bucket::Year => "year",
+            Bucket::Month => "month",
+            Bucket::Day => "day",
+            Bucket::Weekday => "weekday",
+            Bucket::Hour => "hour",
+        }
+    }
+
+    fn key(self, date: &DateTime<Tz>) -> String {
+        let format = match self {
+            Bucket::Year => "%Y",
+            Bucket::Month => "%Y-%m",
+            Bucket::Day => "%Y-%m-%d",
+            Bucket::Weekday => "%a",
+            Bucket::Hour => "%H",
+        };
+        date.format(format).to_string()
+    }
+}
+
+/// Matches the dates where every given field holds, in the requested time zone.
+#[derive(Debug, Deserialize, Clone, PartialEq)]
+struct DatePredicate {
+    name: String,
+    year: Option<i32>,
+    month: Option<u32>,
+    day: Option<u32>,
+    weekdays: Option<Vec<Weekday>>,
+    /// Inclusive range of hours, `[22, 2]` wraps around midnight.
+    hours: Option<(u32, u32)>,
+    after: Option<DateTime<Utc>>,
+    before: Option<DateTime<Utc>>,
+}
+
+impl DatePredicate {
+    fn matches(&self, date: &DateTime<Tz>) -> bool {
+        let in_hours = |(from, to): (u32, u32)| {
+            let hour = date.hour();
+            if from <= to {
+                from <= hour && hour <= to
             } else {
-                None
+                hour >= from || hour <= to
             }
-        })
-        .rev()
+        };
+        holds(self.year, |year| date.year() == year)
+            && holds(self.month, |month| date.month() == month)
+            && holds(self.day, |day| date.day() == day)
+            && holds(self.weekdays.as_ref(), |weekdays| {
+                weekdays.contains(&date.weekday())
+            })
+            && holds(self.hours, in_hours)
+            && holds(self.after, |after| *date > after)
+            && holds(self.before, |before| *date < before)
+    }
+}
+
+/// A field that was left out matches everything.
+fn holds<T>(field: Option<T>, check: impl FnOnce(T) -> bool) -> bool {
+    match field {
+        Some(value) => check(value),
+        None => true,
+    }
+}
+
+#[derive(Debug, Deserialize, Clone, PartialEq)]
+struct StatsRequest {
+    ulids: Vec<String>,
+    /// An IANA time zone name like `Europe/Stockholm`.
+    #[serde(default = "default_timezone")]
+    timezone: String,
+    #[serde(default = "default_buckets")]
+    buckets: Vec<Bucket>,
+    #[serde(default)]
+    predicates: Vec<DatePredicate>,
+}
+
+fn default_timezone() -> String {
+    "UTC".to_string()
+}
+
+fn default_buckets() -> Vec<Bucket> {
+    Bucket::ALL.to_vec()
+}
+
+#[derive(Debug, Serialize, Clone, PartialEq, Eq)]
+struct StatsTimestamp {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    ulid: Option<String>,
+    timestamp_ms: u64,
+    datetime: String,
+}
+
+#[derive(Debug, Serialize, Clone, PartialEq, Eq)]
+struct StatsResult {
+    count: usize,
+    timezone: String,
+    min: Option<StatsTimestamp>,
+    max: Option<StatsTimestamp>,
+    /// The mean of the two middle timestamps when the count is even.
+    median: Option<StatsTimestamp>,
+    buckets: BTreeMap<&'static str, BTreeMap<String, usize>>,
+    predicates: BTreeMap<String, usize>,
+}
+
+async fn ulids_stats(Json(request): Json<StatsRequest>) -> Result<Json<StatsResult>, Response> {
+    let tz: Tz = request.timezone.parse().map_err(|e| {
+        tracing::error!("unknown time zone {} {e}", request.timezone);
+        StatusCode::BAD_REQUEST.into_response()
+    })?;
+    if request.predicates.len() > MAX_DATE_PREDICATES {
+        tracing::error!("more than {MAX_DATE_PREDICATES} date predicates");
+        return Err(StatusCode::PAYLOAD_TOO_LARGE.into_response());
+    }
+    let mut ulids = parse_all(&request.ulids, parse_ulid).map_err(IntoResponse::into_response)?;
+    ulids.sort();
+    let dates: Vec<DateTime<Tz>> = ulids
+        .iter()
+        .map(|ulid| ulid_datetime(ulid).with_timezone(&tz))
         .collect();
-    Json(uuids)
+
+    let timestamp = |timestamp_ms: u64, ulid: Option<&Ulid>| {
+        let datetime = DateTime::<Utc>::from_timestamp(
+            (timestamp_ms / 1000) as i64,
+            (timestamp_ms % 1000) as u32 * 1_000_000,
+        )
+        .unwrap_or_default()
+        .with_timezone(&tz);
+        StatsTimestamp {
+            ulid: ulid.map(Ulid::to_string),
+            timestamp_ms,
+            datetime: datetime.to_rfc3339(),
+        }
+    };
+    let median = match ulids.len() {
+        0 => None,
+        len if len % 2 == 1 => Some(ulids[len / 2].timestamp_ms()),
+        len => {
+            let (a, b) = (
+                ulids[len / 2 - 1].timestamp_ms(),
+                ulids[len / 2].timestamp_ms(),
+            );
+            Some(a + (b - a) / 2)
+        }
+    };
+
+    let mut buckets: BTreeMap<&'static str, BTreeMap<String, usize>> = BTreeMap::new();
+    for bucket in request.buckets.iter().collect::<BTreeSet<_>>() {
+        let counts = buckets.entry(bucket.name()).or_default();
+        for date in &dates {
+            *counts.entry(bucket.key(date)).or_default() += 1;
+        }
+    }
+
+    Ok(Json(StatsResult {
+        count: ulids.len(),
+        timezone: tz.name().to_string(),
+        min: ulids.first().map(|u| timestamp(u.timestamp_ms(), Some(u))),
+        max: ulids.last().map(|u| timestamp(u.timestamp_ms(), Some(u))),
+        median: median.map(|ms| timestamp(ms, None)),
+        buckets,
+        predicates: request
+            .predicates
+            .iter()
+            .map(|p| {
+                (
+                    p.name.clone(),
+                    dates.iter().filter(|d| p.matches(d)).count(),
+                )
+            })
+            .collect(),
+    }))
 }
+
 #[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct UlidsWeekdayResult {
     #[serde(rename = "christmas eve")]
@@ -98,25 +1006,17 @@ pub struct UlidsWeekdayResult {
 }
 
 async fn ulids_weekday(
+    State(state): State<Day12State>,
     Path(weekday): Path<u8>,
     Json(ulids): Json<Vec<String>>,
-) -> Result<Json<UlidsWeekdayResult>, StatusCode> {
+) -> Result<Json<UlidsWeekdayResult>, Response> {
     let weekday = Weekday::try_from(weekday).map_err(|e| {
         tracing::error!("Failed to parse weekday  {e}");
-        StatusCode::BAD_REQUEST
+        StatusCode::BAD_REQUEST.into_response()
     })?;
-    // Convert all the ULIDs to UUIDs
-    let dates: Vec<DateTime<Utc>> = ulids
-        .iter()
-        .filter_map(|ulid| {
-            if let Ok(ulid) = Ulid::from_string(ulid) {
-                let day: DateTime<Utc> = ulid.datetime().into();
-                Some(day)
-            } else {
-                None
-            }
-        })
-        .collect();
+    let now = state.clock.now();
+    let ulids = parse_all(&ulids, parse_ulid).map_err(IntoResponse::into_response)?;
+    let dates: Vec<DateTime<Utc>> = ulids.iter().map(ulid_datetime).collect();
 
     Ok(Json(UlidsWeekdayResult {
         christmas_eve: dates
@@ -127,11 +1027,7 @@ async fn ulids_weekday(
             .iter()
             .filter(|date| date.weekday() == weekday)
             .count(),
-        in_the_future: dates.iter().filter(|date| date > &&Utc::now()).count(),
-        lsb_is_1: ulids
-            .iter()
-            .map(|ulid| Ulid::from_string(ulid).unwrap())
-            .filter(|ulid| ulid.0 & 1 == 1)
-            .count(),
+        in_the_future: dates.iter().filter(|date| date > &&now).count(),
+        lsb_is_1: ulids.iter().filter(|ulid| ulid.0 & 1 == 1).count(),
     }))
 }
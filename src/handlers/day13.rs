@@ -1,12 +1,31 @@
+use std::{collections::HashSet, hash::Hash};
+
 use axum::{
-    extract::State,
-    http::StatusCode,
+    body::Body,
+    extract::{Path, Query, State},
+    http::{HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
     routing::{get, post},
     Json, Router,
 };
+use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
 use serde_json::{json, Value};
-use sqlx::{PgPool, Pool, Postgres};
+use sqlparser::{
+    ast::{Query as SqlQuery, SetExpr, Statement},
+    dialect::PostgreSqlDialect,
+    parser::Parser,
+};
+use sqlx::{
+    postgres::PgCopyIn, Column, Connection, Either, Executor, PgConnection, PgPool, Pool, Postgres,
+    Statement as _, Transaction,
+};
+
+use super::{
+    bulk::{self, BulkFormat, ExportTableQuery, ImportQuery, ImportRow, ParquetColumn},
+    day18::{csv_response, ExportFormat, ExportQuery},
+    tenants::Namespace,
+};
 
 #[derive(Clone)]
 pub struct SqlState {
@@ -16,9 +35,18 @@ pub struct SqlState {
 pub fn router(pool: Pool<Postgres>) -> Router {
     Router::new()
         .route("/13/health", get(|| async { StatusCode::OK }))
-        .route("/13/sql", get(sequal))
+        .route("/13/sql", get(sequal).post(run_sql))
         .route("/13/reset", post(reset))
-        .route("/13/orders", post(create_orders))
+        .route("/13/orders", get(list_orders).post(create_orders))
+        .route("/13/orders/import", post(import_orders))
+        .route("/13/orders/export", get(export_orders))
+        .route(
+            "/13/orders/:id",
+            get(get_order)
+                .put(replace_order)
+                .patch(update_order)
+                .delete(delete_order),
+        )
         .route("/13/orders/total", get(total_orders))
         .route("/13/orders/popular", get(popular))
         .with_state(SqlState { pool })
@@ -42,36 +70,357 @@ async fn sequal(State(state): State<SqlState>) -> Result<String, StatusCode> {
     Ok(format!("{row}"))
 }
 
-async fn reset(State(state): State<SqlState>) -> Result<(), StatusCode> {
-    // drop orders table if exists
-    tracing::info!("reset orders called");
-    sqlx::query!("DROP TABLE IF EXISTS orders;")
-        .execute(&state.pool)
+/// Rows an ad-hoc query answers with when it doesn't ask for a limit.
+const DEFAULT_SQL_ROWS: i64 = 100;
+const MAX_SQL_ROWS: i64 = 1_000;
+/// How long postgres lets an ad-hoc query run before cancelling it.
+const SQL_STATEMENT_TIMEOUT_MS: u32 = 5_000;
+/// The role ad-hoc queries on the shared tables run as, the migrations only
+/// let it read them.
+pub(crate) const SQL_READER: &str = "sql_reader";
+
+#[derive(Debug, Deserialize)]
+pub struct SqlRequest {
+    pub query: String,
+    /// Bound to `$1`, `$2`, ... by their JSON type, strings and nulls go in
+    /// as text so other types need a cast in the query (`$1::timestamptz`).
+    #[serde(default)]
+    pub params: Vec<Value>,
+    pub limit: Option<i64>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct SqlResult {
+    pub columns: Vec<String>,
+    pub rows: Vec<Vec<Value>>,
+    /// Whether the query had more rows than the limit let through.
+    pub truncated: bool,
+}
+
+/// Accepts a single SELECT that neither writes nor locks, and returns it
+/// without its trailing semicolon so it can be wrapped in a subquery.
+fn read_only_query(sql: &str) -> Result<&str, FieldError> {
+    let statements = Parser::parse_sql(&PostgreSqlDialect {}, sql)
+        .map_err(|e| FieldError::new("query", e.to_string()))?;
+    let [Statement::Query(query)] = statements.as_slice() else {
+        return Err(FieldError::new(
+            "query",
+            "must be a single SELECT statement",
+        ));
+    };
+    if !only_reads(query) {
+        return Err(FieldError::new("query", "must not write or lock rows"));
+    }
+    Ok(sql.trim().trim_end_matches(';'))
+}
+
+fn only_reads(query: &SqlQuery) -> bool {
+    query.locks.is_empty()
+        && query
+            .with
+            .iter()
+            .flat_map(|with| &with.cte_tables)
+            .all(|cte| only_reads(&cte.query))
+        && set_only_reads(&query.body)
+}
+
+fn set_only_reads(body: &SetExpr) -> bool {
+    match body {
+        SetExpr::Select(select) => select.into.is_none(),
+        SetExpr::Query(query) => only_reads(query),
+        SetExpr::SetOperation { left, right, .. } => set_only_reads(left) && set_only_reads(right),
+        SetExpr::Values(_) | SetExpr::Table(_) => true,
+        SetExpr::Insert(_) | SetExpr::Update(_) => false,
+    }
+}
+
+/// Errors raised by postgres are the analyst's to fix, anything else is ours.
+fn sql_error(e: sqlx::Error) -> OrdersRejection {
+    match e {
+        sqlx::Error::Database(e) => {
+            tracing::info!("ad-hoc query rejected by the database {e}");
+            OrdersRejection::Invalid(vec![FieldError::new("query", e.message())])
+        }
+        e => {
+            tracing::error!("error running ad-hoc query {e}");
+            StatusCode::INTERNAL_SERVER_ERROR.into()
+        }
+    }
+}
+
+/// Runs an analyst's SELECT as the namespace's reader in a read-only
+/// transaction under a statement timeout, answering with at most `limit`
+/// rows as JSON or CSV.
+async fn run_sql(
+    State(state): State<SqlState>,
+    namespace: Namespace,
+    Query(export): Query<ExportQuery>,
+    Json(request): Json<SqlRequest>,
+) -> Result<Response, OrdersRejection> {
+    tracing::info!("run sql called {request:?}");
+    let limit = request.limit.unwrap_or(DEFAULT_SQL_ROWS);
+    let query = read_only_query(&request.query);
+    let mut errors: Vec<FieldError> = query.clone().err().into_iter().collect();
+    if !(1..=MAX_SQL_ROWS).contains(&limit) {
+        errors.push(FieldError::new(
+            "limit",
+            format!("must be between 1 and {MAX_SQL_ROWS}"),
+        ));
+    }
+    reject_invalid(errors)?;
+    let query = query.map_err(|e| OrdersRejection::Invalid(vec![e]))?;
+
+    let mut db = namespace.connect(&state.pool).await?;
+    let mut transaction = db.begin().await.map_err(|e| {
+        tracing::error!("error starting transaction {e}");
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+    transaction
+        .execute(
+            format!(
+                "set transaction read only; \
+                 set local statement_timeout = {SQL_STATEMENT_TIMEOUT_MS}; \
+                 set local role \"{}\"",
+                namespace.reader()
+            )
+            .as_str(),
+        )
         .await
         .map_err(|e| {
-            tracing::error!("error droping orders table {e}");
+            tracing::error!("error making the transaction read only {e}");
             StatusCode::INTERNAL_SERVER_ERROR
         })?;
 
-    // create order table
+    let statement = (&mut *transaction)
+        .prepare(query)
+        .await
+        .map_err(sql_error)?;
+    let columns: Vec<String> = statement
+        .columns()
+        .iter()
+        .map(|column| column.name().to_string())
+        .collect();
+    let expected = match statement.parameters() {
+        Some(Either::Left(types)) => types.len(),
+        Some(Either::Right(count)) => count,
+        None => 0,
+    };
+    if request.params.len() != expected {
+        return Err(OrdersRejection::Invalid(vec![FieldError::new(
+            "params",
+            format!("expected {expected}, got {}", request.params.len()),
+        )]));
+    }
+
+    // Positional aliases keep the column order and survive duplicate names.
+    let aliases = if columns.is_empty() {
+        String::new()
+    } else {
+        let aliases: Vec<String> = (1..=columns.len()).map(|i| format!("c{i}")).collect();
+        format!("({})", aliases.join(", "))
+    };
+    let wrapped = format!(
+        "select row_to_json(t)::text from (\n{query}\n) t{aliases} limit {}",
+        limit + 1
+    );
+    let mut rows = sqlx::query_scalar::<_, String>(&wrapped);
+    for param in &request.params {
+        rows = match param {
+            Value::Null => rows.bind(None::<String>),
+            Value::Bool(value) => rows.bind(*value),
+            Value::Number(number) => match number.as_i64() {
+                Some(value) => rows.bind(value),
+                None => rows.bind(number.as_f64()),
+            },
+            Value::String(value) => rows.bind(value.as_str()),
+            other => rows.bind(other.to_string()),
+        };
+    }
+    let mut rows = rows
+        .fetch_all(&mut *transaction)
+        .await
+        .map_err(sql_error)?
+        .into_iter()
+        .map(|row| {
+            let mut row: serde_json::Map<String, Value> =
+                serde_json::from_str(&row).map_err(|e| {
+                    tracing::error!("error reading an ad-hoc query row {e}");
+                    StatusCode::INTERNAL_SERVER_ERROR
+                })?;
+            Ok((1..=columns.len())
+                .map(|i| row.remove(&format!("c{i}")).unwrap_or(Value::Null))
+                .collect())
+        })
+        .collect::<Result<Vec<Vec<Value>>, StatusCode>>()?;
+    // The transaction only read, dropping it rolls it back.
+    let truncated = rows.len() as i64 > limit;
+    rows.truncate(limit as usize);
+
+    match export.format {
+        ExportFormat::Json => Ok(Json(SqlResult {
+            columns,
+            rows,
+            truncated,
+        })
+        .into_response()),
+        ExportFormat::Csv => {
+            let rows: Vec<Vec<String>> = rows
+                .into_iter()
+                .map(|row| {
+                    row.into_iter()
+                        .map(|value| match value {
+                            Value::Null => String::new(),
+                            Value::String(value) => value,
+                            value => value.to_string(),
+                        })
+                        .collect()
+                })
+                .collect();
+            Ok(csv_response("sql", &columns, &rows)?)
+        }
+    }
+}
+
+async fn reset(State(state): State<SqlState>, namespace: Namespace) -> Result<(), StatusCode> {
+    tracing::info!("reset orders called");
+    let mut db = namespace.connect(&state.pool).await?;
+    reset_schema(&mut db, ResetScope::Orders).await
+}
+
+/// Which tables a reset starts over.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ResetScope {
+    /// Only the orders, regions and gifts are kept. This is what day 13's
+    /// reset has always done.
+    Orders,
+    /// Orders, gifts and regions, for day 18 and new tenants.
+    All,
+}
+
+/// Drops the tables in `scope` and creates whichever of the regions, gifts
+/// and orders tables of the connection's namespace are missing, in one
+/// transaction.
+///
+/// Orders may arrive before the region they belong to, so a region only
+/// known from its orders is kept as a row without a name until it is
+/// posted.
+pub async fn reset_schema(db: &mut PgConnection, scope: ResetScope) -> Result<(), StatusCode> {
+    let mut transaction = db.begin().await.map_err(|e| {
+        tracing::error!("error starting transaction {e}");
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+
+    // drop the tables, orders first as it references the other two
+    let dropped = match scope {
+        ResetScope::Orders => sqlx::query!("DROP TABLE IF EXISTS orders;"),
+        ResetScope::All => sqlx::query!("DROP TABLE IF EXISTS orders, gifts, regions;"),
+    };
+    dropped.execute(&mut *transaction).await.map_err(|e| {
+        tracing::error!("error droping tables {e}");
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+
+    // create regions table
     sqlx::query!(
         r#"
-        CREATE TABLE orders (
+        CREATE TABLE IF NOT EXISTS regions (
             id INT PRIMARY KEY,
-            region_id INT,
-            gift_name VARCHAR(50),
-            quantity INT
+            name VARCHAR(50),
+            parent_id INT REFERENCES regions (id) CHECK (parent_id <> id),
+            created_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+            updated_at TIMESTAMPTZ NOT NULL DEFAULT now()
+        );
+    "#
+    )
+    .execute(&mut *transaction)
+    .await
+    .map_err(|e| {
+        tracing::error!("error creating regions table {e}");
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+
+    // create gifts table
+    sqlx::query!(
+        r#"
+        CREATE TABLE IF NOT EXISTS gifts (
+            id SERIAL PRIMARY KEY,
+            name VARCHAR(50) NOT NULL UNIQUE,
+            created_at TIMESTAMPTZ NOT NULL DEFAULT now()
         );
     "#
     )
-    .execute(&state.pool)
+    .execute(&mut *transaction)
+    .await
+    .map_err(|e| {
+        tracing::error!("error creating gifts table {e}");
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+
+    // create orders table
+    sqlx::query!(
+        r#"
+        CREATE TABLE IF NOT EXISTS orders (
+            id INT PRIMARY KEY,
+            region_id INT NOT NULL REFERENCES regions (id),
+            gift_id INT NOT NULL REFERENCES gifts (id),
+            quantity INT NOT NULL CHECK (quantity >= 0),
+            created_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+            updated_at TIMESTAMPTZ NOT NULL DEFAULT now()
+        );
+    "#
+    )
+    .execute(&mut *transaction)
     .await
     .map_err(|e| {
         tracing::error!("error creating orders table {e}");
         StatusCode::INTERNAL_SERVER_ERROR
     })?;
+
+    transaction.commit().await.map_err(|e| {
+        tracing::error!("error commiting the transaction {e}");
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
     Ok(())
 }
+
+/// Makes sure every region and gift the orders point at has a row, adding
+/// unnamed regions and new gifts as needed.
+async fn ensure_order_refs(
+    transaction: &mut Transaction<'_, Postgres>,
+    region_ids: &[i32],
+    gift_names: &[String],
+) -> Result<(), StatusCode> {
+    sqlx::query!(
+        r#"
+        insert into regions(id)
+        select distinct region_id from unnest($1::int[]) as r(region_id)
+        on conflict (id) do nothing
+        "#,
+        region_ids
+    )
+    .execute(&mut **transaction)
+    .await
+    .map_err(|e| {
+        tracing::error!("error adding regions of the orders {e}");
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+    sqlx::query!(
+        r#"
+        insert into gifts(name)
+        select distinct name from unnest($1::text[]) as g(name)
+        on conflict (name) do nothing
+        "#,
+        gift_names
+    )
+    .execute(&mut **transaction)
+    .await
+    .map_err(|e| {
+        tracing::error!("error adding gifts of the orders {e}");
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+    Ok(())
+}
+
 #[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize, sqlx::FromRow)]
 pub struct Order {
     pub id: i32,
@@ -80,42 +429,266 @@ pub struct Order {
     pub quantity: i32,
 }
 
+/// Longest gift name the `VARCHAR(50)` column takes, in characters.
+const MAX_GIFT_NAME_LEN: usize = 50;
+const DEFAULT_PAGE_SIZE: i64 = 50;
+const MAX_PAGE_SIZE: i64 = 500;
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct FieldError {
+    /// Position of the offending order in a batch.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub index: Option<usize>,
+    pub field: &'static str,
+    pub message: String,
+}
+
+impl FieldError {
+    pub fn new(field: &'static str, message: impl Into<String>) -> Self {
+        Self {
+            index: None,
+            field,
+            message: message.into(),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum OrdersRejection {
+    /// Input that would break a column constraint, answered with a 422
+    /// listing every bad field.
+    Invalid(Vec<FieldError>),
+    Status(StatusCode),
+}
+
+impl From<StatusCode> for OrdersRejection {
+    fn from(status: StatusCode) -> Self {
+        Self::Status(status)
+    }
+}
+
+impl IntoResponse for OrdersRejection {
+    fn into_response(self) -> Response {
+        match self {
+            OrdersRejection::Invalid(errors) => (
+                StatusCode::UNPROCESSABLE_ENTITY,
+                Json(json!({ "errors": errors })),
+            )
+                .into_response(),
+            OrdersRejection::Status(status) => status.into_response(),
+        }
+    }
+}
+
+fn check_gift_name(gift_name: &str, errors: &mut Vec<FieldError>) {
+    if gift_name.chars().count() > MAX_GIFT_NAME_LEN {
+        errors.push(FieldError::new(
+            "gift_name",
+            format!("must be at most {MAX_GIFT_NAME_LEN} characters"),
+        ));
+    }
+}
+
+fn check_quantity(quantity: i32, errors: &mut Vec<FieldError>) {
+    if quantity < 0 {
+        errors.push(FieldError::new("quantity", "must not be negative"));
+    }
+}
+
+fn reject_invalid(errors: Vec<FieldError>) -> Result<(), OrdersRejection> {
+    if errors.is_empty() {
+        Ok(())
+    } else {
+        Err(OrdersRejection::Invalid(errors))
+    }
+}
+
+/// Checks a whole batch up front so nothing reaches the database when any
+/// order in it is bad.
+fn validate_orders(orders: &[Order]) -> Result<(), OrdersRejection> {
+    let mut errors = vec![];
+    for (index, order) in orders.iter().enumerate() {
+        let mut order_errors = vec![];
+        check_gift_name(&order.gift_name, &mut order_errors);
+        check_quantity(order.quantity, &mut order_errors);
+        errors.extend(order_errors.into_iter().map(|error| FieldError {
+            index: Some(index),
+            ..error
+        }));
+    }
+    reject_invalid(errors)
+}
+
+/// What to do with a submitted row whose id is already in the table.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum OnConflict {
+    /// Reject the whole batch with 409.
+    #[default]
+    Error,
+    /// Keep the existing row and skip the submitted one.
+    Ignore,
+    /// Overwrite the existing row with the submitted one.
+    Upsert,
+}
+
+#[derive(Debug, Default, Deserialize)]
+pub struct InsertOptions {
+    #[serde(default)]
+    pub on_conflict: OnConflict,
+}
+
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct InsertSummary {
+    pub inserted: usize,
+    pub updated: usize,
+    pub skipped: usize,
+}
+
+impl InsertSummary {
+    /// Counts a batch of `submitted` rows from the `(xmax = 0)` flags the
+    /// insert returned, one per row it actually wrote.
+    pub fn from_returned(submitted: usize, returned: &[bool]) -> Self {
+        let inserted = returned.iter().filter(|inserted| **inserted).count();
+        Self {
+            inserted,
+            updated: returned.len() - inserted,
+            skipped: submitted - returned.len(),
+        }
+    }
+}
+
+/// Keeps only the last row for every key. An upsert can't touch the same
+/// row twice in one statement, so earlier duplicates are dropped and end up
+/// counted as skipped.
+pub fn dedup_last<T, K: Eq + Hash>(rows: Vec<T>, key: impl Fn(&T) -> K) -> Vec<T> {
+    let mut seen = HashSet::new();
+    let mut rows: Vec<T> = rows
+        .into_iter()
+        .rev()
+        .filter(|row| seen.insert(key(row)))
+        .collect();
+    rows.reverse();
+    rows
+}
+
+/// Maps a failed batch insert to a status, duplicate ids being the
+/// caller's fault rather than ours.
+pub fn insert_error(e: sqlx::Error) -> StatusCode {
+    match e.as_database_error() {
+        Some(db) if db.is_unique_violation() => StatusCode::CONFLICT,
+        _ => StatusCode::INTERNAL_SERVER_ERROR,
+    }
+}
+
 pub async fn create_orders(
     State(state): State<SqlState>,
+    namespace: Namespace,
+    Query(options): Query<InsertOptions>,
     Json(orders): Json<Vec<Order>>,
-) -> Result<(), StatusCode> {
+) -> Result<Json<InsertSummary>, OrdersRejection> {
     tracing::info!("create orders called {orders:?}");
-    // create order table
-    let transaction = state.pool.begin().await.map_err(|e| {
+    validate_orders(&orders)?;
+    let submitted = orders.len();
+    let orders = match options.on_conflict {
+        OnConflict::Upsert => dedup_last(orders, |order| order.id),
+        _ => orders,
+    };
+    let ids: Vec<i32> = orders.iter().map(|order| order.id).collect();
+    let region_ids: Vec<i32> = orders.iter().map(|order| order.region_id).collect();
+    let gift_names: Vec<String> = orders.iter().map(|order| order.gift_name.clone()).collect();
+    let quantities: Vec<i32> = orders.iter().map(|order| order.quantity).collect();
+
+    let mut db = namespace.connect(&state.pool).await?;
+    let mut transaction = db.begin().await.map_err(|e| {
         tracing::error!("error starting transaction {e}");
         StatusCode::INTERNAL_SERVER_ERROR
     })?;
-    for order in orders {
-        sqlx::query!(
-            "insert into orders(id, region_id, gift_name, quantity) values($1, $2, $3, $4)",
-            order.id,
-            order.region_id,
-            order.gift_name,
-            order.quantity,
-        )
-        .execute(&state.pool)
-        .await
-        .map_err(|e| {
-            tracing::error!("error inserting to the database {e}");
-            StatusCode::INTERNAL_SERVER_ERROR
-        })?;
+    ensure_order_refs(&mut transaction, &region_ids, &gift_names).await?;
+    let returned = match options.on_conflict {
+        OnConflict::Error => {
+            sqlx::query_scalar!(
+                r#"
+                insert into orders(id, region_id, gift_id, quantity)
+                select o.id, o.region_id, g.id, o.quantity
+                from unnest($1::int[], $2::int[], $3::text[], $4::int[])
+                    with ordinality as o(id, region_id, gift_name, quantity, position)
+                join gifts g on g.name = o.gift_name
+                order by o.position
+                returning (xmax = 0) as "inserted!"
+                "#,
+                &ids,
+                &region_ids,
+                &gift_names,
+                &quantities,
+            )
+            .fetch_all(&mut *transaction)
+            .await
+        }
+        OnConflict::Ignore => {
+            sqlx::query_scalar!(
+                r#"
+                insert into orders(id, region_id, gift_id, quantity)
+                select o.id, o.region_id, g.id, o.quantity
+                from unnest($1::int[], $2::int[], $3::text[], $4::int[])
+                    with ordinality as o(id, region_id, gift_name, quantity, position)
+                join gifts g on g.name = o.gift_name
+                order by o.position
+                on conflict (id) do nothing
+                returning (xmax = 0) as "inserted!"
+                "#,
+                &ids,
+                &region_ids,
+                &gift_names,
+                &quantities,
+            )
+            .fetch_all(&mut *transaction)
+            .await
+        }
+        OnConflict::Upsert => {
+            sqlx::query_scalar!(
+                r#"
+                insert into orders(id, region_id, gift_id, quantity)
+                select o.id, o.region_id, g.id, o.quantity
+                from unnest($1::int[], $2::int[], $3::text[], $4::int[])
+                    with ordinality as o(id, region_id, gift_name, quantity, position)
+                join gifts g on g.name = o.gift_name
+                order by o.position
+                on conflict (id) do update set
+                    region_id = excluded.region_id,
+                    gift_id = excluded.gift_id,
+                    quantity = excluded.quantity,
+                    updated_at = now()
+                returning (xmax = 0) as "inserted!"
+                "#,
+                &ids,
+                &region_ids,
+                &gift_names,
+                &quantities,
+            )
+            .fetch_all(&mut *transaction)
+            .await
+        }
     }
+    .map_err(|e| {
+        tracing::error!("error inserting to the database {e}");
+        insert_error(e)
+    })?;
     transaction.commit().await.map_err(|e| {
         tracing::error!("error commiting the transaction {e}");
         StatusCode::INTERNAL_SERVER_ERROR
     })?;
-    Ok(())
+    Ok(Json(InsertSummary::from_returned(submitted, &returned)))
 }
 
-async fn total_orders(State(state): State<SqlState>) -> Result<Json<Value>, StatusCode> {
+async fn total_orders(
+    State(state): State<SqlState>,
+    namespace: Namespace,
+) -> Result<Json<Value>, StatusCode> {
     tracing::info!("total orders called");
+    let mut db = namespace.connect(&state.pool).await?;
     let total = sqlx::query!("select sum(quantity) from orders")
-        .fetch_one(&state.pool)
+        .fetch_one(&mut *db)
         .await
         .map_err(|e| {
             tracing::error!("error creating orders table {e}");
@@ -126,21 +699,575 @@ async fn total_orders(State(state): State<SqlState>) -> Result<Json<Value>, Stat
     Ok(Json(json!({ "total": total})))
 }
 
-async fn popular(State(state): State<SqlState>) -> Result<Json<Value>, StatusCode> {
-    tracing::info!("popular called");
-    let popular =
-        sqlx::query!("select sum(quantity) as quantity, gift_name from orders group by gift_name")
-            .fetch_all(&state.pool)
+#[derive(Debug, Default, Deserialize)]
+pub struct PopularQuery {
+    /// How many ranks to return, gifts tied on the last one included.
+    pub top: Option<i64>,
+    /// Only count the orders of this region id.
+    pub region: Option<i32>,
+    /// Only count orders created at or after this time.
+    pub since: Option<DateTime<Utc>>,
+}
+
+/// Gifts sharing a rank because they were ordered in the same quantity.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PopularRank {
+    pub rank: i64,
+    pub quantity: i64,
+    pub gifts: Vec<String>,
+}
+
+async fn popular(
+    State(state): State<SqlState>,
+    namespace: Namespace,
+    Query(query): Query<PopularQuery>,
+) -> Result<Json<Value>, OrdersRejection> {
+    tracing::info!("popular called {query:?}");
+    let legacy = query.top.is_none() && query.region.is_none() && query.since.is_none();
+    let top = query.top.unwrap_or(1);
+    if !(1..=MAX_PAGE_SIZE).contains(&top) {
+        return Err(OrdersRejection::Invalid(vec![FieldError::new(
+            "top",
+            format!("must be between 1 and {MAX_PAGE_SIZE}"),
+        )]));
+    }
+
+    let mut db = namespace.connect(&state.pool).await?;
+    let ranked = sqlx::query!(
+        r#"
+        select gift_name as "gift_name!", quantity as "quantity!", rank as "rank!"
+        from (
+            select
+                g.name as gift_name,
+                sum(o.quantity)::BIGINT as quantity,
+                rank() over (order by sum(o.quantity) desc) as rank
+            from orders o
+            inner join gifts g on g.id = o.gift_id
+            where ($1::int is null or o.region_id = $1)
+                and ($2::timestamptz is null or o.created_at >= $2)
+            group by g.name
+        ) ranked
+        where rank <= $3
+        order by rank, gift_name
+        "#,
+        query.region,
+        query.since,
+        top,
+    )
+    .fetch_all(&mut *db)
+    .await
+    .map_err(|e| {
+        tracing::error!("error ranking popular gifts {e}");
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+
+    if legacy {
+        // the first of the most popular gifts, or null without any order
+        let popular = ranked.into_iter().next().map(|row| row.gift_name);
+        return Ok(Json(json!({ "popular": popular })));
+    }
+    let mut ranking: Vec<PopularRank> = vec![];
+    for row in ranked {
+        match ranking.last_mut() {
+            Some(last) if last.rank == row.rank => last.gifts.push(row.gift_name),
+            _ => ranking.push(PopularRank {
+                rank: row.rank,
+                quantity: row.quantity,
+                gifts: vec![row.gift_name],
+            }),
+        }
+    }
+    Ok(Json(json!({ "ranking": ranking })))
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum OrderSort {
+    #[default]
+    Id,
+    RegionId,
+    GiftName,
+    Quantity,
+}
+
+impl OrderSort {
+    fn column(self) -> &'static str {
+        match self {
+            OrderSort::Id => "id",
+            OrderSort::RegionId => "region_id",
+            OrderSort::GiftName => "gift_name",
+            OrderSort::Quantity => "quantity",
+        }
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SortOrder {
+    #[default]
+    Asc,
+    Desc,
+}
+
+#[derive(Debug, Default, Deserialize)]
+pub struct ListOrdersQuery {
+    pub region_id: Option<i32>,
+    /// Case sensitive prefix of the gift name.
+    pub gift_prefix: Option<String>,
+    /// `ILIKE` pattern the gift name has to match, e.g. `%tree%`.
+    pub gift_name: Option<String>,
+    pub min_quantity: Option<i32>,
+    pub max_quantity: Option<i32>,
+    #[serde(default)]
+    pub sort: OrderSort,
+    #[serde(default)]
+    pub order: SortOrder,
+    pub limit: Option<i64>,
+    #[serde(default)]
+    pub offset: i64,
+}
+
+async fn list_orders(
+    State(state): State<SqlState>,
+    namespace: Namespace,
+    Query(query): Query<ListOrdersQuery>,
+) -> Result<Json<Vec<Order>>, OrdersRejection> {
+    tracing::info!("list orders called {query:?}");
+    let limit = query.limit.unwrap_or(DEFAULT_PAGE_SIZE);
+    let mut errors = vec![];
+    if !(1..=MAX_PAGE_SIZE).contains(&limit) {
+        errors.push(FieldError::new(
+            "limit",
+            format!("must be between 1 and {MAX_PAGE_SIZE}"),
+        ));
+    }
+    if query.offset < 0 {
+        errors.push(FieldError::new("offset", "must not be negative"));
+    }
+    if let (Some(min), Some(max)) = (query.min_quantity, query.max_quantity) {
+        if min > max {
+            errors.push(FieldError::new(
+                "min_quantity",
+                "must not be greater than max_quantity",
+            ));
+        }
+    }
+    reject_invalid(errors)?;
+
+    let mut db = namespace.connect(&state.pool).await?;
+    let orders = sqlx::query_as!(
+        Order,
+        r#"
+        select o.id, o.region_id, g.name as gift_name, o.quantity
+        from orders o
+        inner join gifts g on g.id = o.gift_id
+        where ($1::int is null or o.region_id = $1)
+            and ($2::text is null or starts_with(g.name, $2))
+            and ($3::text is null or g.name ilike $3)
+            and ($4::int is null or o.quantity >= $4)
+            and ($5::int is null or o.quantity <= $5)
+        order by
+            case when $6::text = 'region_id' and not $7::bool then o.region_id end,
+            case when $6::text = 'region_id' and $7::bool then o.region_id end desc,
+            case when $6::text = 'gift_name' and not $7::bool then g.name end,
+            case when $6::text = 'gift_name' and $7::bool then g.name end desc,
+            case when $6::text = 'quantity' and not $7::bool then o.quantity end,
+            case when $6::text = 'quantity' and $7::bool then o.quantity end desc,
+            case when $6::text = 'id' and $7::bool then o.id end desc,
+            o.id
+        limit $8 offset $9
+        "#,
+        query.region_id,
+        query.gift_prefix,
+        query.gift_name,
+        query.min_quantity,
+        query.max_quantity,
+        query.sort.column(),
+        query.order == SortOrder::Desc,
+        limit,
+        query.offset,
+    )
+    .fetch_all(&mut *db)
+    .await
+    .map_err(|e| {
+        tracing::error!("error listing orders {e}");
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+    Ok(Json(orders))
+}
+
+async fn get_order(
+    State(state): State<SqlState>,
+    namespace: Namespace,
+    Path(id): Path<i32>,
+) -> Result<Json<Order>, StatusCode> {
+    tracing::info!("get order called {id}");
+    let mut db = namespace.connect(&state.pool).await?;
+    sqlx::query_as!(
+        Order,
+        r#"
+        select o.id, o.region_id, g.name as gift_name, o.quantity
+        from orders o
+        inner join gifts g on g.id = o.gift_id
+        where o.id = $1
+        "#,
+        id
+    )
+    .fetch_optional(&mut *db)
+    .await
+    .map_err(|e| {
+        tracing::error!("error fetching order {id} {e}");
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?
+    .map(Json)
+    .ok_or(StatusCode::NOT_FOUND)
+}
+
+/// Everything about an order but its id, which comes from the path.
+#[derive(Debug, Clone, Deserialize)]
+pub struct OrderFields {
+    pub region_id: i32,
+    pub gift_name: String,
+    pub quantity: i32,
+}
+
+async fn replace_order(
+    State(state): State<SqlState>,
+    namespace: Namespace,
+    Path(id): Path<i32>,
+    Json(fields): Json<OrderFields>,
+) -> Result<Json<Order>, OrdersRejection> {
+    tracing::info!("replace order called {id} {fields:?}");
+    let mut errors = vec![];
+    check_gift_name(&fields.gift_name, &mut errors);
+    check_quantity(fields.quantity, &mut errors);
+    reject_invalid(errors)?;
+
+    let mut db = namespace.connect(&state.pool).await?;
+    let mut transaction = db.begin().await.map_err(|e| {
+        tracing::error!("error starting transaction {e}");
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+    ensure_order_refs(
+        &mut transaction,
+        &[fields.region_id],
+        std::slice::from_ref(&fields.gift_name),
+    )
+    .await?;
+    let order = sqlx::query_as!(
+        Order,
+        r#"
+        with updated as (
+            update orders
+            set
+                region_id = $2,
+                gift_id = (select id from gifts where name = $3),
+                quantity = $4,
+                updated_at = now()
+            where id = $1
+            returning id, region_id, gift_id, quantity
+        )
+        select u.id, u.region_id, g.name as gift_name, u.quantity
+        from updated u
+        inner join gifts g on g.id = u.gift_id
+        "#,
+        id,
+        fields.region_id,
+        fields.gift_name,
+        fields.quantity,
+    )
+    .fetch_optional(&mut *transaction)
+    .await
+    .map_err(|e| {
+        tracing::error!("error replacing order {id} {e}");
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?
+    .ok_or(StatusCode::NOT_FOUND)?;
+    transaction.commit().await.map_err(|e| {
+        tracing::error!("error commiting the transaction {e}");
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+    Ok(Json(order))
+}
+
+/// The fields of an order to change, the rest are kept as they are.
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct OrderPatch {
+    pub region_id: Option<i32>,
+    pub gift_name: Option<String>,
+    pub quantity: Option<i32>,
+}
+
+async fn update_order(
+    State(state): State<SqlState>,
+    namespace: Namespace,
+    Path(id): Path<i32>,
+    Json(patch): Json<OrderPatch>,
+) -> Result<Json<Order>, OrdersRejection> {
+    tracing::info!("update order called {id} {patch:?}");
+    let mut errors = vec![];
+    if let Some(gift_name) = &patch.gift_name {
+        check_gift_name(gift_name, &mut errors);
+    }
+    if let Some(quantity) = patch.quantity {
+        check_quantity(quantity, &mut errors);
+    }
+    reject_invalid(errors)?;
+
+    let mut db = namespace.connect(&state.pool).await?;
+    let mut transaction = db.begin().await.map_err(|e| {
+        tracing::error!("error starting transaction {e}");
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+    let region_ids: Vec<i32> = patch.region_id.into_iter().collect();
+    let gift_names: Vec<String> = patch.gift_name.iter().cloned().collect();
+    ensure_order_refs(&mut transaction, &region_ids, &gift_names).await?;
+    let order = sqlx::query_as!(
+        Order,
+        r#"
+        with updated as (
+            update orders
+            set
+                region_id = coalesce($2, region_id),
+                gift_id = coalesce((select id from gifts where name = $3), gift_id),
+                quantity = coalesce($4, quantity),
+                updated_at = now()
+            where id = $1
+            returning id, region_id, gift_id, quantity
+        )
+        select u.id, u.region_id, g.name as gift_name, u.quantity
+        from updated u
+        inner join gifts g on g.id = u.gift_id
+        "#,
+        id,
+        patch.region_id,
+        patch.gift_name,
+        patch.quantity,
+    )
+    .fetch_optional(&mut *transaction)
+    .await
+    .map_err(|e| {
+        tracing::error!("error updating order {id} {e}");
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?
+    .ok_or(StatusCode::NOT_FOUND)?;
+    transaction.commit().await.map_err(|e| {
+        tracing::error!("error commiting the transaction {e}");
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+    Ok(Json(order))
+}
+
+async fn delete_order(
+    State(state): State<SqlState>,
+    namespace: Namespace,
+    Path(id): Path<i32>,
+) -> Result<StatusCode, StatusCode> {
+    tracing::info!("delete order called {id}");
+    let mut db = namespace.connect(&state.pool).await?;
+    let deleted = sqlx::query!("delete from orders where id = $1", id)
+        .execute(&mut *db)
+        .await
+        .map_err(|e| {
+            tracing::error!("error deleting order {id} {e}");
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?
+        .rows_affected();
+    if deleted == 0 {
+        Err(StatusCode::NOT_FOUND)
+    } else {
+        Ok(StatusCode::NO_CONTENT)
+    }
+}
+
+impl ImportRow for Order {
+    fn problems(&self) -> Vec<FieldError> {
+        let mut errors = vec![];
+        check_gift_name(&self.gift_name, &mut errors);
+        check_quantity(self.quantity, &mut errors);
+        errors
+    }
+
+    fn record(&self) -> Vec<String> {
+        vec![
+            self.id.to_string(),
+            self.region_id.to_string(),
+            self.gift_name.clone(),
+            self.quantity.to_string(),
+        ]
+    }
+}
+
+// The import statements below work on a staging table that only lives in
+// the importing transaction, so they can't be checked at build time.
+
+const MERGE_IMPORTED_ORDERS: &str = r#"
+    insert into orders(id, region_id, gift_id, quantity)
+    select i.id, i.region_id, g.id, i.quantity
+    from orders_import i
+    inner join gifts g on g.name = i.gift_name
+    order by i.line
+    returning (xmax = 0) as inserted
+"#;
+
+const MERGE_IMPORTED_ORDERS_IGNORE: &str = r#"
+    insert into orders(id, region_id, gift_id, quantity)
+    select distinct on (i.id) i.id, i.region_id, g.id, i.quantity
+    from orders_import i
+    inner join gifts g on g.name = i.gift_name
+    order by i.id, i.line
+    on conflict (id) do nothing
+    returning (xmax = 0) as inserted
+"#;
+
+const MERGE_IMPORTED_ORDERS_UPSERT: &str = r#"
+    insert into orders(id, region_id, gift_id, quantity)
+    select distinct on (i.id) i.id, i.region_id, g.id, i.quantity
+    from orders_import i
+    inner join gifts g on g.name = i.gift_name
+    order by i.id, i.line desc
+    on conflict (id) do update set
+        region_id = excluded.region_id,
+        gift_id = excluded.gift_id,
+        quantity = excluded.quantity,
+        updated_at = now()
+    returning (xmax = 0) as inserted
+"#;
+
+async fn import_orders(
+    State(state): State<SqlState>,
+    namespace: Namespace,
+    Query(options): Query<ImportQuery>,
+    headers: HeaderMap,
+    body: Body,
+) -> Result<Response, OrdersRejection> {
+    tracing::info!("import orders called {options:?}");
+    let format = options.format(&headers)?;
+    if options.dry_run {
+        let report =
+            bulk::import::<Order, &mut PgConnection>(body, format, None::<PgCopyIn<_>>).await?;
+        return Ok(Json(report).into_response());
+    }
+
+    let mut db = namespace.connect(&state.pool).await?;
+    let mut transaction = db.begin().await.map_err(|e| {
+        tracing::error!("error starting transaction {e}");
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+    sqlx::query(
+        r#"
+        create temp table orders_import (
+            line BIGINT,
+            id INT,
+            region_id INT,
+            gift_name TEXT,
+            quantity INT
+        ) on commit drop
+        "#,
+    )
+    .execute(&mut *transaction)
+    .await
+    .map_err(|e| {
+        tracing::error!("error creating the orders staging table {e}");
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+    let copy = transaction
+        .copy_in_raw("copy orders_import from stdin with (format csv, force_not_null (gift_name))")
+        .await
+        .map_err(|e| {
+            tracing::error!("error starting the orders copy {e}");
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?;
+    let report = bulk::import::<Order, _>(body, format, Some(copy)).await?;
+    if report.invalid > 0 {
+        return Ok(report.into_response());
+    }
+
+    for statement in [
+        "insert into regions(id) select distinct region_id from orders_import on conflict (id) do nothing",
+        "insert into gifts(name) select distinct gift_name from orders_import on conflict (name) do nothing",
+    ] {
+        sqlx::query(statement)
+            .execute(&mut *transaction)
             .await
             .map_err(|e| {
-                tracing::error!("error creating orders table {e}");
+                tracing::error!("error adding the regions and gifts of the import {e}");
                 StatusCode::INTERNAL_SERVER_ERROR
-            })?
-            .into_iter()
-            .max_by_key(|p| p.quantity);
-    if let Some(p) = popular {
-        Ok(Json(json!({ "popular": p.gift_name})))
-    } else {
-        Ok(Json(json!({ "popular": Value::Null})))
+            })?;
+    }
+    let merge = match options.on_conflict {
+        OnConflict::Error => MERGE_IMPORTED_ORDERS,
+        OnConflict::Ignore => MERGE_IMPORTED_ORDERS_IGNORE,
+        OnConflict::Upsert => MERGE_IMPORTED_ORDERS_UPSERT,
+    };
+    let returned: Vec<bool> = sqlx::query_scalar(merge)
+        .fetch_all(&mut *transaction)
+        .await
+        .map_err(|e| {
+            tracing::error!("error merging the imported orders {e}");
+            insert_error(e)
+        })?;
+    transaction.commit().await.map_err(|e| {
+        tracing::error!("error commiting the transaction {e}");
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+    Ok(Json(InsertSummary::from_returned(
+        report.valid as usize,
+        &returned,
+    ))
+    .into_response())
+}
+
+const ORDERS_EXPORT: &str = r#"
+    select o.id, o.region_id, g.name as gift_name, o.quantity
+    from orders o
+    inner join gifts g on g.id = o.gift_id
+    order by o.id
+"#;
+
+const ORDERS_PARQUET_SCHEMA: &str = r#"
+    message order {
+        REQUIRED INT32 id;
+        REQUIRED INT32 region_id;
+        REQUIRED BYTE_ARRAY gift_name (UTF8);
+        REQUIRED INT32 quantity;
     }
+"#;
+
+async fn export_orders(
+    State(state): State<SqlState>,
+    namespace: Namespace,
+    Query(query): Query<ExportTableQuery>,
+) -> Result<Response, StatusCode> {
+    tracing::info!("export orders called {query:?}");
+    let mut db = namespace.connect(&state.pool).await?;
+    if query.format != BulkFormat::Parquet {
+        return bulk::copy_out(db, "orders", query.format, ORDERS_EXPORT).await;
+    }
+
+    let orders = sqlx::query_as!(
+        Order,
+        r#"
+        select o.id, o.region_id, g.name as gift_name, o.quantity
+        from orders o
+        inner join gifts g on g.id = o.gift_id
+        order by o.id
+        "#
+    )
+    .fetch_all(&mut *db)
+    .await
+    .map_err(|e| {
+        tracing::error!("error fetching orders to export {e}");
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+    bulk::parquet_response(
+        "orders",
+        ORDERS_PARQUET_SCHEMA,
+        vec![
+            ParquetColumn::Int32(orders.iter().map(|order| order.id).collect()),
+            ParquetColumn::Int32(orders.iter().map(|order| order.region_id).collect()),
+            ParquetColumn::Utf8(orders.iter().map(|order| order.gift_name.clone()).collect()),
+            ParquetColumn::Int32(orders.iter().map(|order| order.quantity).collect()),
+        ],
+    )
 }
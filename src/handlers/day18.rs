@@ -1,13 +1,23 @@
+use std::collections::{HashMap, HashSet};
+
 use axum::{
-    extract::{Path, State},
-    http::StatusCode,
+    body::Body,
+    extract::{Path, Query, State},
+    http::{header, HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
     routing::{get, post},
     Json, Router,
 };
 use serde::{Deserialize, Serialize};
-use sqlx::{Pool, Postgres};
+use sqlx::{postgres::PgCopyIn, Connection, PgConnection, Pool, Postgres, Transaction};
+
+use super::bulk::{self, BulkFormat, ExportTableQuery, ImportQuery, ImportRow, ParquetColumn};
+use super::tenants::Namespace;
 
-use super::day13::{create_orders, SqlState};
+use super::day13::{
+    create_orders, dedup_last, insert_error, reset_schema, FieldError, InsertOptions,
+    InsertSummary, OnConflict, OrdersRejection, ResetScope, SqlState,
+};
 
 pub fn router(pool: Pool<Postgres>) -> Router {
     Router::new()
@@ -15,111 +25,212 @@ pub fn router(pool: Pool<Postgres>) -> Router {
         .route("/18/reset", post(reset))
         .route("/18/orders", post(create_orders))
         .route("/18/regions", post(create_regions))
+        .route("/18/regions/import", post(import_regions))
+        .route("/18/regions/export", get(export_regions))
         .route("/18/regions/total", get(total_per_region))
         .route("/18/regions/top_list/:q", get(top_list))
+        .route("/18/regions/tree", get(region_tree))
+        .route("/18/analytics/share", get(region_share))
+        .route("/18/analytics/pivot", get(gift_region_pivot))
+        .route("/18/analytics/empty_regions", get(empty_regions))
+        .route("/18/analytics/running_totals", get(running_totals))
+        .route("/18/analytics/percentiles", get(order_size_percentiles))
         .with_state(SqlState { pool })
 }
 
-async fn reset(State(state): State<SqlState>) -> Result<(), StatusCode> {
-    // drop orders table if exists
+async fn reset(State(state): State<SqlState>, namespace: Namespace) -> Result<(), StatusCode> {
     tracing::info!("reset orders called");
+    let mut db = namespace.connect(&state.pool).await?;
+    reset_schema(&mut db, ResetScope::All).await
+}
+
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize, sqlx::FromRow)]
+pub struct Region {
+    pub id: i32,
+    pub name: String,
+    /// The region this one is part of, e.g. the state of a city.
+    #[serde(default)]
+    pub parent_id: Option<i32>,
+}
+
+pub async fn create_regions(
+    State(state): State<SqlState>,
+    namespace: Namespace,
+    Query(options): Query<InsertOptions>,
+    Json(regions): Json<Vec<Region>>,
+) -> Result<Json<InsertSummary>, OrdersRejection> {
+    tracing::info!("create regions called {regions:?}");
+    let submitted = regions.len();
+    // one statement can't name the same region twice, so duplicates in the
+    // batch are settled here the way a conflict with the table would be
+    let regions = match options.on_conflict {
+        OnConflict::Error => {
+            let mut seen = HashSet::new();
+            if !regions.iter().all(|region| seen.insert(region.id)) {
+                tracing::error!("duplicate region ids in the batch");
+                return Err(StatusCode::CONFLICT.into());
+            }
+            regions
+        }
+        OnConflict::Ignore => {
+            let mut seen = HashSet::new();
+            regions
+                .into_iter()
+                .filter(|region| seen.insert(region.id))
+                .collect()
+        }
+        OnConflict::Upsert => dedup_last(regions, |region| region.id),
+    };
+    let ids: Vec<i32> = regions.iter().map(|region| region.id).collect();
+    let names: Vec<String> = regions.iter().map(|region| region.name.clone()).collect();
+    let parent_ids: Vec<Option<i32>> = regions.iter().map(|region| region.parent_id).collect();
+    if regions
+        .iter()
+        .any(|region| region.parent_id == Some(region.id))
+    {
+        return Err(cyclic_regions());
+    }
 
-    let transaction = state.pool.begin().await.map_err(|e| {
+    let mut db = namespace.connect(&state.pool).await?;
+    let mut transaction = db.begin().await.map_err(|e| {
         tracing::error!("error starting transaction {e}");
         StatusCode::INTERNAL_SERVER_ERROR
     })?;
-
-    // drop regions table
-    sqlx::query!("DROP TABLE IF EXISTS regions;")
-        .execute(&state.pool)
-        .await
-        .map_err(|e| {
-            tracing::error!("error droping regions table {e}");
-            StatusCode::INTERNAL_SERVER_ERROR
-        })?;
-
-    // drop orders table
-    sqlx::query!("DROP TABLE IF EXISTS orders;")
-        .execute(&state.pool)
-        .await
-        .map_err(|e| {
-            tracing::error!("error droping orders table {e}");
-            StatusCode::INTERNAL_SERVER_ERROR
-        })?;
-
-    // create regions table
+    // parents outside the batch may not be posted yet, they are kept
+    // unnamed like the regions only known from their orders
+    let missing_parents: Vec<i32> = parent_ids
+        .iter()
+        .flatten()
+        .copied()
+        .filter(|parent_id| !ids.contains(parent_id))
+        .collect();
     sqlx::query!(
         r#"
-        CREATE TABLE regions (
-            id INT PRIMARY KEY,
-            name VARCHAR(50)
-        )"#
+        insert into regions(id)
+        select distinct parent_id from unnest($1::int[]) as p(parent_id)
+        on conflict (id) do nothing
+        "#,
+        &missing_parents
     )
-    .execute(&state.pool)
+    .execute(&mut *transaction)
     .await
     .map_err(|e| {
-        tracing::error!("error creating regions table {e}");
+        tracing::error!("error adding parent regions {e}");
         StatusCode::INTERNAL_SERVER_ERROR
     })?;
-
-    // create orders table
-    sqlx::query!(
-        r#"
-        CREATE TABLE orders (
-            id INT PRIMARY KEY,
-            region_id INT,
-            gift_name VARCHAR(50),
-            quantity INT
-        );
-    "#,
+    // regions only known from their orders yet, naming them counts as an insert
+    let unnamed: HashSet<i32> = sqlx::query_scalar!(
+        "select id from regions where id = any($1) and name is null",
+        &ids
     )
-    .execute(&state.pool)
+    .fetch_all(&mut *transaction)
     .await
     .map_err(|e| {
-        tracing::error!("error creating orders table {e}");
+        tracing::error!("error fetching unnamed regions {e}");
         StatusCode::INTERNAL_SERVER_ERROR
+    })?
+    .into_iter()
+    .collect();
+    let returned = match options.on_conflict {
+        OnConflict::Error | OnConflict::Ignore => sqlx::query!(
+            r#"
+                insert into regions(id, name, parent_id)
+                select * from unnest($1::int[], $2::text[], $3::int[])
+                on conflict (id) do update set
+                    name = excluded.name,
+                    parent_id = excluded.parent_id,
+                    updated_at = now()
+                    where regions.name is null
+                returning id, (xmax = 0) as "inserted!"
+                "#,
+            &ids,
+            &names,
+            &parent_ids as &[Option<i32>],
+        )
+        .fetch_all(&mut *transaction)
+        .await
+        .map(|rows| {
+            rows.into_iter()
+                .map(|row| row.inserted || unnamed.contains(&row.id))
+                .collect::<Vec<_>>()
+        }),
+        OnConflict::Upsert => sqlx::query!(
+            r#"
+                insert into regions(id, name, parent_id)
+                select * from unnest($1::int[], $2::text[], $3::int[])
+                on conflict (id) do update set
+                    name = excluded.name,
+                    parent_id = excluded.parent_id,
+                    updated_at = now()
+                returning id, (xmax = 0) as "inserted!"
+                "#,
+            &ids,
+            &names,
+            &parent_ids as &[Option<i32>],
+        )
+        .fetch_all(&mut *transaction)
+        .await
+        .map(|rows| {
+            rows.into_iter()
+                .map(|row| row.inserted || unnamed.contains(&row.id))
+                .collect::<Vec<_>>()
+        }),
+    }
+    .map_err(|e| {
+        tracing::error!("error inserting regions to the database {e}");
+        insert_error(e)
     })?;
-
+    if options.on_conflict == OnConflict::Error && returned.len() < regions.len() {
+        tracing::error!("regions already exist");
+        return Err(StatusCode::CONFLICT.into());
+    }
+    check_hierarchy(&mut transaction, &ids).await?;
     transaction.commit().await.map_err(|e| {
         tracing::error!("error commiting the transaction {e}");
         StatusCode::INTERNAL_SERVER_ERROR
     })?;
-
-    Ok(())
+    Ok(Json(InsertSummary::from_returned(submitted, &returned)))
 }
 
-#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize, sqlx::FromRow)]
-pub struct Region {
-    pub id: i32,
-    pub name: String,
+fn cyclic_regions() -> OrdersRejection {
+    OrdersRejection::Invalid(vec![FieldError::new(
+        "parent_id",
+        "a region can't be part of itself",
+    )])
 }
 
-pub async fn create_regions(
-    State(state): State<SqlState>,
-    Json(regions): Json<Vec<Region>>,
-) -> Result<(), StatusCode> {
-    tracing::info!("create regions called {regions:?}");
-    let transaction = state.pool.begin().await.map_err(|e| {
-        tracing::error!("error starting transaction {e}");
-        StatusCode::INTERNAL_SERVER_ERROR
-    })?;
-    for region in regions {
-        sqlx::query!(
-            "insert into regions(id, name) values($1, $2)",
-            region.id,
-            region.name
+/// Rejects the write when it made any of the `ids` regions part of itself.
+async fn check_hierarchy(
+    transaction: &mut Transaction<'_, Postgres>,
+    ids: &[i32],
+) -> Result<(), OrdersRejection> {
+    // walk up from every written region, a cycle brings a walk back to a
+    // region it already went through
+    let cyclic = sqlx::query_scalar!(
+        r#"
+        with recursive walk(id, parent_id, path, cyclic) as (
+            select id, parent_id, array[id], false
+            from regions
+            where id = any($1)
+            union all
+            select r.id, r.parent_id, w.path || r.id, r.id = any(w.path)
+            from walk w
+            inner join regions r on r.id = w.parent_id
+            where not w.cyclic
         )
-        .execute(&state.pool)
-        .await
-        .map_err(|e| {
-            tracing::error!("error inserting regions to the database {e}");
-            StatusCode::INTERNAL_SERVER_ERROR
-        })?;
-    }
-    transaction.commit().await.map_err(|e| {
-        tracing::error!("error commiting the transaction {e}");
+        select exists (select 1 from walk where cyclic) as "cyclic!"
+        "#,
+        ids
+    )
+    .fetch_one(&mut **transaction)
+    .await
+    .map_err(|e| {
+        tracing::error!("error checking the region hierarchy {e}");
         StatusCode::INTERNAL_SERVER_ERROR
     })?;
+    if cyclic {
+        return Err(cyclic_regions());
+    }
     Ok(())
 }
 
@@ -128,35 +239,193 @@ pub struct TotalPerRegion {
     pub region: String,
     pub total: i64,
 }
+#[derive(Debug, Default, Deserialize)]
+pub struct TotalQuery {
+    /// Depth in the hierarchy to roll the totals up to, 0 being the regions
+    /// without a parent. Without it every region only counts its own orders.
+    pub level: Option<i32>,
+}
+
 pub async fn total_per_region(
     State(state): State<SqlState>,
+    namespace: Namespace,
+    Query(query): Query<TotalQuery>,
 ) -> Result<Json<Vec<TotalPerRegion>>, StatusCode> {
-    tracing::info!("total per regions called");
+    tracing::info!("total per regions called {query:?}");
+    let mut db = namespace.connect(&state.pool).await?;
+    let Some(level) = query.level else {
+        let orders = sqlx::query_as!(
+            TotalPerRegion,
+            r#"
+            select
+                r.name as "region!",
+                sum(o.quantity)::INT as "total!"
+            from
+                orders o
+            inner join regions r on o.region_id = r.id
+            where
+                r.name is not null
+            group by
+                o.region_id, r.name
+            order by
+                r.name
+                ;
+        "#
+        )
+        .fetch_all(&mut *db)
+        .await
+        .map_err(|e| {
+            tracing::error!("error getting total per region from database {e}");
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?;
+        return Ok(Json(orders));
+    };
+
     let orders = sqlx::query_as!(
         TotalPerRegion,
         r#"
-        select 
-            name as "region!", 
-            sum(quantity)::INT as "total!" 
-        from 
-            orders o 
-        inner join regions r on o.region_id = r.id 
-        group by 
-            region_id, name
+        with recursive tree(id, depth, path) as (
+            select id, 0, array[id]
+            from regions
+            where parent_id is null
+            union all
+            select r.id, t.depth + 1, t.path || r.id
+            from tree t
+            inner join regions r on r.parent_id = t.id
+        )
+        select
+            r.name as "region!",
+            sum(o.quantity)::INT as "total!"
+        from
+            tree t
+        inner join regions r on r.id = t.id
+        inner join tree d on t.id = any(d.path)
+        inner join orders o on o.region_id = d.id
+        where
+            t.depth = $1 and r.name is not null
+        group by
+            t.id, r.name
         order by
-            name
+            r.name
             ;
-    "#
+    "#,
+        level
     )
-    .fetch_all(&state.pool)
+    .fetch_all(&mut *db)
     .await
     .map_err(|e| {
-        tracing::error!("error getting total per region from database {e}");
+        tracing::error!("error rolling up totals per region from database {e}");
         StatusCode::INTERNAL_SERVER_ERROR
     })?;
     Ok(Json(orders))
 }
 
+#[derive(Debug, Default, Deserialize)]
+pub struct TreeQuery {
+    /// Only the subtree of this region id.
+    pub root: Option<i32>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RegionNode {
+    pub id: i32,
+    /// `None` for a region only known from its orders or children.
+    pub name: Option<String>,
+    /// Quantity ordered in this region itself.
+    pub quantity: i64,
+    /// Quantity ordered in this region and everything below it.
+    pub total: i64,
+    pub children: Vec<RegionNode>,
+}
+
+struct TreeRow {
+    id: i32,
+    parent_id: Option<i32>,
+    name: Option<String>,
+    quantity: i64,
+    total: i64,
+}
+
+fn build_tree(
+    children: &mut HashMap<Option<i32>, Vec<TreeRow>>,
+    parent: Option<i32>,
+) -> Vec<RegionNode> {
+    children
+        .remove(&parent)
+        .unwrap_or_default()
+        .into_iter()
+        .map(|row| RegionNode {
+            id: row.id,
+            name: row.name,
+            quantity: row.quantity,
+            total: row.total,
+            children: build_tree(children, Some(row.id)),
+        })
+        .collect()
+}
+
+async fn region_tree(
+    State(state): State<SqlState>,
+    namespace: Namespace,
+    Query(query): Query<TreeQuery>,
+) -> Result<Json<Vec<RegionNode>>, StatusCode> {
+    tracing::info!("region tree called {query:?}");
+    let mut db = namespace.connect(&state.pool).await?;
+    let rows = sqlx::query_as!(
+        TreeRow,
+        r#"
+        with recursive tree(id, path) as (
+            select id, array[id]
+            from regions
+            where case when $1::int is null then parent_id is null else id = $1 end
+            union all
+            select r.id, t.path || r.id
+            from tree t
+            inner join regions r on r.parent_id = t.id
+        ),
+        own(id, quantity) as (
+            select t.id, coalesce(sum(o.quantity), 0)::BIGINT
+            from tree t
+            left join orders o on o.region_id = t.id
+            group by t.id
+        )
+        select
+            t.id as "id!",
+            r.parent_id,
+            r.name,
+            own.quantity as "quantity!",
+            (
+                select sum(d_own.quantity)
+                from tree d
+                inner join own d_own on d_own.id = d.id
+                where t.id = any(d.path)
+            )::BIGINT as "total!"
+        from tree t
+        inner join regions r on r.id = t.id
+        inner join own on own.id = t.id
+        order by r.name nulls last, t.id
+        "#,
+        query.root
+    )
+    .fetch_all(&mut *db)
+    .await
+    .map_err(|e| {
+        tracing::error!("error getting the region tree from database {e}");
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+
+    let mut children: HashMap<Option<i32>, Vec<TreeRow>> = HashMap::new();
+    let mut top = None;
+    for row in rows {
+        if query.root == Some(row.id) {
+            // the requested root hangs off its own parent
+            top = row.parent_id;
+        }
+        children.entry(row.parent_id).or_default().push(row);
+    }
+    Ok(Json(build_tree(&mut children, top)))
+}
+
 #[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize, sqlx::FromRow)]
 pub struct TopListResult {
     pub region: String,
@@ -164,17 +433,19 @@ pub struct TopListResult {
 }
 pub async fn top_list(
     State(state): State<SqlState>,
+    namespace: Namespace,
     Path(top): Path<i64>,
 ) -> Result<Json<Vec<TopListResult>>, StatusCode> {
     tracing::info!("top list called with {top}");
+    let mut db = namespace.connect(&state.pool).await?;
     let regions = sqlx::query_as!(TopListResult,
-        r#"select r.name as "region!", COALESCE(NULLIF(ARRAY_AGG(o.gift_name), '{NULL}'), '{}'::text[]) AS "top_gifts!" from regions r left join LATERAL  
+        r#"select r.name as "region!", COALESCE(NULLIF(ARRAY_AGG(o.gift_name), '{NULL}'), '{}'::text[]) AS "top_gifts!" from regions r left join LATERAL
         (
-            select gift_name, sum(quantity) as sum_quantity from orders where r.id = region_id group by gift_name, region_id order by sum_quantity desc limit $1
-        ) o on true group by r.name order by r.name
+            select g.name as gift_name, sum(quantity) as sum_quantity from orders inner join gifts g on g.id = orders.gift_id where r.id = region_id group by g.name, region_id order by sum_quantity desc, g.name limit $1
+        ) o on true where r.name is not null group by r.name order by r.name
          ;"#, top
     )
-    .fetch_all(&state.pool)
+    .fetch_all(&mut *db)
     .await
     .map_err(|e| {
         tracing::error!("error getting total per region from database {e}");
@@ -182,3 +453,636 @@ pub async fn top_list(
     })?;
     Ok(Json(regions))
 }
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ExportFormat {
+    #[default]
+    Json,
+    Csv,
+}
+
+#[derive(Debug, Default, Deserialize)]
+pub struct ExportQuery {
+    #[serde(default)]
+    pub format: ExportFormat,
+}
+
+/// Writes `rows` as CSV under the given header, as a download named after
+/// the report.
+pub(crate) fn csv_response<H, R>(name: &str, header: H, rows: &[R]) -> Result<Response, StatusCode>
+where
+    H: IntoIterator,
+    H::Item: AsRef<[u8]>,
+    R: Serialize,
+{
+    let mut writer = csv::WriterBuilder::new()
+        .has_headers(false)
+        .from_writer(vec![]);
+    writer.write_record(header).map_err(|e| {
+        tracing::error!("error writing the {name} csv header {e}");
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+    for row in rows {
+        writer.serialize(row).map_err(|e| {
+            tracing::error!("error writing a {name} csv row {e}");
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?;
+    }
+    let body = writer.into_inner().map_err(|e| {
+        tracing::error!("error finishing the {name} csv {e}");
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+    Ok((
+        [
+            (header::CONTENT_TYPE, "text/csv; charset=utf-8".to_string()),
+            (
+                header::CONTENT_DISPOSITION,
+                format!("attachment; filename=\"{name}.csv\""),
+            ),
+        ],
+        body,
+    )
+        .into_response())
+}
+
+/// Answers with flat report rows in the requested format.
+fn export<R: Serialize>(
+    format: ExportFormat,
+    name: &str,
+    header: &[&str],
+    rows: Vec<R>,
+) -> Result<Response, StatusCode> {
+    match format {
+        ExportFormat::Json => Ok(Json(rows).into_response()),
+        ExportFormat::Csv => csv_response(name, header, &rows),
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct RegionShare {
+    pub region: String,
+    pub total: i64,
+    /// Fraction of all the quantities ordered in named regions.
+    pub share: f64,
+}
+
+async fn region_share(
+    State(state): State<SqlState>,
+    namespace: Namespace,
+    Query(query): Query<ExportQuery>,
+) -> Result<Response, StatusCode> {
+    tracing::info!("region share called");
+    let mut db = namespace.connect(&state.pool).await?;
+    let shares = sqlx::query_as!(
+        RegionShare,
+        r#"
+        select
+            r.name as "region!",
+            sum(o.quantity)::BIGINT as "total!",
+            coalesce(
+                sum(o.quantity)::FLOAT8 / nullif(sum(sum(o.quantity)) over (), 0),
+                0
+            ) as "share!"
+        from orders o
+        inner join regions r on o.region_id = r.id
+        where r.name is not null
+        group by r.id, r.name
+        order by r.name
+        "#
+    )
+    .fetch_all(&mut *db)
+    .await
+    .map_err(|e| {
+        tracing::error!("error getting region shares from database {e}");
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+    export(
+        query.format,
+        "region_share",
+        &["region", "total", "share"],
+        shares,
+    )
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PivotRow {
+    pub gift: String,
+    /// Quantity ordered in each region, in the order of `Pivot::regions`.
+    pub quantities: Vec<i64>,
+    pub total: i64,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Pivot {
+    pub regions: Vec<String>,
+    pub gifts: Vec<PivotRow>,
+}
+
+async fn gift_region_pivot(
+    State(state): State<SqlState>,
+    namespace: Namespace,
+    Query(query): Query<ExportQuery>,
+) -> Result<Response, StatusCode> {
+    tracing::info!("gift region pivot called");
+    let mut db = namespace.connect(&state.pool).await?;
+    // every gift against every named region, zero where nothing was ordered
+    let cells = sqlx::query!(
+        r#"
+        select
+            g.name as "gift!",
+            r.name as "region!",
+            coalesce(sum(o.quantity), 0)::BIGINT as "quantity!"
+        from gifts g
+        cross join regions r
+        left join orders o on o.gift_id = g.id and o.region_id = r.id
+        where r.name is not null
+        group by g.name, r.id, r.name
+        order by g.name, r.name, r.id
+        "#
+    )
+    .fetch_all(&mut *db)
+    .await
+    .map_err(|e| {
+        tracing::error!("error getting gift region pivot from database {e}");
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+
+    let mut pivot = Pivot {
+        regions: vec![],
+        gifts: vec![],
+    };
+    for cell in cells {
+        match pivot.gifts.last_mut() {
+            Some(row) if row.gift == cell.gift => {
+                row.quantities.push(cell.quantity);
+                row.total += cell.quantity;
+            }
+            _ => pivot.gifts.push(PivotRow {
+                gift: cell.gift,
+                quantities: vec![cell.quantity],
+                total: cell.quantity,
+            }),
+        }
+        // the first gift walks through all the regions
+        if pivot.gifts.len() == 1 {
+            pivot.regions.push(cell.region);
+        }
+    }
+
+    match query.format {
+        ExportFormat::Json => Ok(Json(pivot).into_response()),
+        ExportFormat::Csv => {
+            let header = std::iter::once("gift")
+                .chain(pivot.regions.iter().map(String::as_str))
+                .chain(std::iter::once("total"));
+            let rows: Vec<_> = pivot
+                .gifts
+                .iter()
+                .map(|row| (&row.gift, &row.quantities, row.total))
+                .collect();
+            csv_response("gift_region_pivot", header, &rows)
+        }
+    }
+}
+
+async fn empty_regions(
+    State(state): State<SqlState>,
+    namespace: Namespace,
+    Query(query): Query<ExportQuery>,
+) -> Result<Response, StatusCode> {
+    tracing::info!("empty regions called");
+    let mut db = namespace.connect(&state.pool).await?;
+    let regions = sqlx::query_as!(
+        Region,
+        r#"
+        select r.id, r.name as "name!", r.parent_id
+        from regions r
+        where r.name is not null
+            and not exists (select 1 from orders o where o.region_id = r.id)
+        order by r.id
+        "#
+    )
+    .fetch_all(&mut *db)
+    .await
+    .map_err(|e| {
+        tracing::error!("error getting empty regions from database {e}");
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+    export(
+        query.format,
+        "empty_regions",
+        &["id", "name", "parent_id"],
+        regions,
+    )
+}
+
+#[derive(Debug, Default, Deserialize)]
+pub struct RunningTotalsQuery {
+    /// Only the orders of this region id.
+    pub region: Option<i32>,
+    #[serde(default)]
+    pub format: ExportFormat,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RunningTotal {
+    pub order_id: i32,
+    pub region_id: i32,
+    pub gift_name: String,
+    pub quantity: i32,
+    /// Quantity of this order and every order with a lower id.
+    pub running_total: i64,
+    /// The same, counting only the orders of this region.
+    pub region_running_total: i64,
+}
+
+async fn running_totals(
+    State(state): State<SqlState>,
+    namespace: Namespace,
+    Query(query): Query<RunningTotalsQuery>,
+) -> Result<Response, StatusCode> {
+    tracing::info!("running totals called {query:?}");
+    let mut db = namespace.connect(&state.pool).await?;
+    let totals = sqlx::query_as!(
+        RunningTotal,
+        r#"
+        select
+            o.id as order_id,
+            o.region_id,
+            g.name as gift_name,
+            o.quantity,
+            sum(o.quantity) over (order by o.id) as "running_total!",
+            sum(o.quantity) over (partition by o.region_id order by o.id)
+                as "region_running_total!"
+        from orders o
+        inner join gifts g on g.id = o.gift_id
+        where ($1::int is null or o.region_id = $1)
+        order by o.id
+        "#,
+        query.region
+    )
+    .fetch_all(&mut *db)
+    .await
+    .map_err(|e| {
+        tracing::error!("error getting running totals from database {e}");
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+    export(
+        query.format,
+        "running_totals",
+        &[
+            "order_id",
+            "region_id",
+            "gift_name",
+            "quantity",
+            "running_total",
+            "region_running_total",
+        ],
+        totals,
+    )
+}
+
+const DEFAULT_PERCENTILES: [f64; 5] = [0.25, 0.5, 0.75, 0.9, 0.99];
+const MAX_PERCENTILES: usize = 100;
+
+#[derive(Debug, Default, Deserialize)]
+pub struct PercentilesQuery {
+    /// Comma separated fractions between 0 and 1, e.g. `0.5,0.9`.
+    pub p: Option<String>,
+    /// Only the orders of this region id.
+    pub region: Option<i32>,
+    #[serde(default)]
+    pub format: ExportFormat,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Percentile {
+    pub p: f64,
+    /// Interpolated order size, `None` without any order.
+    pub quantity: Option<f64>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct OrderSizeDistribution {
+    pub orders: i64,
+    pub min: Option<i32>,
+    pub max: Option<i32>,
+    pub mean: Option<f64>,
+    pub percentiles: Vec<Percentile>,
+}
+
+fn parse_percentiles(p: Option<&str>) -> Result<Vec<f64>, OrdersRejection> {
+    let Some(p) = p else {
+        return Ok(DEFAULT_PERCENTILES.to_vec());
+    };
+    let invalid = |message: String| OrdersRejection::Invalid(vec![FieldError::new("p", message)]);
+    let fractions = p
+        .split(',')
+        .map(|fraction| match fraction.trim().parse::<f64>() {
+            Ok(fraction) if (0.0..=1.0).contains(&fraction) => Ok(fraction),
+            _ => Err(invalid(format!(
+                "{fraction:?} is not a fraction between 0 and 1"
+            ))),
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    if fractions.len() > MAX_PERCENTILES {
+        return Err(invalid(format!(
+            "must list at most {MAX_PERCENTILES} percentiles"
+        )));
+    }
+    Ok(fractions)
+}
+
+async fn order_size_percentiles(
+    State(state): State<SqlState>,
+    namespace: Namespace,
+    Query(query): Query<PercentilesQuery>,
+) -> Result<Response, OrdersRejection> {
+    tracing::info!("order size percentiles called {query:?}");
+    let fractions = parse_percentiles(query.p.as_deref())?;
+    let mut db = namespace.connect(&state.pool).await?;
+    let row = sqlx::query!(
+        r#"
+        select
+            count(*) as "orders!",
+            min(quantity) as min,
+            max(quantity) as max,
+            avg(quantity)::FLOAT8 as mean,
+            percentile_cont($1::FLOAT8[]) within group (order by quantity) as percentiles
+        from orders
+        where ($2::int is null or region_id = $2)
+        "#,
+        &fractions,
+        query.region
+    )
+    .fetch_one(&mut *db)
+    .await
+    .map_err(|e| {
+        tracing::error!("error getting order size percentiles from database {e}");
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+
+    let quantities = row.percentiles.unwrap_or_default();
+    let percentiles: Vec<Percentile> = fractions
+        .iter()
+        .enumerate()
+        .map(|(i, &p)| Percentile {
+            p,
+            quantity: quantities.get(i).copied(),
+        })
+        .collect();
+    match query.format {
+        ExportFormat::Json => Ok(Json(OrderSizeDistribution {
+            orders: row.orders,
+            min: row.min,
+            max: row.max,
+            mean: row.mean,
+            percentiles,
+        })
+        .into_response()),
+        ExportFormat::Csv => Ok(csv_response(
+            "order_size_percentiles",
+            ["p", "quantity"],
+            &percentiles,
+        )?),
+    }
+}
+
+/// Longest region name the `VARCHAR(50)` column takes, in characters.
+const MAX_REGION_NAME_LEN: usize = 50;
+
+impl ImportRow for Region {
+    fn problems(&self) -> Vec<FieldError> {
+        let mut errors = vec![];
+        if self.name.chars().count() > MAX_REGION_NAME_LEN {
+            errors.push(FieldError::new(
+                "name",
+                format!("must be at most {MAX_REGION_NAME_LEN} characters"),
+            ));
+        }
+        if self.parent_id == Some(self.id) {
+            errors.push(FieldError::new(
+                "parent_id",
+                "a region can't be part of itself",
+            ));
+        }
+        errors
+    }
+
+    fn record(&self) -> Vec<String> {
+        vec![
+            self.id.to_string(),
+            self.name.clone(),
+            self.parent_id
+                .map(|parent_id| parent_id.to_string())
+                .unwrap_or_default(),
+        ]
+    }
+}
+
+// The import statements below work on a staging table that only lives in
+// the importing transaction, so they can't be checked at build time.
+
+/// Names regions nobody described yet, and only those, keeping the first
+/// line of an id.
+const MERGE_IMPORTED_REGIONS: &str = r#"
+    insert into regions(id, name, parent_id)
+    select distinct on (id) id, name, parent_id
+    from regions_import
+    order by id, line
+    on conflict (id) do update set
+        name = excluded.name,
+        parent_id = excluded.parent_id,
+        updated_at = now()
+        where regions.name is null
+    returning id, (xmax = 0) as inserted
+"#;
+
+const MERGE_IMPORTED_REGIONS_UPSERT: &str = r#"
+    insert into regions(id, name, parent_id)
+    select distinct on (id) id, name, parent_id
+    from regions_import
+    order by id, line desc
+    on conflict (id) do update set
+        name = excluded.name,
+        parent_id = excluded.parent_id,
+        updated_at = now()
+    returning id, (xmax = 0) as inserted
+"#;
+
+async fn import_regions(
+    State(state): State<SqlState>,
+    namespace: Namespace,
+    Query(options): Query<ImportQuery>,
+    headers: HeaderMap,
+    body: Body,
+) -> Result<Response, OrdersRejection> {
+    tracing::info!("import regions called {options:?}");
+    let format = options.format(&headers)?;
+    if options.dry_run {
+        let report =
+            bulk::import::<Region, &mut PgConnection>(body, format, None::<PgCopyIn<_>>).await?;
+        return Ok(Json(report).into_response());
+    }
+
+    let mut db = namespace.connect(&state.pool).await?;
+    let mut transaction = db.begin().await.map_err(|e| {
+        tracing::error!("error starting transaction {e}");
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+    sqlx::query(
+        r#"
+        create temp table regions_import (
+            line BIGINT,
+            id INT,
+            name TEXT,
+            parent_id INT
+        ) on commit drop
+        "#,
+    )
+    .execute(&mut *transaction)
+    .await
+    .map_err(|e| {
+        tracing::error!("error creating the regions staging table {e}");
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+    let copy = transaction
+        .copy_in_raw("copy regions_import from stdin with (format csv, force_not_null (name))")
+        .await
+        .map_err(|e| {
+            tracing::error!("error starting the regions copy {e}");
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?;
+    let report = bulk::import::<Region, _>(body, format, Some(copy)).await?;
+    if report.invalid > 0 {
+        return Ok(report.into_response());
+    }
+
+    if options.on_conflict == OnConflict::Error {
+        let duplicated: bool =
+            sqlx::query_scalar("select count(*) <> count(distinct id) from regions_import")
+                .fetch_one(&mut *transaction)
+                .await
+                .map_err(|e| {
+                    tracing::error!("error looking for duplicate imported regions {e}");
+                    StatusCode::INTERNAL_SERVER_ERROR
+                })?;
+        if duplicated {
+            tracing::error!("duplicate region ids in the import");
+            return Err(StatusCode::CONFLICT.into());
+        }
+    }
+    sqlx::query(
+        r#"
+        insert into regions(id)
+        select distinct parent_id
+        from regions_import
+        where parent_id is not null
+            and parent_id not in (select id from regions_import)
+        on conflict (id) do nothing
+        "#,
+    )
+    .execute(&mut *transaction)
+    .await
+    .map_err(|e| {
+        tracing::error!("error adding parent regions of the import {e}");
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+    // regions only known from their orders yet, naming them counts as an insert
+    let unnamed: HashSet<i32> = sqlx::query_scalar(
+        "select id from regions where name is null and id in (select id from regions_import)",
+    )
+    .fetch_all(&mut *transaction)
+    .await
+    .map_err(|e| {
+        tracing::error!("error fetching unnamed regions {e}");
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?
+    .into_iter()
+    .collect();
+    let merge = match options.on_conflict {
+        OnConflict::Error | OnConflict::Ignore => MERGE_IMPORTED_REGIONS,
+        OnConflict::Upsert => MERGE_IMPORTED_REGIONS_UPSERT,
+    };
+    let written: Vec<(i32, bool)> = sqlx::query_as(merge)
+        .fetch_all(&mut *transaction)
+        .await
+        .map_err(|e| {
+            tracing::error!("error merging the imported regions {e}");
+            insert_error(e)
+        })?;
+    if options.on_conflict == OnConflict::Error && written.len() < report.valid as usize {
+        tracing::error!("imported regions already exist");
+        return Err(StatusCode::CONFLICT.into());
+    }
+    let ids: Vec<i32> = written.iter().map(|(id, _)| *id).collect();
+    check_hierarchy(&mut transaction, &ids).await?;
+    transaction.commit().await.map_err(|e| {
+        tracing::error!("error commiting the transaction {e}");
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+    let returned: Vec<bool> = written
+        .into_iter()
+        .map(|(id, inserted)| inserted || unnamed.contains(&id))
+        .collect();
+    Ok(Json(InsertSummary::from_returned(
+        report.valid as usize,
+        &returned,
+    ))
+    .into_response())
+}
+
+/// Regions only known from their orders are left out, importing the
+/// export brings them back from the references.
+const REGIONS_EXPORT: &str = r#"
+    select id, name, parent_id
+    from regions
+    where name is not null
+    order by id
+"#;
+
+const REGIONS_PARQUET_SCHEMA: &str = r#"
+    message region {
+        REQUIRED INT32 id;
+        REQUIRED BYTE_ARRAY name (UTF8);
+        OPTIONAL INT32 parent_id;
+    }
+"#;
+
+async fn export_regions(
+    State(state): State<SqlState>,
+    namespace: Namespace,
+    Query(query): Query<ExportTableQuery>,
+) -> Result<Response, StatusCode> {
+    tracing::info!("export regions called {query:?}");
+    let mut db = namespace.connect(&state.pool).await?;
+    if query.format != BulkFormat::Parquet {
+        return bulk::copy_out(db, "regions", query.format, REGIONS_EXPORT).await;
+    }
+
+    let regions = sqlx::query_as!(
+        Region,
+        r#"
+        select id, name as "name!", parent_id
+        from regions
+        where name is not null
+        order by id
+        "#
+    )
+    .fetch_all(&mut *db)
+    .await
+    .map_err(|e| {
+        tracing::error!("error fetching regions to export {e}");
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+    bulk::parquet_response(
+        "regions",
+        REGIONS_PARQUET_SCHEMA,
+        vec![
+            ParquetColumn::Int32(regions.iter().map(|region| region.id).collect()),
+            ParquetColumn::Utf8(regions.iter().map(|region| region.name.clone()).collect()),
+            ParquetColumn::OptionalInt32(regions.iter().map(|region| region.parent_id).collect()),
+        ],
+    )
+}
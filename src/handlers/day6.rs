@@ -1,15 +1,137 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::{Arc, RwLock},
+    time::{Duration, Instant},
+};
+
 use axum::{
+    extract::{Query, State},
     http::StatusCode,
+    response::{IntoResponse, Response},
     routing::{get, post},
     Json,
 };
-use fancy_regex::Regex;
+use fancy_regex::{Regex, RegexBuilder};
 use serde::{Deserialize, Serialize};
+use unicode_normalization::UnicodeNormalization;
+
+// Limits that keep user supplied patterns from eating the server.
+const MAX_PATTERNS: usize = 32;
+const MAX_PATTERN_LEN: usize = 256;
+const MAX_TEXT_LEN: usize = 1024 * 1024;
+const MAX_CACHED_PATTERNS: usize = 1024;
+const BACKTRACK_LIMIT: usize = 100_000;
+const DELEGATE_SIZE_LIMIT: usize = 1024 * 1024;
+// Overlapping matches search the rest of the text again after every match
+// start, so they get a smaller text and a cap on the matches.
+const MAX_OVERLAPPING_TEXT_LEN: usize = 64 * 1024;
+const MAX_OVERLAPPING_MATCHES: usize = 10_000;
+// How long one request may spend looking for overlapping matches.
+const MATCH_TIME_BUDGET: Duration = Duration::from_secs(1);
+
+type SharedPatternCache = Arc<PatternCache>;
 
 pub fn router() -> axum::Router {
     axum::Router::new()
         .route("/6", post(elf_on_shelf))
+        .route("/6/count", post(count_patterns))
         .route("/6/health", get(|| async { StatusCode::OK }))
+        .with_state(Arc::new(PatternCache::default()))
+}
+
+/// Compiled regexes shared across requests, keyed by their final source.
+#[derive(Default)]
+struct PatternCache {
+    compiled: RwLock<HashMap<String, Regex>>,
+}
+
+impl PatternCache {
+    fn get_or_compile(&self, source: &str) -> Result<Regex, StatusCode> {
+        if let Some(re) = self
+            .compiled
+            .read()
+            .map_err(|e| {
+                tracing::error!("error while getting read lock {e}");
+                StatusCode::INTERNAL_SERVER_ERROR
+            })?
+            .get(source)
+        {
+            return Ok(re.clone());
+        }
+
+        let re = RegexBuilder::new(source)
+            .backtrack_limit(BACKTRACK_LIMIT)
+            .delegate_size_limit(DELEGATE_SIZE_LIMIT)
+            .build()
+            .map_err(|e| {
+                tracing::error!("couldn't compile the regex {source}: {e}");
+                StatusCode::BAD_REQUEST
+            })?;
+
+        let mut compiled = self.compiled.write().map_err(|e| {
+            tracing::error!("error while getting write lock {e}");
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?;
+        // the cache is only there to skip recompiling hot patterns,
+        // so starting over when it's full is good enough
+        if compiled.len() >= MAX_CACHED_PATTERNS {
+            compiled.clear();
+        }
+        compiled.insert(source.to_string(), re.clone());
+        Ok(re)
+    }
+}
+
+/// Returns the byte ranges of every match of `re` in `text`.
+///
+/// With `overlapping` the search restarts one character after the start of
+/// the previous match instead of at its end, and gives up with 422 after
+/// [`MAX_OVERLAPPING_MATCHES`] matches or once `deadline` has passed.
+fn find_matches(
+    re: &Regex,
+    text: &str,
+    overlapping: bool,
+    deadline: Instant,
+) -> Result<Vec<MatchOffset>, StatusCode> {
+    let to_status = |e: fancy_regex::Error| {
+        tracing::error!("error while matching the regex {e}");
+        StatusCode::UNPROCESSABLE_ENTITY
+    };
+
+    if !overlapping {
+        return re
+            .find_iter(text)
+            .map(|m| {
+                m.map(|m| MatchOffset {
+                    start: m.start(),
+                    end: m.end(),
+                })
+                .map_err(to_status)
+            })
+            .collect();
+    }
+
+    let mut matches = Vec::new();
+    let mut pos = 0;
+    while let Some(m) = re.find_from_pos(text, pos).map_err(to_status)? {
+        if matches.len() == MAX_OVERLAPPING_MATCHES {
+            tracing::error!("more than {MAX_OVERLAPPING_MATCHES} overlapping matches");
+            return Err(StatusCode::UNPROCESSABLE_ENTITY);
+        }
+        if Instant::now() > deadline {
+            tracing::error!("overlapping matches took longer than {MATCH_TIME_BUDGET:?}");
+            return Err(StatusCode::UNPROCESSABLE_ENTITY);
+        }
+        matches.push(MatchOffset {
+            start: m.start(),
+            end: m.end(),
+        });
+        match text[m.start()..].chars().next() {
+            Some(c) => pos = m.start() + c.len_utf8(),
+            None => break,
+        }
+    }
+    Ok(matches)
 }
 
 #[derive(Debug, Serialize, Deserialize, Default, PartialEq, Eq)]
@@ -21,42 +143,215 @@ struct ElfOnShelfResult {
     shelf_with_no_elf_on_it: u64,
 }
 
-async fn elf_on_shelf(elf_text: String) -> Result<Json<ElfOnShelfResult>, StatusCode> {
-    tracing::info!("elf_text: {elf_text}");
-    let shelf = Regex::new("shelf")
-        .map_err(|e| {
-            eprintln!("ERR: coud't make the regex {e}");
-            StatusCode::INTERNAL_SERVER_ERROR
-        })?
-        .captures_iter(elf_text.as_str())
-        .count() as u64;
-    let elf_on_a_shelf = Regex::new("elf(?= on a shelf)")
-        .map_err(|e| {
-            eprintln!("ERR: coud't make the regex {e}");
-            StatusCode::INTERNAL_SERVER_ERROR
-        })?
-        .captures_iter(elf_text.as_str())
-        .count() as u64;
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+enum Normalization {
+    Nfc,
+    Nfkc,
+}
 
-    let elf = Regex::new("elf")
-        .map_err(|e| {
-            eprintln!("ERR: coud't make the regex {e}");
-            StatusCode::INTERNAL_SERVER_ERROR
-        })?
-        .captures_iter(elf_text.as_str())
-        .count() as u64;
+#[derive(Debug, Serialize, Deserialize, Default, Clone, Copy, PartialEq, Eq)]
+struct ElfOnShelfOptions {
+    #[serde(default)]
+    normalize: Option<Normalization>,
+    #[serde(default)]
+    case_fold: bool,
+    #[serde(default)]
+    overlapping: bool,
+    #[serde(default)]
+    per_line: bool,
+}
 
-    tracing::info!(
-        "elf_text result: {:?}",
-        ElfOnShelfResult {
-            elf,
-            elf_on_a_shelf,
-            shelf_with_no_elf_on_it: shelf - elf_on_a_shelf,
+impl ElfOnShelfOptions {
+    // Apply the requested normalization and case folding to the text
+    fn prepare(&self, text: &str) -> String {
+        let text = match self.normalize {
+            Some(Normalization::Nfc) => text.nfc().collect(),
+            Some(Normalization::Nfkc) => text.nfkc().collect(),
+            None => text.to_string(),
+        };
+        if self.case_fold {
+            caseless::default_case_fold_str(&text)
+        } else {
+            text
         }
-    );
-    Ok(Json(ElfOnShelfResult {
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Default, PartialEq, Eq)]
+struct LineElfOnShelfResult {
+    line: usize,
+    #[serde(flatten)]
+    result: ElfOnShelfResult,
+}
+
+fn count_elves(
+    cache: &PatternCache,
+    text: &str,
+    overlapping: bool,
+    deadline: Instant,
+) -> Result<ElfOnShelfResult, StatusCode> {
+    let count = |source: &str| -> Result<u64, StatusCode> {
+        let re = cache.get_or_compile(source)?;
+        Ok(find_matches(&re, text, overlapping, deadline)?.len() as u64)
+    };
+
+    let shelf = count("shelf")?;
+    let elf_on_a_shelf = count("elf(?= on a shelf)")?;
+    let elf = count("elf")?;
+
+    Ok(ElfOnShelfResult {
         elf,
         elf_on_a_shelf,
-        shelf_with_no_elf_on_it: shelf - elf_on_a_shelf,
-    }))
+        shelf_with_no_elf_on_it: shelf.saturating_sub(elf_on_a_shelf),
+    })
+}
+
+async fn elf_on_shelf(
+    State(cache): State<SharedPatternCache>,
+    Query(options): Query<ElfOnShelfOptions>,
+    elf_text: String,
+) -> Result<Response, StatusCode> {
+    tracing::info!("elf_text: {elf_text} with {options:?}");
+    if options.overlapping && elf_text.len() > MAX_OVERLAPPING_TEXT_LEN {
+        tracing::error!("text too long for overlapping matches");
+        return Err(StatusCode::PAYLOAD_TOO_LARGE);
+    }
+
+    // matching a long text is cpu bound, keep it off the async workers
+    tokio::task::spawn_blocking(move || {
+        let deadline = Instant::now() + MATCH_TIME_BUDGET;
+        let elf_text = options.prepare(&elf_text);
+
+        if options.per_line {
+            let results = elf_text
+                .lines()
+                .enumerate()
+                .map(|(i, line)| {
+                    Ok(LineElfOnShelfResult {
+                        line: i + 1,
+                        result: count_elves(&cache, line, options.overlapping, deadline)?,
+                    })
+                })
+                .collect::<Result<Vec<_>, StatusCode>>()?;
+            return Ok(Json(results).into_response());
+        }
+
+        let result = count_elves(&cache, &elf_text, options.overlapping, deadline)?;
+        tracing::info!("elf_text result: {result:?}");
+        Ok(Json(result).into_response())
+    })
+    .await
+    .map_err(|e| {
+        tracing::error!("error counting the elves {e}");
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?
+}
+
+#[derive(Debug, Serialize, Deserialize, Default, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+enum PatternKind {
+    #[default]
+    Literal,
+    Regex,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+struct PatternSpec {
+    name: String,
+    pattern: String,
+    #[serde(default)]
+    kind: PatternKind,
+    #[serde(default)]
+    case_insensitive: bool,
+    #[serde(default)]
+    whole_word: bool,
+}
+
+impl PatternSpec {
+    // Build the regex source the pattern compiles to
+    fn regex_source(&self) -> String {
+        let mut source = match self.kind {
+            PatternKind::Literal => fancy_regex::escape(&self.pattern).into_owned(),
+            PatternKind::Regex => self.pattern.clone(),
+        };
+        if self.whole_word {
+            source = format!(r"\b(?:{source})\b");
+        }
+        if self.case_insensitive {
+            source = format!("(?i){source}");
+        }
+        source
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+struct CountRequest {
+    text: String,
+    patterns: Vec<PatternSpec>,
+    #[serde(default)]
+    overlapping: bool,
+    #[serde(default)]
+    offsets: bool,
+}
+
+#[derive(Debug, Serialize, Deserialize, Default, Clone, Copy, PartialEq, Eq)]
+struct MatchOffset {
+    start: usize,
+    end: usize,
+}
+
+#[derive(Debug, Serialize, Deserialize, Default, Clone, PartialEq, Eq)]
+struct PatternCount {
+    count: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    offsets: Option<Vec<MatchOffset>>,
+}
+
+async fn count_patterns(
+    State(cache): State<SharedPatternCache>,
+    Json(request): Json<CountRequest>,
+) -> Result<Json<BTreeMap<String, PatternCount>>, StatusCode> {
+    tracing::info!(
+        "count patterns called with {} patterns",
+        request.patterns.len()
+    );
+    if request.patterns.len() > MAX_PATTERNS
+        || request.text.len() > MAX_TEXT_LEN
+        || request
+            .patterns
+            .iter()
+            .any(|p| p.pattern.len() > MAX_PATTERN_LEN)
+    {
+        tracing::error!("count request exceeds the size limits");
+        return Err(StatusCode::PAYLOAD_TOO_LARGE);
+    }
+    if request.overlapping && request.text.len() > MAX_OVERLAPPING_TEXT_LEN {
+        tracing::error!("text too long for overlapping matches");
+        return Err(StatusCode::PAYLOAD_TOO_LARGE);
+    }
+
+    // matching a long text is cpu bound, keep it off the async workers
+    tokio::task::spawn_blocking(move || {
+        let deadline = Instant::now() + MATCH_TIME_BUDGET;
+        let mut counts = BTreeMap::new();
+        for spec in &request.patterns {
+            let re = cache.get_or_compile(&spec.regex_source())?;
+            let matches = find_matches(&re, &request.text, request.overlapping, deadline)?;
+            let count = PatternCount {
+                count: matches.len() as u64,
+                offsets: request.offsets.then_some(matches),
+            };
+            if counts.insert(spec.name.clone(), count).is_some() {
+                tracing::error!("duplicate pattern name {}", spec.name);
+                return Err(StatusCode::BAD_REQUEST);
+            }
+        }
+        Ok(Json(counts))
+    })
+    .await
+    .map_err(|e| {
+        tracing::error!("error counting the patterns {e}");
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?
 }
@@ -1,26 +1,209 @@
-use std::collections::HashMap;
+use std::{collections::HashMap, fmt, str::FromStr};
 
-use axum::{http::StatusCode, routing::get, Json};
+use aes_gcm::{
+    aead::{Aead, AeadCore, KeyInit, OsRng},
+    Aes256Gcm, Nonce,
+};
+use axum::{
+    extract::State,
+    http::{header, StatusCode},
+    response::IntoResponse,
+    routing::{get, post},
+    Json,
+};
 use axum_extra::{headers::Cookie, TypedHeader};
-use base64::{engine::general_purpose, Engine};
-use serde::{Deserialize, Serialize};
+use base64::{
+    alphabet,
+    engine::{general_purpose, DecodePaddingMode, GeneralPurpose, GeneralPurposeConfig},
+    Engine,
+};
+use hmac::{Hmac, Mac};
+use itertools::Itertools;
+use rust_decimal::{prelude::ToPrimitive, Decimal};
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
 use serde_json::Value;
+use sha2::{Digest, Sha256};
 
-pub fn router() -> axum::Router {
+use crate::config::CookieFormat;
+
+type HmacSha256 = Hmac<Sha256>;
+
+// Engines accepting both padded and unpadded input
+const STANDARD_ANY_PAD: GeneralPurpose = GeneralPurpose::new(
+    &alphabet::STANDARD,
+    GeneralPurposeConfig::new().with_decode_padding_mode(DecodePaddingMode::Indifferent),
+);
+const URL_SAFE_ANY_PAD: GeneralPurpose = GeneralPurpose::new(
+    &alphabet::URL_SAFE,
+    GeneralPurposeConfig::new().with_decode_padding_mode(DecodePaddingMode::Indifferent),
+);
+
+const NONCE_LEN: usize = 12;
+
+// Grams in one cup, so recipes in cups can be baked from a pantry in grams
+const GRAMS_PER_CUP: &[(&str, u32)] = &[
+    ("butter", 227),
+    ("chocolate chips", 170),
+    ("cocoa", 85),
+    ("flour", 120),
+    ("milk", 245),
+    ("oats", 90),
+    ("sugar", 200),
+];
+
+// Keep the planner from searching forever on big inputs
+const MAX_PLAN_RECIPES: usize = 16;
+const MAX_PLAN_INGREDIENTS: usize = 32;
+const MAX_PLAN_NODES: usize = 1_000_000;
+
+pub fn router(cookie_codec: RecipeCookieCodec) -> axum::Router {
     axum::Router::new()
         .route("/7/decode", get(santa_cookie))
         .route("/7/bake", get(secret_cookie))
+        .route("/7/plan", post(plan_cookies))
         .route("/7/health", get(|| async { StatusCode::OK }))
+        .with_state(cookie_codec)
+}
+
+/// Reads and writes the `recipe` cookie in the configured format.
+///
+/// Plain cookies are base64 JSON, signed cookies are `payload.hmac` and
+/// encrypted cookies are the AES-256-GCM nonce followed by the ciphertext.
+#[derive(Clone, Debug)]
+pub struct RecipeCookieCodec {
+    format: CookieFormat,
+    // keys derived from the configured secrets, the first one is the active key
+    keys: Vec<[u8; 32]>,
+}
+
+/// Signed and encrypted cookies were configured without any secret.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MissingCookieKeys(pub CookieFormat);
+
+impl fmt::Display for MissingCookieKeys {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "cookie keys are required for {:?} recipe cookies",
+            self.0
+        )
+    }
+}
+
+impl std::error::Error for MissingCookieKeys {}
+
+impl RecipeCookieCodec {
+    pub fn new(format: CookieFormat, secrets: &[String]) -> Result<Self, MissingCookieKeys> {
+        let keys: Vec<[u8; 32]> = secrets
+            .iter()
+            .filter(|secret| !secret.is_empty())
+            .map(|secret| Sha256::digest(secret.as_bytes()).into())
+            .collect();
+        if format != CookieFormat::Plain && keys.is_empty() {
+            return Err(MissingCookieKeys(format));
+        }
+        Ok(Self { format, keys })
+    }
+
+    pub fn decode(&self, value: &str) -> Result<Vec<u8>, StatusCode> {
+        match self.format {
+            CookieFormat::Plain => decode_base64(value),
+            CookieFormat::Signed => {
+                let (payload, signature) = value.rsplit_once('.').ok_or_else(|| {
+                    tracing::error!("signed recipe cookie without a signature");
+                    StatusCode::BAD_REQUEST
+                })?;
+                let signature = decode_base64(signature)?;
+                self.keys
+                    .iter()
+                    .find(|key| {
+                        hmac_for(key.as_slice(), payload.as_bytes())
+                            .verify_slice(&signature)
+                            .is_ok()
+                    })
+                    .ok_or_else(|| {
+                        tracing::error!("recipe cookie signature doesn't match any key");
+                        StatusCode::UNAUTHORIZED
+                    })?;
+                decode_base64(payload)
+            }
+            CookieFormat::Encrypted => {
+                let data = decode_base64(value)?;
+                if data.len() < NONCE_LEN {
+                    tracing::error!("encrypted recipe cookie is too short");
+                    return Err(StatusCode::BAD_REQUEST);
+                }
+                let (nonce, ciphertext) = data.split_at(NONCE_LEN);
+                self.keys
+                    .iter()
+                    .find_map(|key| {
+                        Aes256Gcm::new(key.into())
+                            .decrypt(Nonce::from_slice(nonce), ciphertext)
+                            .ok()
+                    })
+                    .ok_or_else(|| {
+                        tracing::error!("recipe cookie couldn't be decrypted with any key");
+                        StatusCode::UNAUTHORIZED
+                    })
+            }
+        }
+    }
+
+    pub fn encode(&self, payload: &[u8]) -> Result<String, StatusCode> {
+        match self.format {
+            CookieFormat::Plain => Ok(general_purpose::STANDARD.encode(payload)),
+            CookieFormat::Signed => {
+                let payload = general_purpose::URL_SAFE_NO_PAD.encode(payload);
+                let signature = hmac_for(&self.keys[0], payload.as_bytes())
+                    .finalize()
+                    .into_bytes();
+                Ok(format!(
+                    "{payload}.{}",
+                    general_purpose::URL_SAFE_NO_PAD.encode(signature)
+                ))
+            }
+            CookieFormat::Encrypted => {
+                let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+                let ciphertext = Aes256Gcm::new((&self.keys[0]).into())
+                    .encrypt(&nonce, payload)
+                    .map_err(|e| {
+                        tracing::error!("error while encrypting the recipe cookie {e}");
+                        StatusCode::INTERNAL_SERVER_ERROR
+                    })?;
+                let mut data = nonce.to_vec();
+                data.extend(ciphertext);
+                Ok(general_purpose::URL_SAFE_NO_PAD.encode(data))
+            }
+        }
+    }
+}
+
+fn hmac_for(key: &[u8], payload: &[u8]) -> HmacSha256 {
+    let mut mac =
+        <HmacSha256 as Mac>::new_from_slice(key).expect("HMAC can take a key of any size");
+    mac.update(payload);
+    mac
+}
+
+// Accept standard and URL safe base64, with or without padding
+fn decode_base64(value: &str) -> Result<Vec<u8>, StatusCode> {
+    STANDARD_ANY_PAD
+        .decode(value)
+        .or_else(|_| URL_SAFE_ANY_PAD.decode(value))
+        .map_err(|e| {
+            eprintln!("ERR: error while decoding recipe from base64 {e}");
+            StatusCode::BAD_REQUEST
+        })
 }
 
 #[axum::debug_handler]
-async fn santa_cookie(TypedHeader(cookie): TypedHeader<Cookie>) -> Result<Json<Value>, StatusCode> {
+async fn santa_cookie(
+    State(cookie_codec): State<RecipeCookieCodec>,
+    TypedHeader(cookie): TypedHeader<Cookie>,
+) -> Result<Json<Value>, StatusCode> {
     let recipe = cookie.get("recipe").ok_or(StatusCode::BAD_REQUEST)?;
 
-    let de = general_purpose::STANDARD.decode(recipe).map_err(|e| {
-        eprintln!("ERR: error while decoding recipe from base64 {e}");
-        StatusCode::BAD_REQUEST
-    })?;
+    let de = cookie_codec.decode(recipe)?;
 
     let recipe_pantry: Value = serde_json::from_slice(&de).map_err(|e| {
         eprintln!("ERR: error while deserialize from json {e}");
@@ -29,59 +212,559 @@ async fn santa_cookie(TypedHeader(cookie): TypedHeader<Cookie>) -> Result<Json<V
     Ok(Json(recipe_pantry))
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Unit {
+    Gram,
+    Kilogram,
+    Cup,
+    Piece,
+}
+
+impl Unit {
+    fn symbol(&self) -> &'static str {
+        match self {
+            Unit::Gram => "g",
+            Unit::Kilogram => "kg",
+            Unit::Cup => "cups",
+            Unit::Piece => "pieces",
+        }
+    }
+
+    // How many grams one of this unit weighs, if it's known for the ingredient
+    fn grams(&self, ingredient: &str) -> Option<Decimal> {
+        match self {
+            Unit::Gram => Some(Decimal::ONE),
+            Unit::Kilogram => Some(Decimal::ONE_THOUSAND),
+            Unit::Cup => GRAMS_PER_CUP
+                .iter()
+                .find(|(name, _)| *name == ingredient)
+                .map(|&(_, grams)| Decimal::from(grams)),
+            Unit::Piece => None,
+        }
+    }
+}
+
+impl FromStr for Unit {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "g" | "gram" | "grams" => Ok(Unit::Gram),
+            "kg" | "kilogram" | "kilograms" => Ok(Unit::Kilogram),
+            "cup" | "cups" => Ok(Unit::Cup),
+            "pc" | "pcs" | "piece" | "pieces" => Ok(Unit::Piece),
+            _ => Err(format!("unknown unit {s}")),
+        }
+    }
+}
+
+/// An ingredient amount, either a plain number or a string like `"1.5 kg"`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Quantity {
+    amount: Decimal,
+    unit: Option<Unit>,
+}
+
+impl Quantity {
+    // Express `amount` given in `from` in the unit of this quantity
+    fn convert(&self, amount: Decimal, from: Option<Unit>, ingredient: &str) -> Option<Decimal> {
+        match (from, self.unit) {
+            (from, to) if from == to => Some(amount),
+            (Some(from), Some(to)) => amount
+                .checked_mul(from.grams(ingredient)?)?
+                .checked_div(to.grams(ingredient)?),
+            _ => None,
+        }
+    }
+}
+
+impl FromStr for Quantity {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        let split = s
+            .find(|c: char| !(c.is_ascii_digit() || c == '.'))
+            .unwrap_or(s.len());
+        let (amount, unit) = s.split_at(split);
+        let amount = Decimal::from_str(amount).map_err(|e| format!("invalid amount {s}: {e}"))?;
+        let unit = match unit.trim() {
+            "" => None,
+            unit => Some(unit.parse()?),
+        };
+        Ok(Quantity { amount, unit })
+    }
+}
+
+impl fmt::Display for Quantity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.unit {
+            Some(unit) => write!(f, "{} {}", self.amount.normalize(), unit.symbol()),
+            None => write!(f, "{}", self.amount.normalize()),
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for Quantity {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct QuantityVisitor;
+
+        impl<'de> de::Visitor<'de> for QuantityVisitor {
+            type Value = Quantity;
+
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                f.write_str("a non negative number or a string like \"1.5 kg\"")
+            }
+
+            fn visit_u64<E: de::Error>(self, v: u64) -> Result<Quantity, E> {
+                Ok(Quantity {
+                    amount: Decimal::from(v),
+                    unit: None,
+                })
+            }
+
+            fn visit_i64<E: de::Error>(self, v: i64) -> Result<Quantity, E> {
+                u64::try_from(v)
+                    .map_err(|_| E::custom("quantities can't be negative"))
+                    .and_then(|v| self.visit_u64(v))
+            }
+
+            fn visit_f64<E: de::Error>(self, v: f64) -> Result<Quantity, E> {
+                self.visit_str(&v.to_string())
+            }
+
+            fn visit_str<E: de::Error>(self, v: &str) -> Result<Quantity, E> {
+                if v.trim_start().starts_with('-') {
+                    return Err(E::custom("quantities can't be negative"));
+                }
+                v.parse().map_err(E::custom)
+            }
+        }
+
+        deserializer.deserialize_any(QuantityVisitor)
+    }
+}
+
+impl Serialize for Quantity {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match self.unit {
+            Some(_) => serializer.collect_str(self),
+            None => serialize_amount(&self.amount, serializer),
+        }
+    }
+}
+
+// Whole amounts stay integers in the JSON, anything else becomes a float
+fn serialize_amount<S: Serializer>(amount: &Decimal, serializer: S) -> Result<S::Ok, S::Error> {
+    match amount.fract().is_zero().then(|| amount.to_u128()).flatten() {
+        Some(amount) => serializer.serialize_u128(amount),
+        None => serializer.serialize_f64(amount.to_f64().unwrap_or_default()),
+    }
+}
+
 #[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
 struct RecipePantry {
-    recipe: HashMap<String, usize>,
-    pantry: HashMap<String, usize>,
+    recipe: HashMap<String, Quantity>,
+    pantry: HashMap<String, Quantity>,
 }
 
-#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+impl RecipePantry {
+    // How many whole cookies the pantry has enough ingredients for
+    fn max_cookies(&self) -> Result<Decimal, StatusCode> {
+        let mut cookies = None;
+        for (ingredient, needed) in &self.recipe {
+            if needed.amount.is_zero() {
+                continue;
+            }
+            let available = match self.pantry.get(ingredient) {
+                Some(available) => needed
+                    .convert(available.amount, available.unit, ingredient)
+                    .ok_or_else(|| {
+                        tracing::error!("can't bake {needed} of {ingredient} from {available}");
+                        StatusCode::BAD_REQUEST
+                    })?,
+                None => Decimal::ZERO,
+            };
+            let count = available
+                .checked_div(needed.amount)
+                .ok_or_else(|| {
+                    tracing::error!("the number of cookies from {ingredient} doesn't fit");
+                    StatusCode::BAD_REQUEST
+                })?
+                .floor();
+            cookies = Some(cookies.map_or(count, |c: Decimal| c.min(count)));
+        }
+        // a recipe that needs nothing at all never runs out, answered with
+        // the usize::MAX it always was
+        Ok(cookies.unwrap_or(if self.recipe.is_empty() {
+            Decimal::ZERO
+        } else {
+            Decimal::from(usize::MAX)
+        }))
+    }
+
+    // Take the ingredients of `cookies` cookies out of the pantry
+    fn bake(&mut self, cookies: Decimal) -> Result<(), StatusCode> {
+        for (ingredient, needed) in &self.recipe {
+            if needed.amount.is_zero() || cookies.is_zero() {
+                continue;
+            }
+            if let Some(available) = self.pantry.get_mut(ingredient) {
+                let used = needed
+                    .amount
+                    .checked_mul(cookies)
+                    .and_then(|used| available.convert(used, needed.unit, ingredient))
+                    .ok_or_else(|| {
+                        tracing::error!("the amount of {ingredient} used doesn't fit");
+                        StatusCode::BAD_REQUEST
+                    })?;
+                // conversions can round a hair over what's there
+                available.amount = (available.amount - used.min(available.amount)).normalize();
+            }
+        }
+        Ok(())
+    }
+}
+
+#[derive(Default, Debug, Clone, PartialEq, Serialize)]
 struct CookieResult {
-    cookies: usize,
-    pantry: HashMap<String, usize>,
+    #[serde(serialize_with = "serialize_amount")]
+    cookies: Decimal,
+    pantry: HashMap<String, Quantity>,
 }
 
 async fn secret_cookie(
+    State(cookie_codec): State<RecipeCookieCodec>,
     TypedHeader(cookie): TypedHeader<Cookie>,
-) -> Result<Json<CookieResult>, StatusCode> {
+) -> Result<impl IntoResponse, StatusCode> {
     let recipe = cookie.get("recipe").ok_or(StatusCode::BAD_REQUEST)?;
 
-    let de = general_purpose::STANDARD.decode(recipe).map_err(|e| {
-        eprintln!("ERR: error while decoding recipe from base64 {e}");
-        StatusCode::BAD_REQUEST
-    })?;
+    let de = cookie_codec.decode(recipe)?;
 
     let mut recipe_pantry: RecipePantry = serde_json::from_slice(&de).map_err(|e| {
         eprintln!("ERR: error while deserialize from json {e}");
         StatusCode::BAD_REQUEST
     })?;
 
-    let cookies_count = recipe_pantry
-        .recipe
-        .iter()
-        .map(|(recipe_key, &pantry_needed)| {
-            if pantry_needed == 0 {
-                usize::MAX
-            } else {
-                recipe_pantry
-                    .pantry
-                    .get(recipe_key)
-                    .map(|&pantry_available| pantry_available / pantry_needed)
-                    .unwrap_or_default()
-            }
-        })
-        .min()
-        .unwrap_or_default();
+    let cookies_count = recipe_pantry.max_cookies()?;
+    recipe_pantry.bake(cookies_count)?;
 
-    for (recipe_key, &pantry_needed) in &recipe_pantry.recipe {
-        if let Some(p) = recipe_pantry.pantry.get_mut(recipe_key) {
-            *p -= cookies_count * pantry_needed;
-        }
-    }
+    // hand the remaining pantry back so the next bake starts from it
+    let updated_cookie = serde_json::to_vec(&recipe_pantry).map_err(|e| {
+        eprintln!("ERR: error while serialize to json {e}");
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+    let set_cookie = format!(
+        "recipe={}; Path=/7; HttpOnly; SameSite=Strict",
+        cookie_codec.encode(&updated_cookie)?
+    );
 
     let result = CookieResult {
         cookies: cookies_count,
         pantry: recipe_pantry.pantry,
     };
-    Ok(Json(result))
+    Ok(([(header::SET_COOKIE, set_cookie)], Json(result)))
+}
+
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+struct PlanRecipe {
+    name: String,
+    recipe: HashMap<String, Quantity>,
+    #[serde(default = "default_recipe_value")]
+    value: usize,
+}
+
+fn default_recipe_value() -> usize {
+    1
+}
+
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+struct PlanRequest {
+    recipes: Vec<PlanRecipe>,
+    pantry: HashMap<String, Quantity>,
+    // how many cookies of each recipe we'd like to be able to bake
+    #[serde(default)]
+    target: HashMap<String, usize>,
+}
+
+#[derive(Default, Debug, Clone, PartialEq, Serialize)]
+struct PlanResult {
+    value: usize,
+    // false when the search gave up before proving the plan is the best one
+    optimal: bool,
+    cookies: HashMap<String, usize>,
+    pantry: HashMap<String, Quantity>,
+    #[serde(skip_serializing_if = "HashMap::is_empty")]
+    shopping_list: HashMap<String, Quantity>,
+}
+
+/// Branch and bound over how many cookies of each recipe to bake.
+///
+/// Recipes are tried in order, largest count first, and a branch is cut when
+/// even a fractional relaxation of what's left can't beat the best plan.
+struct PlanSearch {
+    // needs[recipe][ingredient]
+    needs: Vec<Vec<usize>>,
+    values: Vec<usize>,
+    counts: Vec<usize>,
+    best_counts: Vec<usize>,
+    best_value: usize,
+    nodes: usize,
+}
+
+impl PlanSearch {
+    fn new(needs: Vec<Vec<usize>>, values: Vec<usize>) -> Self {
+        let recipes = values.len();
+        Self {
+            needs,
+            values,
+            counts: vec![0; recipes],
+            best_counts: vec![0; recipes],
+            best_value: 0,
+            nodes: 0,
+        }
+    }
+
+    fn max_count(&self, recipe: usize, remaining: &[usize]) -> usize {
+        self.needs[recipe]
+            .iter()
+            .zip(remaining)
+            .filter(|(&need, _)| need > 0)
+            .map(|(&need, &available)| available / need)
+            .min()
+            .unwrap_or_default()
+    }
+
+    // Upper bound on the value the recipes from `from` on can still add
+    fn bound(&self, from: usize, remaining: &[usize]) -> f64 {
+        let caps: Vec<usize> = (from..self.values.len())
+            .map(|recipe| self.max_count(recipe, remaining))
+            .collect();
+        let mut bound: f64 = caps
+            .iter()
+            .zip(&self.values[from..])
+            .map(|(&cap, &value)| cap as f64 * value as f64)
+            .sum();
+
+        // every ingredient on its own is a fractional knapsack
+        for (ingredient, &available) in remaining.iter().enumerate() {
+            let mut items: Vec<(f64, f64, f64)> = (from..self.values.len())
+                .map(|recipe| {
+                    (
+                        self.values[recipe] as f64,
+                        self.needs[recipe][ingredient] as f64,
+                        caps[recipe - from] as f64,
+                    )
+                })
+                .filter(|&(value, _, cap)| value > 0.0 && cap > 0.0)
+                .collect();
+            items.sort_by(|a, b| (b.0 / b.1).total_cmp(&(a.0 / a.1)));
+
+            let mut left = available as f64;
+            let mut ingredient_bound = 0.0;
+            for (value, need, cap) in items {
+                let count = if need == 0.0 {
+                    cap
+                } else {
+                    cap.min(left / need)
+                };
+                ingredient_bound += count * value;
+                left -= count * need;
+            }
+            bound = bound.min(ingredient_bound);
+        }
+        bound
+    }
+
+    fn search(&mut self, recipe: usize, remaining: &mut [usize], value: usize) {
+        self.nodes += 1;
+        if self.nodes > MAX_PLAN_NODES {
+            return;
+        }
+        if value > self.best_value {
+            self.best_value = value;
+            self.best_counts.clone_from(&self.counts);
+        }
+        if recipe == self.values.len()
+            || value as f64 + self.bound(recipe, remaining) <= self.best_value as f64
+        {
+            return;
+        }
+
+        for count in (0..=self.max_count(recipe, remaining)).rev() {
+            // a huge pantry gives a huge range of counts, stop walking it
+            // once the budget is spent
+            if self.nodes > MAX_PLAN_NODES {
+                break;
+            }
+            for (available, &need) in remaining.iter_mut().zip(&self.needs[recipe]) {
+                *available -= need * count;
+            }
+            self.counts[recipe] = count;
+            self.search(
+                recipe + 1,
+                remaining,
+                value.saturating_add(self.values[recipe].saturating_mul(count)),
+            );
+            for (available, &need) in remaining.iter_mut().zip(&self.needs[recipe]) {
+                *available += need * count;
+            }
+        }
+        self.counts[recipe] = 0;
+    }
+}
+
+async fn plan_cookies(Json(plan): Json<PlanRequest>) -> Result<Json<PlanResult>, StatusCode> {
+    tracing::info!("plan cookies called with {} recipes", plan.recipes.len());
+    if plan.recipes.len() > MAX_PLAN_RECIPES {
+        tracing::error!("too many recipes to plan");
+        return Err(StatusCode::PAYLOAD_TOO_LARGE);
+    }
+    if let Some(r) = plan
+        .recipes
+        .iter()
+        .find(|r| r.recipe.values().all(|need| need.amount.is_zero()))
+    {
+        tracing::error!("recipe {} doesn't need any ingredient", r.name);
+        return Err(StatusCode::BAD_REQUEST);
+    }
+
+    if !plan.recipes.iter().map(|r| &r.name).all_unique() {
+        tracing::error!("recipe names in the plan must be unique");
+        return Err(StatusCode::BAD_REQUEST);
+    }
+
+    // every ingredient is planned in the unit of the first recipe using it,
+    // the same way baking converts the pantry into the recipe's unit
+    let ingredients: Vec<(&String, &Quantity)> = plan
+        .recipes
+        .iter()
+        .flat_map(|r| r.recipe.iter())
+        .unique_by(|(ingredient, _)| *ingredient)
+        .collect();
+    if ingredients.len() > MAX_PLAN_INGREDIENTS {
+        tracing::error!("too many ingredients to plan");
+        return Err(StatusCode::PAYLOAD_TOO_LARGE);
+    }
+    let in_reference = |ingredient: &String, reference: &Quantity, quantity: Option<&Quantity>| {
+        let Some(quantity) = quantity else {
+            return Ok(Decimal::ZERO);
+        };
+        reference
+            .convert(quantity.amount, quantity.unit, ingredient)
+            .ok_or_else(|| {
+                tracing::error!("can't plan {quantity} of {ingredient} next to {reference}");
+                StatusCode::BAD_REQUEST
+            })
+    };
+    let needs: Vec<Vec<Decimal>> = plan
+        .recipes
+        .iter()
+        .map(|r| {
+            ingredients
+                .iter()
+                .map(|&(i, reference)| in_reference(i, reference, r.recipe.get(i)))
+                .collect::<Result<_, _>>()
+        })
+        .collect::<Result<_, _>>()?;
+    let available: Vec<Decimal> = ingredients
+        .iter()
+        .map(|&(i, reference)| in_reference(i, reference, plan.pantry.get(i)))
+        .collect::<Result<_, _>>()?;
+
+    // the search only knows whole numbers, so every ingredient is counted in
+    // steps small enough for the most precise amount a recipe needs
+    let mut whole_needs = vec![vec![0; ingredients.len()]; plan.recipes.len()];
+    let mut remaining = vec![0; ingredients.len()];
+    for (ingredient, &(name, _)) in ingredients.iter().enumerate() {
+        let scale = needs
+            .iter()
+            .map(|need| need[ingredient].normalize().scale())
+            .max()
+            .unwrap_or_default();
+        let step = Decimal::from_i128_with_scale(10i128.pow(scale), 0);
+        let whole = |amount: Decimal| {
+            amount
+                .checked_mul(step)
+                .and_then(|amount| amount.floor().to_usize())
+                .ok_or_else(|| {
+                    tracing::error!("the amounts of {name} are too big or too precise to plan");
+                    StatusCode::BAD_REQUEST
+                })
+        };
+        for (recipe, need) in needs.iter().enumerate() {
+            whole_needs[recipe][ingredient] = whole(need[ingredient])?;
+        }
+        remaining[ingredient] = whole(available[ingredient])?;
+    }
+
+    // the search can take a while, keep it off the async workers
+    let values = plan.recipes.iter().map(|r| r.value).collect();
+    let search = tokio::task::spawn_blocking(move || {
+        let mut search = PlanSearch::new(whole_needs, values);
+        search.search(0, &mut remaining, 0);
+        search
+    })
+    .await
+    .map_err(|e| {
+        tracing::error!("error running the plan search {e}");
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+
+    // take the plan out of the pantry exactly like /7/bake would
+    let mut pantry = plan.pantry.clone();
+    for (r, &count) in plan.recipes.iter().zip(&search.best_counts) {
+        let mut recipe_pantry = RecipePantry {
+            recipe: r.recipe.clone(),
+            pantry,
+        };
+        recipe_pantry.bake(Decimal::from(count))?;
+        pantry = recipe_pantry.pantry;
+    }
+
+    let mut required = vec![Decimal::ZERO; ingredients.len()];
+    for (name, &count) in &plan.target {
+        let recipe = plan
+            .recipes
+            .iter()
+            .position(|r| &r.name == name)
+            .ok_or_else(|| {
+                tracing::error!("target recipe {name} isn't in the plan");
+                StatusCode::BAD_REQUEST
+            })?;
+        for (total, &need) in required.iter_mut().zip(&needs[recipe]) {
+            *total = need
+                .checked_mul(Decimal::from(count))
+                .and_then(|n| total.checked_add(n))
+                .ok_or_else(|| {
+                    tracing::error!("target for {name} is too big");
+                    StatusCode::BAD_REQUEST
+                })?;
+        }
+    }
+    let shopping_list = ingredients
+        .iter()
+        .zip(required.into_iter().zip(available))
+        .filter(|(_, (total, available))| total > available)
+        .map(|(&(ingredient, reference), (total, available))| {
+            let missing = Quantity {
+                amount: (total - available).normalize(),
+                unit: reference.unit,
+            };
+            (ingredient.clone(), missing)
+        })
+        .collect();
+
+    Ok(Json(PlanResult {
+        value: search.best_value,
+        optimal: search.nodes <= MAX_PLAN_NODES,
+        cookies: plan
+            .recipes
+            .iter()
+            .map(|r| r.name.clone())
+            .zip(search.best_counts)
+            .collect(),
+        pantry,
+        shopping_list,
+    }))
 }
@@ -1,53 +1,673 @@
-use std::error::Error;
+use std::{
+    collections::HashMap,
+    fmt,
+    num::NonZeroUsize,
+    path::Path as FsPath,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
 
-use axum::{extract::Path, http::StatusCode, routing::get};
-use serde::Deserialize;
+use async_trait::async_trait;
+use axum::{
+    extract::{Path, Query, State},
+    http::StatusCode,
+    routing::{get, post},
+    Json,
+};
+use chrono::{DateTime, Utc};
+use futures::{stream, StreamExt};
+use lru::LruCache;
+use serde::{Deserialize, Serialize};
 
-pub fn router() -> axum::Router {
+use crate::clock::{SharedClock, SystemClock};
+
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+const MAX_RETRIES: u32 = 3;
+const RETRY_BACKOFF: Duration = Duration::from_millis(200);
+const CACHE_SIZE: usize = 1024;
+const MAX_BATCH_SIZE: usize = 100;
+const MAX_CONCURRENT_LOOKUPS: usize = 8;
+
+// The challenge drops pokemons from 10 meters with this gravity
+const DEFAULT_GRAVITY: f64 = 9.825;
+const DEFAULT_DROP_HEIGHT: f64 = 10.0;
+const SEA_LEVEL_AIR_DENSITY: f64 = 1.225;
+const DRAG_TIME_STEP: f64 = 0.001;
+const MAX_DRAG_STEPS: usize = 1_000_000;
+
+const FEET_PER_METER: f64 = 3.280_839_895;
+const FOOT_POUNDS_PER_JOULE: f64 = 0.737_562_149;
+const LB_FT_PER_KG_M: f64 = 7.233_013_851;
+
+pub type SharedPokedex = Arc<dyn PokedexClient>;
+
+pub fn router(pokedex: SharedPokedex) -> axum::Router {
     axum::Router::new()
         .route("/8/weight/:pokedex", get(poke_weight))
+        .route("/8/weights", post(poke_weights))
         .route("/8/drop/:pokedex", get(poke_drop))
+        .route("/8/drop/:pokedex/velocity", get(poke_drop_velocity))
+        .route("/8/drop/:pokedex/energy", get(poke_drop_energy))
+        .route("/8/drop/:pokedex/time", get(poke_drop_time))
         .route("/8/health", get(|| async { StatusCode::OK }))
+        .with_state(pokedex)
 }
 
-#[derive(Deserialize, Debug, Clone)]
-struct PokeWeight {
-    weight: u32,
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct Pokemon {
+    pub id: u32,
+    pub name: String,
+    // in hectograms, like the PokéAPI returns it
+    pub weight: u32,
 }
 
-impl PokeWeight {
+impl Pokemon {
     // Extract the weight from the pokemone in kg
     fn extract_weight_kg(&self) -> f64 {
         self.weight as f64 / 10.0
     }
 }
 
-async fn fetch_poke(poke_id: u32) -> Result<PokeWeight, Box<dyn Error>> {
-    Ok(
-        reqwest::get(format!("https://pokeapi.co/api/v2/pokemon/{poke_id}"))
-            .await?
-            .json::<PokeWeight>()
-            .await?,
-    )
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PokedexError {
+    NotFound,
+    Upstream(String),
+}
+
+impl fmt::Display for PokedexError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PokedexError::NotFound => f.write_str("pokemon not found"),
+            PokedexError::Upstream(e) => write!(f, "upstream failure: {e}"),
+        }
+    }
+}
+
+impl PokedexError {
+    fn kind(&self) -> &'static str {
+        match self {
+            PokedexError::NotFound => "not_found",
+            PokedexError::Upstream(_) => "upstream_failure",
+        }
+    }
+}
+
+impl From<PokedexError> for StatusCode {
+    fn from(e: PokedexError) -> Self {
+        match e {
+            PokedexError::NotFound => StatusCode::NOT_FOUND,
+            PokedexError::Upstream(_) => StatusCode::BAD_GATEWAY,
+        }
+    }
+}
+
+/// Looks pokemons up by their pokedex id or name.
+#[async_trait]
+pub trait PokedexClient: Send + Sync {
+    async fn pokemon(&self, id_or_name: &str) -> Result<Pokemon, PokedexError>;
+}
+
+/// Talks to the PokéAPI over one pooled client, retrying transient failures.
+pub struct HttpPokedexClient {
+    client: reqwest::Client,
+    base_url: String,
 }
 
-async fn poke_weight(Path(pokedex): Path<u32>) -> Result<String, StatusCode> {
-    let poke_weight = fetch_poke(pokedex).await.map_err(|e| {
+impl HttpPokedexClient {
+    pub fn new(base_url: &str) -> std::io::Result<Self> {
+        let client = reqwest::Client::builder()
+            .timeout(REQUEST_TIMEOUT)
+            .connect_timeout(CONNECT_TIMEOUT)
+            .build()
+            .map_err(std::io::Error::other)?;
+        Ok(Self {
+            client,
+            base_url: base_url.trim_end_matches('/').to_string(),
+        })
+    }
+
+    async fn fetch(&self, id_or_name: &str) -> Result<Pokemon, FetchFailure> {
+        // the name ends up in the upstream path, so nothing that could
+        // change which path that is gets through
+        if id_or_name.is_empty()
+            || !id_or_name
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '-')
+        {
+            return Err(FetchFailure::permanent(PokedexError::NotFound));
+        }
+        let mut url = reqwest::Url::parse(&self.base_url)
+            .map_err(|e| FetchFailure::permanent(PokedexError::Upstream(e.to_string())))?;
+        url.path_segments_mut()
+            .map_err(|_| {
+                FetchFailure::permanent(PokedexError::Upstream(format!(
+                    "{} can't have a path",
+                    self.base_url
+                )))
+            })?
+            .pop_if_empty()
+            .push("pokemon")
+            .push(id_or_name);
+
+        let response = self
+            .client
+            .get(url)
+            .send()
+            .await
+            .map_err(FetchFailure::from_reqwest)?;
+        let status = response.status();
+        if status == reqwest::StatusCode::NOT_FOUND {
+            return Err(FetchFailure::permanent(PokedexError::NotFound));
+        }
+        if !status.is_success() {
+            return Err(FetchFailure {
+                error: PokedexError::Upstream(format!("upstream answered {status}")),
+                transient: status.is_server_error()
+                    || status == reqwest::StatusCode::TOO_MANY_REQUESTS,
+            });
+        }
+        response
+            .json::<Pokemon>()
+            .await
+            .map_err(FetchFailure::from_reqwest)
+    }
+}
+
+/// A failed fetch and whether trying again could help, only when the
+/// PokéAPI couldn't be reached or is struggling.
+struct FetchFailure {
+    error: PokedexError,
+    transient: bool,
+}
+
+impl FetchFailure {
+    fn permanent(error: PokedexError) -> Self {
+        Self {
+            error,
+            transient: false,
+        }
+    }
+
+    fn from_reqwest(e: reqwest::Error) -> Self {
+        Self {
+            transient: e.is_connect() || e.is_timeout(),
+            error: PokedexError::Upstream(e.to_string()),
+        }
+    }
+}
+
+#[async_trait]
+impl PokedexClient for HttpPokedexClient {
+    async fn pokemon(&self, id_or_name: &str) -> Result<Pokemon, PokedexError> {
+        let mut attempt = 0;
+        loop {
+            match self.fetch(id_or_name).await {
+                Ok(pokemon) => return Ok(pokemon),
+                Err(failure) if failure.transient && attempt < MAX_RETRIES => {
+                    tracing::warn!(
+                        "error while fetch poke {id_or_name}, retrying: {}",
+                        failure.error
+                    );
+                    tokio::time::sleep(RETRY_BACKOFF * 2u32.pow(attempt)).await;
+                    attempt += 1;
+                }
+                Err(failure) => return Err(failure.error),
+            }
+        }
+    }
+}
+
+/// Serves pokemons from a directory of PokéAPI style JSON files, for running offline.
+pub struct FixturePokedexClient {
+    pokemons: HashMap<String, Pokemon>,
+}
+
+impl FixturePokedexClient {
+    pub fn from_dir(dir: &FsPath) -> std::io::Result<Self> {
+        let mut pokemons = HashMap::new();
+        for entry in std::fs::read_dir(dir)? {
+            let path = entry?.path();
+            if path.extension().is_some_and(|ext| ext == "json") {
+                let pokemon: Pokemon = serde_json::from_slice(&std::fs::read(&path)?)?;
+                pokemons.insert(pokemon.id.to_string(), pokemon.clone());
+                pokemons.insert(pokemon.name.to_lowercase(), pokemon);
+            }
+        }
+        Ok(Self { pokemons })
+    }
+}
+
+#[async_trait]
+impl PokedexClient for FixturePokedexClient {
+    async fn pokemon(&self, id_or_name: &str) -> Result<Pokemon, PokedexError> {
+        self.pokemons
+            .get(&id_or_name.to_lowercase())
+            .cloned()
+            .ok_or(PokedexError::NotFound)
+    }
+}
+
+/// Keeps recent lookups of another client around for `ttl`.
+pub struct CachedPokedexClient<C> {
+    inner: C,
+    ttl: chrono::Duration,
+    clock: SharedClock,
+    cache: Mutex<LruCache<String, (DateTime<Utc>, Pokemon)>>,
+}
+
+impl<C: PokedexClient> CachedPokedexClient<C> {
+    pub fn new(inner: C, ttl: Duration) -> Self {
+        Self {
+            inner,
+            ttl: chrono::Duration::from_std(ttl).unwrap_or(chrono::Duration::MAX),
+            clock: Arc::new(SystemClock),
+            cache: Mutex::new(LruCache::new(
+                NonZeroUsize::new(CACHE_SIZE).expect("cache size isn't zero"),
+            )),
+        }
+    }
+
+    pub fn with_clock(mut self, clock: SharedClock) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    fn cached(&self, key: &str) -> Option<Pokemon> {
+        let mut cache = self.cache.lock().ok()?;
+        match cache.get(key) {
+            Some((saved_at, pokemon)) if self.clock.now() - *saved_at < self.ttl => {
+                Some(pokemon.clone())
+            }
+            Some(_) => {
+                cache.pop(key);
+                None
+            }
+            None => None,
+        }
+    }
+}
+
+#[async_trait]
+impl<C: PokedexClient> PokedexClient for CachedPokedexClient<C> {
+    async fn pokemon(&self, id_or_name: &str) -> Result<Pokemon, PokedexError> {
+        let key = id_or_name.to_lowercase();
+        if let Some(pokemon) = self.cached(&key) {
+            return Ok(pokemon);
+        }
+        let pokemon = self.inner.pokemon(&key).await?;
+        if let Ok(mut cache) = self.cache.lock() {
+            cache.put(key, (self.clock.now(), pokemon.clone()));
+        }
+        Ok(pokemon)
+    }
+}
+
+/// Picks the fixture backend when a directory is given, else the PokéAPI, behind the cache.
+pub fn pokedex_client(
+    base_url: &str,
+    fixtures: Option<&FsPath>,
+    cache_ttl: Duration,
+    clock: SharedClock,
+) -> std::io::Result<SharedPokedex> {
+    Ok(match fixtures {
+        Some(dir) => Arc::new(
+            CachedPokedexClient::new(FixturePokedexClient::from_dir(dir)?, cache_ttl)
+                .with_clock(clock),
+        ),
+        None => Arc::new(
+            CachedPokedexClient::new(HttpPokedexClient::new(base_url)?, cache_ttl)
+                .with_clock(clock),
+        ),
+    })
+}
+
+async fn fetch_poke(pokedex: &SharedPokedex, poke_id: u32) -> Result<Pokemon, StatusCode> {
+    pokedex.pokemon(&poke_id.to_string()).await.map_err(|e| {
         eprintln!("ERR: error while fetch poke {e}");
-        StatusCode::INTERNAL_SERVER_ERROR
-    })?;
+        e.into()
+    })
+}
+
+async fn poke_weight(
+    State(pokedex): State<SharedPokedex>,
+    Path(pokedex_id): Path<u32>,
+) -> Result<String, StatusCode> {
+    let poke_weight = fetch_poke(&pokedex, pokedex_id).await?;
 
     Ok(format!("{}", poke_weight.extract_weight_kg()))
 }
 
-async fn poke_drop(Path(pokedex): Path<u32>) -> Result<String, StatusCode> {
-    let poke_weight = fetch_poke(pokedex).await.map_err(|e| {
-        eprintln!("ERR: error while fetch poke {e}");
-        StatusCode::INTERNAL_SERVER_ERROR
-    })?;
+/// A pokemon asked for by its pokedex id or its name.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+#[serde(untagged)]
+enum PokemonRef {
+    Id(u32),
+    Name(String),
+}
+
+impl fmt::Display for PokemonRef {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PokemonRef::Id(id) => write!(f, "{id}"),
+            PokemonRef::Name(name) => f.write_str(name),
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+struct PokemonWeight {
+    id: u32,
+    name: String,
+    weight: f64,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+struct LookupError {
+    kind: String,
+    message: String,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+struct WeightLookup {
+    query: PokemonRef,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pokemon: Option<PokemonWeight>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<LookupError>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+struct WeightsResult {
+    results: Vec<WeightLookup>,
+    total: f64,
+    heaviest: Option<PokemonWeight>,
+    lightest: Option<PokemonWeight>,
+}
+
+async fn poke_weights(
+    State(pokedex): State<SharedPokedex>,
+    Json(queries): Json<Vec<PokemonRef>>,
+) -> Result<Json<WeightsResult>, StatusCode> {
+    tracing::info!("poke weights called with {} pokemons", queries.len());
+    if queries.len() > MAX_BATCH_SIZE {
+        tracing::error!("too many pokemons in one batch");
+        return Err(StatusCode::PAYLOAD_TOO_LARGE);
+    }
+
+    // `buffered` keeps the results in the order they were asked for
+    let results: Vec<WeightLookup> = stream::iter(queries)
+        .map(|query| {
+            let pokedex = pokedex.clone();
+            async move {
+                match pokedex.pokemon(&query.to_string()).await {
+                    Ok(pokemon) => WeightLookup {
+                        pokemon: Some(PokemonWeight {
+                            weight: pokemon.extract_weight_kg(),
+                            id: pokemon.id,
+                            name: pokemon.name,
+                        }),
+                        error: None,
+                        query,
+                    },
+                    Err(e) => {
+                        eprintln!("ERR: error while fetch poke {query}: {e}");
+                        WeightLookup {
+                            pokemon: None,
+                            error: Some(LookupError {
+                                kind: e.kind().to_string(),
+                                message: e.to_string(),
+                            }),
+                            query,
+                        }
+                    }
+                }
+            }
+        })
+        .buffered(MAX_CONCURRENT_LOOKUPS)
+        .collect()
+        .await;
+
+    let found = || results.iter().filter_map(|r| r.pokemon.as_ref());
+    Ok(Json(WeightsResult {
+        total: found().map(|p| p.weight).sum(),
+        heaviest: found()
+            .reduce(|a, b| if b.weight > a.weight { b } else { a })
+            .cloned(),
+        lightest: found()
+            .reduce(|a, b| if b.weight < a.weight { b } else { a })
+            .cloned(),
+        results,
+    }))
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+enum Planet {
+    Mercury,
+    Venus,
+    Earth,
+    Moon,
+    Mars,
+    Jupiter,
+    Saturn,
+    Uranus,
+    Neptune,
+}
+
+impl Planet {
+    // surface gravity in m/s²
+    fn gravity(&self) -> f64 {
+        match self {
+            Planet::Mercury => 3.7,
+            Planet::Venus => 8.87,
+            Planet::Earth => 9.80665,
+            Planet::Moon => 1.62,
+            Planet::Mars => 3.721,
+            Planet::Jupiter => 24.79,
+            Planet::Saturn => 10.44,
+            Planet::Uranus => 8.69,
+            Planet::Neptune => 11.15,
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Default, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+enum UnitSystem {
+    #[default]
+    Si,
+    Imperial,
+}
+
+fn default_height() -> f64 {
+    DEFAULT_DROP_HEIGHT
+}
+
+fn default_air_density() -> f64 {
+    SEA_LEVEL_AIR_DENSITY
+}
+
+/// How the pokemon is dropped, all in SI units.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+struct DropParams {
+    #[serde(default = "default_height")]
+    height: f64,
+    #[serde(default)]
+    gravity: Option<f64>,
+    #[serde(default)]
+    planet: Option<Planet>,
+    // quadratic drag is only applied when both of these are set
+    #[serde(default)]
+    drag_coefficient: f64,
+    #[serde(default)]
+    area: f64,
+    #[serde(default = "default_air_density")]
+    air_density: f64,
+    #[serde(default)]
+    units: UnitSystem,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Impact {
+    velocity: f64,
+    time: f64,
+}
+
+impl DropParams {
+    fn gravity(&self) -> f64 {
+        self.gravity
+            .or(self.planet.map(|planet| planet.gravity()))
+            .unwrap_or(DEFAULT_GRAVITY)
+    }
+
+    // Works out how fast and when a pokemon of `mass` kg hits the ground
+    fn impact(&self, mass: f64) -> Result<Impact, StatusCode> {
+        let (height, gravity) = (self.height, self.gravity());
+        let valid = [height, self.drag_coefficient, self.area, self.air_density]
+            .iter()
+            .all(|v| v.is_finite() && *v >= 0.0)
+            && gravity.is_finite()
+            && gravity > 0.0;
+        if !valid {
+            tracing::error!("invalid drop parameters {self:?}");
+            return Err(StatusCode::BAD_REQUEST);
+        }
+
+        let drag = 0.5 * self.air_density * self.drag_coefficient * self.area;
+        if drag == 0.0 {
+            return Ok(Impact {
+                velocity: (gravity * height * 2.0).sqrt(),
+                time: (2.0 * height / gravity).sqrt(),
+            });
+        }
+        if mass <= 0.0 {
+            tracing::error!("can't apply drag to a weightless pokemon");
+            return Err(StatusCode::UNPROCESSABLE_ENTITY);
+        }
+        fall_with_drag(height, gravity, drag / mass).ok_or_else(|| {
+            tracing::error!("the pokemon didn't land in time {self:?}");
+            StatusCode::UNPROCESSABLE_ENTITY
+        })
+    }
+}
+
+/// Integrates `dv/dt = g - k v²` with RK4 until the pokemon fell `height` meters.
+fn fall_with_drag(height: f64, gravity: f64, k: f64) -> Option<Impact> {
+    let acceleration = |v: f64| gravity - k * v * v;
+    let dt = DRAG_TIME_STEP;
+    let (mut time, mut fallen, mut velocity) = (0.0, 0.0, 0.0);
+    if height == 0.0 {
+        return Some(Impact { velocity, time });
+    }
+
+    for _ in 0..MAX_DRAG_STEPS {
+        let k1 = (velocity, acceleration(velocity));
+        let k2 = (
+            velocity + 0.5 * dt * k1.1,
+            acceleration(velocity + 0.5 * dt * k1.1),
+        );
+        let k3 = (
+            velocity + 0.5 * dt * k2.1,
+            acceleration(velocity + 0.5 * dt * k2.1),
+        );
+        let k4 = (velocity + dt * k3.1, acceleration(velocity + dt * k3.1));
+        let next_fallen = fallen + dt / 6.0 * (k1.0 + 2.0 * k2.0 + 2.0 * k3.0 + k4.0);
+        let next_velocity = velocity + dt / 6.0 * (k1.1 + 2.0 * k2.1 + 2.0 * k3.1 + k4.1);
+
+        if next_fallen >= height {
+            // interpolate inside the last step to where the ground is
+            let fraction = (height - fallen) / (next_fallen - fallen);
+            return Some(Impact {
+                velocity: velocity + fraction * (next_velocity - velocity),
+                time: time + fraction * dt,
+            });
+        }
+        (time, fallen, velocity) = (time + dt, next_fallen, next_velocity);
+    }
+    None
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+struct Measurement {
+    value: f64,
+    unit: String,
+}
+
+impl Measurement {
+    fn new(si_value: f64, si_unit: &str, imperial: (f64, &str), units: UnitSystem) -> Self {
+        let (value, unit) = match units {
+            UnitSystem::Si => (si_value, si_unit),
+            UnitSystem::Imperial => (si_value * imperial.0, imperial.1),
+        };
+        Self {
+            value,
+            unit: unit.to_string(),
+        }
+    }
+}
+
+async fn poke_drop(
+    State(pokedex): State<SharedPokedex>,
+    Path(pokedex_id): Path<u32>,
+    Query(params): Query<DropParams>,
+) -> Result<String, StatusCode> {
+    let poke_weight = fetch_poke(&pokedex, pokedex_id).await?;
+    let mass = poke_weight.extract_weight_kg();
+    let impact = params.impact(mass)?;
+
+    let momentum = mass * impact.velocity;
+    Ok(match params.units {
+        UnitSystem::Si => format!("{momentum}"),
+        UnitSystem::Imperial => format!("{}", momentum * LB_FT_PER_KG_M),
+    })
+}
+
+async fn poke_drop_velocity(
+    State(pokedex): State<SharedPokedex>,
+    Path(pokedex_id): Path<u32>,
+    Query(params): Query<DropParams>,
+) -> Result<Json<Measurement>, StatusCode> {
+    let poke_weight = fetch_poke(&pokedex, pokedex_id).await?;
+    let impact = params.impact(poke_weight.extract_weight_kg())?;
+
+    Ok(Json(Measurement::new(
+        impact.velocity,
+        "m/s",
+        (FEET_PER_METER, "ft/s"),
+        params.units,
+    )))
+}
+
+async fn poke_drop_energy(
+    State(pokedex): State<SharedPokedex>,
+    Path(pokedex_id): Path<u32>,
+    Query(params): Query<DropParams>,
+) -> Result<Json<Measurement>, StatusCode> {
+    let poke_weight = fetch_poke(&pokedex, pokedex_id).await?;
+    let mass = poke_weight.extract_weight_kg();
+    let impact = params.impact(mass)?;
+
+    Ok(Json(Measurement::new(
+        0.5 * mass * impact.velocity * impact.velocity,
+        "J",
+        (FOOT_POUNDS_PER_JOULE, "ft·lbf"),
+        params.units,
+    )))
+}
+
+async fn poke_drop_time(
+    State(pokedex): State<SharedPokedex>,
+    Path(pokedex_id): Path<u32>,
+    Query(params): Query<DropParams>,
+) -> Result<Json<Measurement>, StatusCode> {
+    let poke_weight = fetch_poke(&pokedex, pokedex_id).await?;
+    let impact = params.impact(poke_weight.extract_weight_kg())?;
 
-    Ok(format!(
-        "{}",
-        poke_weight.extract_weight_kg() * (9.825f64 * 10.0 * 2.0).sqrt()
-    ))
+    Ok(Json(Measurement::new(
+        impact.time,
+        "s",
+        (1.0, "s"),
+        params.units,
+    )))
 }
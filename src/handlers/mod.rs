@@ -1,5 +1,20 @@
+use std::{io, sync::Arc, time::Duration};
+
+use axum::http::StatusCode;
+use axum_extra::{
+    headers::{authorization::Bearer, Authorization},
+    TypedHeader,
+};
+use chrono::Utc;
+use sha2::{Digest, Sha256};
 use sqlx::{Pool, Postgres};
 
+use crate::{
+    clock::{ManualClock, SharedClock, SystemClock},
+    config::Config,
+};
+
+pub mod bulk;
 pub mod day0;
 pub mod day1;
 pub mod day11;
@@ -17,26 +32,80 @@ pub mod day5;
 pub mod day6;
 pub mod day7;
 pub mod day8;
+pub mod tenants;
 pub mod tiebreaker;
 
-pub fn router(pool: Pool<Postgres>, geocoding_api_key: String) -> axum::Router {
-    axum::Router::new()
+/// Lets a request through when it carries `expected` as its bearer token,
+/// with 403 when no token is configured and 401 when it's missing or wrong.
+pub(crate) fn check_bearer(
+    expected: Option<&str>,
+    given: Option<TypedHeader<Authorization<Bearer>>>,
+) -> Result<(), StatusCode> {
+    let Some(expected) = expected else {
+        tracing::error!("no bearer token is configured");
+        return Err(StatusCode::FORBIDDEN);
+    };
+    // comparing digests keeps the comparison time independent of the token
+    let given = given.map(|TypedHeader(auth)| Sha256::digest(auth.token()));
+    if given != Some(Sha256::digest(expected)) {
+        tracing::error!("missing or wrong bearer token");
+        return Err(StatusCode::UNAUTHORIZED);
+    }
+    Ok(())
+}
+
+/// Builds every day's routes, failing when the config can't make one of them.
+pub fn router(pool: Pool<Postgres>, config: &Config) -> io::Result<axum::Router> {
+    let clock: SharedClock = if config.test_mode {
+        Arc::new(ManualClock::new(Utc::now()))
+    } else {
+        Arc::new(SystemClock)
+    };
+    let packet_store = day12::packet_store(config.packet_store, &pool, &config.packet_store_dir)?;
+    if config.packet_sweep_interval_secs > 0 {
+        day12::spawn_packet_sweeper(
+            packet_store.clone(),
+            clock.clone(),
+            Duration::from_secs(config.packet_sweep_interval_secs),
+        );
+    }
+
+    let cookie_codec = day7::RecipeCookieCodec::new(config.cookie_format, &config.cookie_keys)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+    let pokedex = day8::pokedex_client(
+        &config.pokeapi_base_url,
+        config.pokedex_fixtures.as_deref(),
+        Duration::from_secs(config.pokedex_cache_ttl_secs),
+        clock.clone(),
+    )?;
+    let asset_store = day11::AssetStore::open(
+        "assets",
+        &config.asset_store_dir,
+        config.asset_upload_token.clone(),
+    )?
+    .with_clock(clock.clone());
+
+    Ok(axum::Router::new()
         .nest("/", day0::router())
         .nest("/", day1::router())
         .nest("/", day4::router())
         .nest("/", day5::router())
         .nest("/", day6::router())
-        .nest("/", day7::router())
-        .nest("/", day8::router())
-        .nest("/", day11::router())
-        .nest("/", day12::router())
+        .nest("/", day7::router(cookie_codec))
+        .nest("/", day8::router(pokedex))
+        .nest("/", day11::router(Arc::new(asset_store)))
+        .nest(
+            "/",
+            day12::router(packet_store, clock, config.admin_token.clone()),
+        )
         .nest("/", day13::router(pool.clone()))
         .nest("/", day14::router())
         .nest("/", day15::router())
-        .nest("/", day18::router(pool))
+        .nest("/", day18::router(pool.clone()))
+        .nest("/", tenants::router(pool, config.admin_token.clone()))
         .nest("/", day19::router())
         .nest("/", day20::router())
-        .nest("/", day21::router(geocoding_api_key))
+        .nest("/", day21::router(config.geocoding_api_key.clone()))
         .nest("/", day22::router())
-        .nest("/", tiebreaker::router())
+        .nest("/", tiebreaker::router()))
 }
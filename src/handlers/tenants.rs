@@ -0,0 +1,302 @@
+use std::ops::{Deref, DerefMut};
+
+use async_trait::async_trait;
+use axum::{
+    extract::{FromRequestParts, Path, State},
+    http::{request::Parts, StatusCode},
+    routing::{get, post},
+    Json, Router,
+};
+use axum_extra::{
+    headers::{authorization::Bearer, Authorization},
+    TypedHeader,
+};
+use serde::Serialize;
+use sqlx::{pool::PoolConnection, Connection, Executor, PgConnection, PgPool, Pool, Postgres};
+
+use super::{
+    check_bearer,
+    day13::{reset_schema, ResetScope, SQL_READER},
+};
+
+/// Picks the namespace a day 13 or day 18 request works in, requests
+/// without it keep using the shared tables.
+pub const TENANT_HEADER: &str = "x-tenant";
+
+const SCHEMA_PREFIX: &str = "tenant_";
+const MAX_TENANT_NAME_LEN: usize = 32;
+
+#[derive(Clone)]
+struct TenantsState {
+    pool: Pool<Postgres>,
+    admin_token: Option<String>,
+}
+
+/// Creating and deleting tenants needs `admin_token` as a bearer token.
+pub fn router(pool: Pool<Postgres>, admin_token: Option<String>) -> Router {
+    Router::new()
+        .route("/tenants", get(list_tenants))
+        .route("/tenants/:name", post(create_tenant).delete(delete_tenant))
+        .with_state(TenantsState { pool, admin_token })
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Tenant {
+    pub name: String,
+    pub schema: String,
+}
+
+impl Tenant {
+    /// Names end up in a schema name, so they are kept to lowercase ascii
+    /// letters, digits and underscores.
+    fn new(name: &str) -> Option<Self> {
+        let valid = (1..=MAX_TENANT_NAME_LEN).contains(&name.len())
+            && name.starts_with(|c: char| c.is_ascii_lowercase())
+            && name
+                .chars()
+                .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '_');
+        valid.then(|| Self {
+            name: name.to_string(),
+            schema: format!("{SCHEMA_PREFIX}{name}"),
+        })
+    }
+}
+
+/// The namespace picked with the `x-tenant` header, `None` for the shared
+/// tables. Only the header is checked here, a connection is taken with
+/// [`Namespace::connect`] once the rest of the request is known to be good.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Namespace(pub Option<Tenant>);
+
+#[async_trait]
+impl<S: Send + Sync> FromRequestParts<S> for Namespace {
+    type Rejection = StatusCode;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        match parts.headers.get(TENANT_HEADER) {
+            Some(name) => name
+                .to_str()
+                .ok()
+                .and_then(Tenant::new)
+                .map(|tenant| Self(Some(tenant)))
+                .ok_or(StatusCode::BAD_REQUEST),
+            None => Ok(Self(None)),
+        }
+    }
+}
+
+impl Namespace {
+    /// The role ad-hoc queries in this namespace run as, it can only read
+    /// the namespace's own tables.
+    pub fn reader(&self) -> &str {
+        match &self.0 {
+            // every tenant's schema has a role of the same name
+            Some(tenant) => &tenant.schema,
+            None => SQL_READER,
+        }
+    }
+
+    /// Takes a connection from the pool that works in this namespace, a 404
+    /// when the tenant hasn't been provisioned.
+    pub async fn connect(&self, pool: &PgPool) -> Result<TenantConnection, StatusCode> {
+        let mut connection = pool.acquire().await.map_err(|e| {
+            tracing::error!("error acquiring a connection {e}");
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?;
+        if let Some(tenant) = &self.0 {
+            // only the tenant's schema, so a table missing from it can't
+            // fall through to the shared one
+            sqlx::query_scalar!(
+                r#"
+                select set_config('search_path', quote_ident(nspname), false)
+                from pg_namespace
+                where nspname = $1
+                "#,
+                tenant.schema
+            )
+            .fetch_optional(&mut *connection)
+            .await
+            .map_err(|e| {
+                tracing::error!("error switching to tenant {} {e}", tenant.name);
+                StatusCode::INTERNAL_SERVER_ERROR
+            })?
+            .ok_or(StatusCode::NOT_FOUND)?;
+        }
+        Ok(TenantConnection {
+            connection: Some(connection),
+            scoped: self.0.is_some(),
+        })
+    }
+}
+
+/// A pooled connection whose `search_path` is the caller's namespace, so the
+/// unqualified `orders`, `gifts` and `regions` of the handlers are theirs.
+///
+/// The search path is a session setting, so it is put back before the
+/// connection returns to the pool where the other days pick it up.
+pub struct TenantConnection {
+    connection: Option<PoolConnection<Postgres>>,
+    scoped: bool,
+}
+
+impl Deref for TenantConnection {
+    type Target = PgConnection;
+
+    fn deref(&self) -> &Self::Target {
+        self.connection
+            .as_ref()
+            .expect("connection is only taken on drop")
+    }
+}
+
+impl DerefMut for TenantConnection {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        self.connection
+            .as_mut()
+            .expect("connection is only taken on drop")
+    }
+}
+
+impl Drop for TenantConnection {
+    fn drop(&mut self) {
+        let Some(mut connection) = self.connection.take() else {
+            return;
+        };
+        if !self.scoped {
+            return;
+        }
+        tokio::spawn(async move {
+            if let Err(e) = sqlx::query!("reset search_path")
+                .execute(&mut *connection)
+                .await
+            {
+                tracing::error!("error resetting the search path, closing the connection {e}");
+                let _ = connection.close().await;
+            }
+        });
+    }
+}
+
+async fn list_tenants(State(state): State<TenantsState>) -> Result<Json<Vec<Tenant>>, StatusCode> {
+    tracing::info!("list tenants called");
+    let schemas = sqlx::query_scalar!(
+        r#"
+        select nspname as "schema!"
+        from pg_namespace
+        where starts_with(nspname, $1)
+        order by nspname
+        "#,
+        SCHEMA_PREFIX
+    )
+    .fetch_all(&state.pool)
+    .await
+    .map_err(|e| {
+        tracing::error!("error listing tenants {e}");
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+    Ok(Json(
+        schemas
+            .into_iter()
+            .filter_map(|schema| Tenant::new(&schema[SCHEMA_PREFIX.len()..]))
+            .collect(),
+    ))
+}
+
+/// Creates the tenant's schema with empty day 13 and day 18 tables, and the
+/// role that reads them.
+async fn create_tenant(
+    State(state): State<TenantsState>,
+    authorization: Option<TypedHeader<Authorization<Bearer>>>,
+    Path(name): Path<String>,
+) -> Result<(StatusCode, Json<Tenant>), StatusCode> {
+    tracing::info!("create tenant called {name}");
+    check_bearer(state.admin_token.as_deref(), authorization)?;
+    let tenant = Tenant::new(&name).ok_or(StatusCode::BAD_REQUEST)?;
+    let mut connection = state.pool.acquire().await.map_err(|e| {
+        tracing::error!("error acquiring a connection {e}");
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+    let mut transaction = connection.begin().await.map_err(|e| {
+        tracing::error!("error starting transaction {e}");
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+
+    // the name was checked above, schemas can't be bound as parameters
+    sqlx::query(&format!("create schema \"{}\"", tenant.schema))
+        .execute(&mut *transaction)
+        .await
+        .map_err(|e| match e.as_database_error().and_then(|db| db.code()) {
+            // duplicate_schema
+            Some(code) if code == "42P06" => StatusCode::CONFLICT,
+            _ => {
+                tracing::error!("error creating schema {} {e}", tenant.schema);
+                StatusCode::INTERNAL_SERVER_ERROR
+            }
+        })?;
+    // roles are shared by every database of the cluster, so the role may be
+    // left from an earlier tenant of the same name
+    let reader = format!(
+        r#"
+        do $$
+        begin
+            create role "{schema}" nologin;
+        exception when duplicate_object or unique_violation then
+            null;
+        end
+        $$;
+        grant "{schema}" to current_user;
+        grant usage on schema "{schema}" to "{schema}";
+        alter default privileges in schema "{schema}" grant select on tables to "{schema}";
+        "#,
+        schema = tenant.schema
+    );
+    (&mut *transaction)
+        .execute(reader.as_str())
+        .await
+        .map_err(|e| {
+            tracing::error!("error creating the reader of {} {e}", tenant.schema);
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?;
+    // local to the transaction, the pooled connection is left as it was
+    sqlx::query_scalar!(
+        "select set_config('search_path', quote_ident($1), true)",
+        tenant.schema
+    )
+    .fetch_one(&mut *transaction)
+    .await
+    .map_err(|e| {
+        tracing::error!("error switching to schema {} {e}", tenant.schema);
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+    reset_schema(&mut transaction, ResetScope::All).await?;
+
+    transaction.commit().await.map_err(|e| {
+        tracing::error!("error committing transaction {e}");
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+    Ok((StatusCode::CREATED, Json(tenant)))
+}
+
+/// Drops the tenant's schema and everything in it. The role is kept without
+/// any privileges, other databases of the cluster may still use it.
+async fn delete_tenant(
+    State(state): State<TenantsState>,
+    authorization: Option<TypedHeader<Authorization<Bearer>>>,
+    Path(name): Path<String>,
+) -> Result<StatusCode, StatusCode> {
+    tracing::info!("delete tenant called {name}");
+    check_bearer(state.admin_token.as_deref(), authorization)?;
+    let tenant = Tenant::new(&name).ok_or(StatusCode::BAD_REQUEST)?;
+    sqlx::query(&format!("drop schema \"{}\" cascade", tenant.schema))
+        .execute(&state.pool)
+        .await
+        .map_err(|e| match e.as_database_error().and_then(|db| db.code()) {
+            // invalid_schema_name
+            Some(code) if code == "3F000" => StatusCode::NOT_FOUND,
+            _ => {
+                tracing::error!("error dropping schema {} {e}", tenant.schema);
+                StatusCode::INTERNAL_SERVER_ERROR
+            }
+        })?;
+    Ok(StatusCode::NO_CONTENT)
+}
@@ -5,6 +5,7 @@ use sqlx::PgPool;
 use startup::run;
 use tokio::signal;
 
+mod clock;
 mod config;
 mod handlers;
 mod startup;
@@ -10,8 +10,8 @@ use tokio::net::TcpListener;
 
 use crate::{config, handlers};
 
-fn app(pool: Pool<Postgres>, geocoding_api_key: String) -> Router {
-    handlers::router(pool, geocoding_api_key)
+fn app(pool: Pool<Postgres>, config: &config::Config) -> Result<Router, std::io::Error> {
+    handlers::router(pool, config)
 }
 
 type Server = Serve<
@@ -26,7 +26,7 @@ pub fn run(
 ) -> Result<Server, std::io::Error> {
     let server = axum::serve(
         listener,
-        app(db_pool, config.geocoding_api_key).into_make_service_with_connect_info::<SocketAddr>(),
+        app(db_pool, &config)?.into_make_service_with_connect_info::<SocketAddr>(),
     );
     Ok(server)
 }
@@ -0,0 +1,416 @@
+use std::{io::Cursor, path::Path, sync::Arc};
+
+use axum::{
+    body::Body,
+    http::{header, HeaderMap, Method, Request, StatusCode},
+    response::Response,
+    Router,
+};
+use cch23_challenge::handlers::day11::{router, AssetStore};
+use http_body_util::BodyExt;
+use image::{DynamicImage, GenericImageView, ImageBuffer, ImageOutputFormat, Rgba, RgbaImage};
+use serde_json::{json, Value};
+use tower::ServiceExt; // for `call`, `oneshot`, and `ready`
+
+const BOUNDARY: &str = "XMAS";
+const TOKEN: &str = "santa";
+
+fn multipart(fields: &[(&str, &str, &[u8])]) -> Vec<u8> {
+    let mut body = vec![];
+    for (name, content_type, data) in fields {
+        body.extend(format!(
+            "--{BOUNDARY}\r\nContent-Disposition: form-data; name=\"{name}\"; filename=\"{name}\"\r\nContent-Type: {content_type}\r\n\r\n"
+        ).as_bytes());
+        body.extend(*data);
+        body.extend(b"\r\n");
+    }
+    body.extend(format!("--{BOUNDARY}--\r\n").as_bytes());
+    body
+}
+
+fn app(store_dir: &Path) -> Router {
+    router(Arc::new(
+        AssetStore::open("assets", store_dir, Some(TOKEN.to_string())).unwrap(),
+    ))
+}
+
+async fn post(uri: &str, fields: &[(&str, &str, &[u8])]) -> Response {
+    let store_dir = tempfile::tempdir().unwrap();
+    app(store_dir.path())
+        .oneshot(
+            Request::builder()
+                .uri(uri)
+                .method(Method::POST)
+                .header(
+                    header::CONTENT_TYPE,
+                    format!("multipart/form-data; boundary={BOUNDARY}"),
+                )
+                .body(Body::from(multipart(fields)))
+                .unwrap(),
+        )
+        .await
+        .unwrap()
+}
+
+// three quarters red, one quarter blue
+fn red_and_blue_png() -> Vec<u8> {
+    let img = RgbaImage::from_fn(4, 4, |x, _| {
+        if x < 3 {
+            Rgba([200, 10, 10, 255])
+        } else {
+            Rgba([0, 0, 255, 255])
+        }
+    });
+    let mut png = vec![];
+    img.write_to(&mut Cursor::new(&mut png), ImageOutputFormat::Png)
+        .unwrap();
+    png
+}
+
+#[tokio::test]
+async fn day11_analyze_png() {
+    // Arrange
+    let png = red_and_blue_png();
+    let options = json!({
+        "clusters": 2,
+        "predicates": [
+            {"name": "red", "kind": "red_dominant"},
+            {"name": "reds", "kind": "hsv", "hue": [330, 30], "saturation": [0.5, 1]}
+        ]
+    })
+    .to_string();
+
+    // Act
+    let response = post(
+        "/11/analyze",
+        &[
+            ("image", "image/png", &png),
+            ("options", "application/json", options.as_bytes()),
+        ],
+    )
+    .await;
+
+    // Assert
+    assert_eq!(response.status(), StatusCode::OK);
+    let body = response.into_body().collect().await.unwrap().to_bytes();
+    let body: Value = serde_json::from_slice(&body).unwrap();
+    assert_eq!(
+        body["metadata"],
+        json!({"format": "png", "width": 4, "height": 4, "pixels": 16, "color_type": "Rgba8", "has_alpha": true})
+    );
+    assert_eq!(body["histogram"]["red"][200], json!(12));
+    assert_eq!(body["histogram"]["blue"][255], json!(4));
+    assert_eq!(
+        body["dominant_colors"],
+        json!([
+            {"hex": "#c80a0a", "rgb": [200, 10, 10], "share": 0.75},
+            {"hex": "#0000ff", "rgb": [0, 0, 255], "share": 0.25}
+        ])
+    );
+    assert_eq!(body["predicates"], json!({"red": 12, "reds": 12}));
+}
+
+#[tokio::test]
+async fn day11_transform_masks_red_pixels() {
+    // Arrange
+    let png = red_and_blue_png();
+    let pipeline = json!({
+        "operations": [
+            {"op": "crop", "x": 2, "y": 0, "width": 2, "height": 1},
+            {"op": "red_mask"},
+            {"op": "resize", "width": 4, "height": 4, "exact": true}
+        ],
+        "format": "png"
+    })
+    .to_string();
+    let bad_crop = json!({"operations": [{"op": "crop", "x": 3, "y": 0, "width": 2, "height": 1}]})
+        .to_string();
+
+    // Act
+    let response = post(
+        "/11/transform",
+        &[
+            ("image", "image/png", &png),
+            ("pipeline", "application/json", pipeline.as_bytes()),
+        ],
+    )
+    .await;
+    let bad_response = post(
+        "/11/transform",
+        &[
+            ("image", "image/png", &png),
+            ("pipeline", "application/json", bad_crop.as_bytes()),
+        ],
+    )
+    .await;
+
+    // Assert
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(response.headers()[header::CONTENT_TYPE], "image/png");
+    let body = response.into_body().collect().await.unwrap().to_bytes();
+    let img = image::load_from_memory(&body).unwrap().to_rgba8();
+    assert_eq!(img.dimensions(), (4, 4));
+    // the red half is painted green, the blue half is left alone
+    assert_eq!(img.get_pixel(0, 0), &Rgba([0, 255, 0, 255]));
+    assert_eq!(img.get_pixel(3, 3), &Rgba([0, 0, 255, 255]));
+    assert_eq!(bad_response.status(), StatusCode::BAD_REQUEST);
+}
+
+#[tokio::test]
+async fn day11_transform_encodes_16_bit_png_as_bmp() {
+    // Arrange
+    let img = ImageBuffer::from_pixel(2, 2, Rgba([65535u16, 0, 0, 65535]));
+    let mut png = vec![];
+    DynamicImage::ImageRgba16(img)
+        .write_to(&mut Cursor::new(&mut png), ImageOutputFormat::Png)
+        .unwrap();
+    let pipeline = json!({"operations": [], "format": "bmp"}).to_string();
+
+    // Act
+    let response = post(
+        "/11/transform",
+        &[
+            ("image", "image/png", &png),
+            ("pipeline", "application/json", pipeline.as_bytes()),
+        ],
+    )
+    .await;
+
+    // Assert
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(response.headers()[header::CONTENT_TYPE], "image/bmp");
+    let body = response.into_body().collect().await.unwrap().to_bytes();
+    let img = image::load_from_memory(&body).unwrap().to_rgba8();
+    assert_eq!(img.get_pixel(1, 1), &Rgba([255, 0, 0, 255]));
+}
+
+async fn send(app: &Router, request: Request<Body>) -> (StatusCode, HeaderMap, Vec<u8>) {
+    let response = app.clone().oneshot(request).await.unwrap();
+    let status = response.status();
+    let headers = response.headers().clone();
+    let body = response.into_body().collect().await.unwrap().to_bytes();
+    (status, headers, body.to_vec())
+}
+
+fn asset_request(method: Method, uri: &str) -> axum::http::request::Builder {
+    Request::builder().method(method).uri(uri)
+}
+
+#[tokio::test]
+async fn day11_static_asset_conditional_and_range() {
+    // Arrange
+    let store_dir = tempfile::tempdir().unwrap();
+    let app = app(store_dir.path());
+    let decoration = std::fs::read("assets/decoration.png").unwrap();
+
+    // Act
+    let (status, headers, body) = send(
+        &app,
+        asset_request(Method::GET, "/11/assets/decoration.png")
+            .body(Body::empty())
+            .unwrap(),
+    )
+    .await;
+    let etag = headers[header::ETAG].to_str().unwrap();
+    let (cached_status, _, _) = send(
+        &app,
+        asset_request(Method::GET, "/11/assets/decoration.png")
+            .header(header::IF_NONE_MATCH, etag)
+            .body(Body::empty())
+            .unwrap(),
+    )
+    .await;
+    let (range_status, range_headers, range_body) = send(
+        &app,
+        asset_request(Method::GET, "/11/assets/decoration.png")
+            .header(header::RANGE, "bytes=1-3")
+            .body(Body::empty())
+            .unwrap(),
+    )
+    .await;
+
+    // Assert
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(headers[header::CONTENT_TYPE], "image/png");
+    assert_eq!(body, decoration);
+    assert_eq!(cached_status, StatusCode::NOT_MODIFIED);
+    assert_eq!(range_status, StatusCode::PARTIAL_CONTENT);
+    assert_eq!(
+        range_headers[header::CONTENT_RANGE],
+        format!("bytes 1-3/{}", decoration.len())
+    );
+    assert_eq!(range_body, b"PNG");
+}
+
+#[tokio::test]
+async fn day11_upload_versions_and_delete() {
+    // Arrange
+    let store_dir = tempfile::tempdir().unwrap();
+    let app = app(store_dir.path());
+    let upload = |token: &str, body: Vec<u8>| {
+        asset_request(Method::PUT, "/11/assets/tree.png")
+            .header(header::AUTHORIZATION, format!("Bearer {token}"))
+            .body(Body::from(body))
+            .unwrap()
+    };
+    let png = red_and_blue_png();
+
+    // Act
+    let (unauthorized, _, _) = send(&app, upload("grinch", png.clone())).await;
+    let (first, _, _) = send(&app, upload(TOKEN, b"not yet a tree".to_vec())).await;
+    let (second, _, second_body) = send(&app, upload(TOKEN, png.clone())).await;
+    let (_, _, listing) = send(
+        &app,
+        asset_request(Method::GET, "/11/assets")
+            .body(Body::empty())
+            .unwrap(),
+    )
+    .await;
+    let (_, _, old) = send(
+        &app,
+        asset_request(Method::GET, "/11/assets/tree.png?version=1")
+            .body(Body::empty())
+            .unwrap(),
+    )
+    .await;
+    let (_, thumbnail_headers, thumbnail) = send(
+        &app,
+        asset_request(Method::GET, "/11/assets/tree.png?thumbnail=2")
+            .body(Body::empty())
+            .unwrap(),
+    )
+    .await;
+    let (deleted, _, _) = send(
+        &app,
+        asset_request(Method::DELETE, "/11/assets/tree.png")
+            .header(header::AUTHORIZATION, format!("Bearer {TOKEN}"))
+            .body(Body::empty())
+            .unwrap(),
+    )
+    .await;
+    let (gone, _, _) = send(
+        &app,
+        asset_request(Method::GET, "/11/assets/tree.png")
+            .body(Body::empty())
+            .unwrap(),
+    )
+    .await;
+
+    // Assert
+    assert_eq!(unauthorized, StatusCode::UNAUTHORIZED);
+    assert_eq!(first, StatusCode::CREATED);
+    assert_eq!(second, StatusCode::CREATED);
+    let second_body: Value = serde_json::from_slice(&second_body).unwrap();
+    assert_eq!(second_body["version"], json!(2));
+    assert_eq!(second_body["content_type"], json!("image/png"));
+    let listing: Value = serde_json::from_slice(&listing).unwrap();
+    let names: Vec<_> = listing
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|asset| asset["name"].as_str().unwrap())
+        .collect();
+    assert_eq!(names, ["decoration.png", "tree.png"]);
+    assert_eq!(listing[1]["etag"], second_body["etag"]);
+    assert_eq!(old, b"not yet a tree");
+    assert_eq!(thumbnail_headers[header::CONTENT_TYPE], "image/png");
+    assert_eq!(
+        image::load_from_memory(&thumbnail).unwrap().dimensions(),
+        (2, 2)
+    );
+    assert_eq!(deleted, StatusCode::NO_CONTENT);
+    assert_eq!(gone, StatusCode::NOT_FOUND);
+}
+
+#[tokio::test]
+async fn day11_assets_refuse_html_and_serve_nested_static_files() {
+    // Arrange
+    let static_dir = tempfile::tempdir().unwrap();
+    std::fs::create_dir(static_dir.path().join("icons")).unwrap();
+    std::fs::write(static_dir.path().join("icons/star.png"), red_and_blue_png()).unwrap();
+    let store_dir = tempfile::tempdir().unwrap();
+    let app = router(Arc::new(
+        AssetStore::open(static_dir.path(), store_dir.path(), Some(TOKEN.to_string())).unwrap(),
+    ));
+
+    // Act
+    let (html, _, _) = send(
+        &app,
+        asset_request(Method::PUT, "/11/assets/page.png")
+            .header(header::AUTHORIZATION, format!("Bearer {TOKEN}"))
+            .header(header::CONTENT_TYPE, "text/html; charset=utf-8")
+            .body(Body::from("<script>alert(1)</script>"))
+            .unwrap(),
+    )
+    .await;
+    let (nested, nested_headers, _) = send(
+        &app,
+        asset_request(Method::GET, "/11/assets/icons/star.png")
+            .body(Body::empty())
+            .unwrap(),
+    )
+    .await;
+    let (escaped, _, _) = send(
+        &app,
+        asset_request(Method::GET, "/11/assets/icons/..%2F..%2Fsecret")
+            .body(Body::empty())
+            .unwrap(),
+    )
+    .await;
+
+    // Assert
+    assert_eq!(html, StatusCode::UNSUPPORTED_MEDIA_TYPE);
+    assert_eq!(nested, StatusCode::OK);
+    assert_eq!(nested_headers[header::CONTENT_TYPE], "image/png");
+    assert_eq!(nested_headers[header::X_CONTENT_TYPE_OPTIONS], "nosniff");
+    assert_eq!(escaped, StatusCode::BAD_REQUEST);
+}
+
+#[tokio::test]
+async fn day11_red_pixels_sniffs_multiple_images() {
+    // Arrange
+    let png = red_and_blue_png();
+
+    // Act
+    let single = post("/11/red_pixels", &[("image", "image/jpeg", &png)]).await;
+    let several = post(
+        "/11/red_pixels",
+        &[
+            ("image", "application/octet-stream", &png),
+            ("image", "image/png", b"definitely not a png"),
+        ],
+    )
+    .await;
+    let store_dir = tempfile::tempdir().unwrap();
+    let malformed = app(store_dir.path())
+        .oneshot(
+            Request::builder()
+                .uri("/11/red_pixels")
+                .method(Method::POST)
+                .header(
+                    header::CONTENT_TYPE,
+                    format!("multipart/form-data; boundary={BOUNDARY}"),
+                )
+                .body(Body::from(format!("--{BOUNDARY}\r\ngarbage")))
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+
+    // Assert
+    // the declared content type is ignored, the bytes say it is a png
+    assert_eq!(single.status(), StatusCode::OK);
+    let body = single.into_body().collect().await.unwrap().to_bytes();
+    assert_eq!(&body[..], b"12");
+    assert_eq!(several.status(), StatusCode::OK);
+    let body = several.into_body().collect().await.unwrap().to_bytes();
+    let body: Value = serde_json::from_slice(&body).unwrap();
+    assert_eq!(
+        body,
+        json!([
+            {"file_name": "image", "content_type": "image/png", "size": png.len(), "red_pixels": 12},
+            {"file_name": "image", "size": 20, "error": "Unsupported Media Type"}
+        ])
+    );
+    assert_eq!(malformed.status(), StatusCode::BAD_REQUEST);
+}
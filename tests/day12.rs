@@ -0,0 +1,376 @@
+use std::sync::Arc;
+
+use axum::{
+    body::Body,
+    http::{header, Method, Request, StatusCode},
+    Router,
+};
+use cch23_challenge::{
+    clock::{ManualClock, SystemClock},
+    config::PacketStoreKind,
+    handlers::day12::{packet_store, router, DiskPacketStore, MemoryPacketStore, PgPacketStore},
+};
+use chrono::Utc;
+use http_body_util::BodyExt;
+use serde_json::{json, Value};
+use tower::ServiceExt; // for `call`, `oneshot`, and `ready`
+use ulid::Ulid;
+
+async fn send(app: &Router, method: Method, uri: &str) -> (StatusCode, String) {
+    let response = app
+        .clone()
+        .oneshot(
+            Request::builder()
+                .method(method)
+                .uri(uri)
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+    let status = response.status();
+    let body = response.into_body().collect().await.unwrap().to_bytes();
+    (status, String::from_utf8_lossy(&body[..]).to_string())
+}
+
+#[tokio::test]
+async fn day12_packets_expire_and_delete() {
+    // Arrange
+    let app = router(
+        Arc::new(MemoryPacketStore::default()),
+        Arc::new(SystemClock),
+        None,
+    );
+
+    // Act
+    send(&app, Method::POST, "/12/save/kept").await;
+    send(&app, Method::POST, "/12/save/gone?ttl=0").await;
+    send(&app, Method::POST, "/12/save/later?ttl=3600").await;
+    let (kept_status, kept_age) = send(&app, Method::GET, "/12/load/kept").await;
+    let (gone_status, _) = send(&app, Method::GET, "/12/load/gone").await;
+    let (_, listing) = send(&app, Method::GET, "/12/packets").await;
+    let (deleted, _) = send(&app, Method::DELETE, "/12/save/kept").await;
+    let (deleted_again, _) = send(&app, Method::DELETE, "/12/save/kept").await;
+
+    // Assert
+    assert_eq!(kept_status, StatusCode::OK);
+    assert_eq!(kept_age, "0");
+    assert_eq!(gone_status, StatusCode::NOT_FOUND);
+    let listing: Value = serde_json::from_str(&listing).unwrap();
+    let ids: Vec<_> = listing
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|packet| packet["id"].as_str().unwrap())
+        .collect();
+    assert_eq!(ids, ["kept", "later"]);
+    assert!(listing[0].get("expires_at").is_none());
+    assert!(listing[1]["expires_at"].is_string());
+    assert_eq!(deleted, StatusCode::NO_CONTENT);
+    assert_eq!(deleted_again, StatusCode::NOT_FOUND);
+}
+
+#[tokio::test]
+async fn day12_disk_store_survives_restart() {
+    // Arrange
+    let dir = tempfile::tempdir().unwrap();
+    let app = router(
+        Arc::new(DiskPacketStore::open(dir.path()).unwrap()),
+        Arc::new(SystemClock),
+        None,
+    );
+    send(&app, Method::POST, "/12/save/santa").await;
+
+    // Act
+    let restarted = router(
+        Arc::new(DiskPacketStore::open(dir.path()).unwrap()),
+        Arc::new(SystemClock),
+        None,
+    );
+    let (status, age) = send(&restarted, Method::GET, "/12/load/santa").await;
+
+    // Assert
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(age, "0");
+}
+
+#[cfg(unix)]
+#[tokio::test]
+async fn day12_disk_store_keeps_its_file_when_a_write_fails() {
+    // Arrange
+    let dir = tempfile::tempdir().unwrap();
+    let app = router(
+        Arc::new(DiskPacketStore::open(dir.path()).unwrap()),
+        Arc::new(SystemClock),
+        None,
+    );
+    send(&app, Method::POST, "/12/save/santa").await;
+    // writing through a dangling link fails, renaming the link itself wouldn't
+    std::os::unix::fs::symlink(
+        dir.path().join("missing/packets.json"),
+        dir.path().join("packets.json.tmp"),
+    )
+    .unwrap();
+
+    // Act
+    let (saved, _) = send(&app, Method::POST, "/12/save/rudolph").await;
+    let restarted = router(
+        Arc::new(DiskPacketStore::open(dir.path()).unwrap()),
+        Arc::new(SystemClock),
+        None,
+    );
+    let (kept, _) = send(&restarted, Method::GET, "/12/load/santa").await;
+
+    // Assert
+    assert_eq!(saved, StatusCode::INTERNAL_SERVER_ERROR);
+    assert_eq!(kept, StatusCode::OK);
+}
+
+#[sqlx::test]
+async fn day12_postgres_store_uses_the_migrated_table(pool: sqlx::PgPool) {
+    // Arrange
+    let app = router(
+        Arc::new(PgPacketStore::new(pool)),
+        Arc::new(SystemClock),
+        None,
+    );
+
+    // Act
+    send(&app, Method::POST, "/12/save/kept").await;
+    send(&app, Method::POST, "/12/save/gone?ttl=0").await;
+    let (kept_status, kept_age) = send(&app, Method::GET, "/12/load/kept").await;
+    let (gone_status, _) = send(&app, Method::GET, "/12/load/gone").await;
+    let (deleted, _) = send(&app, Method::DELETE, "/12/save/kept").await;
+
+    // Assert
+    assert_eq!(kept_status, StatusCode::OK);
+    assert_eq!(kept_age, "0");
+    assert_eq!(gone_status, StatusCode::NOT_FOUND);
+    assert_eq!(deleted, StatusCode::NO_CONTENT);
+}
+
+#[tokio::test]
+async fn day12_disk_store_reports_a_corrupt_file() {
+    // Arrange
+    let dir = tempfile::tempdir().unwrap();
+    std::fs::write(dir.path().join("packets.json"), "{not json").unwrap();
+    let pool = sqlx::PgPool::connect_lazy("postgres://localhost/unused").unwrap();
+
+    // Act
+    let store = packet_store(PacketStoreKind::Disk, &pool, dir.path());
+
+    // Assert
+    assert!(store.is_err());
+}
+
+#[tokio::test]
+async fn day12_manual_clock_advances_instantly() {
+    // Arrange
+    let app = router(
+        Arc::new(MemoryPacketStore::default()),
+        Arc::new(ManualClock::new(Utc::now())),
+        Some("elf".to_string()),
+    );
+    let advance = |token: &str| {
+        Request::builder()
+            .method(Method::POST)
+            .uri("/12/clock/advance?seconds=2")
+            .header(header::AUTHORIZATION, format!("Bearer {token}"))
+            .body(Body::empty())
+            .unwrap()
+    };
+    send(&app, Method::POST, "/12/save/packet20231212").await;
+
+    // Act
+    let unauthorized = app.clone().oneshot(advance("grinch")).await.unwrap();
+    let advanced = app.clone().oneshot(advance("elf")).await.unwrap();
+    let (_, age) = send(&app, Method::GET, "/12/load/packet20231212").await;
+
+    // Assert
+    assert_eq!(unauthorized.status(), StatusCode::UNAUTHORIZED);
+    assert_eq!(advanced.status(), StatusCode::OK);
+    assert_eq!(age, "2");
+}
+
+#[tokio::test]
+async fn day12_manual_clock_refuses_to_advance_past_the_last_date() {
+    // Arrange
+    let app = router(
+        Arc::new(MemoryPacketStore::default()),
+        Arc::new(ManualClock::new(Utc::now())),
+        Some("elf".to_string()),
+    );
+    let advance = |seconds: u64| {
+        Request::builder()
+            .method(Method::POST)
+            .uri(format!("/12/clock/advance?seconds={seconds}"))
+            .header(header::AUTHORIZATION, "Bearer elf")
+            .body(Body::empty())
+            .unwrap()
+    };
+    send(&app, Method::POST, "/12/save/packet20231212").await;
+
+    // Act
+    let overflowed = app
+        .clone()
+        .oneshot(advance(10_000_000_000_000))
+        .await
+        .unwrap();
+    let advanced = app.clone().oneshot(advance(2)).await.unwrap();
+    let (_, age) = send(&app, Method::GET, "/12/load/packet20231212").await;
+
+    // Assert
+    assert_eq!(overflowed.status(), StatusCode::BAD_REQUEST);
+    assert_eq!(advanced.status(), StatusCode::OK);
+    assert_eq!(age, "2");
+}
+
+async fn post_json(app: &Router, uri: &str, body: Value) -> (StatusCode, Value) {
+    let response = app
+        .clone()
+        .oneshot(
+            Request::builder()
+                .method(Method::POST)
+                .uri(uri)
+                .header(header::CONTENT_TYPE, "application/json")
+                .body(Body::from(body.to_string()))
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+    let status = response.status();
+    let body = response.into_body().collect().await.unwrap().to_bytes();
+    (status, serde_json::from_slice(&body).unwrap_or(Value::Null))
+}
+
+fn memory_app(clock: Arc<ManualClock>) -> Router {
+    router(Arc::new(MemoryPacketStore::default()), clock, None)
+}
+
+#[tokio::test]
+async fn day12_ulid_conversions_are_strict() {
+    // Arrange
+    let app = memory_app(Arc::new(ManualClock::new(Utc::now())));
+    let ulid = "01BJQ0E1C3Z56ABCD0E11HYX4M";
+    let uuid = "015cae07-0583-f94c-a5b1-a070431f7494";
+
+    // Act
+    let (_, uuids) = post_json(&app, "/12/ulids", json!([ulid])).await;
+    let (_, ulids) = post_json(&app, "/12/uuids", json!([uuid])).await;
+    let (bad_status, bad) = post_json(
+        &app,
+        "/12/ulids",
+        json!([ulid, "8ZZZZZZZZZZZZZZZZZZZZZZZZZ", "nope"]),
+    )
+    .await;
+    let (weekday_status, _) = post_json(&app, "/12/ulids/5", json!(["nope"])).await;
+    let (_, decoded) = post_json(&app, "/12/ulids/decode", json!([uuid, "nope"])).await;
+
+    // Assert
+    assert_eq!(uuids, json!([uuid]));
+    assert_eq!(ulids, json!([ulid]));
+    assert_eq!(bad_status, StatusCode::BAD_REQUEST);
+    assert_eq!(bad["errors"][0]["index"], json!(1));
+    assert_eq!(
+        bad["errors"][0]["error"],
+        json!("the timestamp overflows 48 bits")
+    );
+    assert_eq!(bad["errors"][1]["index"], json!(2));
+    assert_eq!(weekday_status, StatusCode::BAD_REQUEST);
+    assert_eq!(decoded[0]["ulid"], json!(ulid));
+    assert_eq!(decoded[0]["timestamp_ms"], json!(1497568314755u64));
+    assert_eq!(decoded[0]["datetime"], json!("2017-06-15T23:11:54.755Z"));
+    assert_eq!(decoded[0]["randomness"], json!("f94ca5b1a070431f7494"));
+    assert!(decoded[1]["error"].is_string());
+}
+
+#[tokio::test]
+async fn day12_generate_sort_and_group() {
+    // Arrange
+    let start = "2023-12-24T10:00:00Z".parse().unwrap();
+    let clock = Arc::new(ManualClock::new(start));
+    let app = memory_app(clock.clone());
+
+    // Act
+    let (_, first) = send(
+        &app,
+        Method::GET,
+        "/12/ulids/generate?count=3&monotonic=true",
+    )
+    .await;
+    clock.advance(chrono::Duration::hours(1)).unwrap();
+    let (_, second) = send(&app, Method::GET, "/12/ulids/generate?count=1").await;
+    let (_, v7) = send(&app, Method::GET, "/12/uuids/generate?version=7").await;
+    let first: Vec<String> = serde_json::from_str(&first).unwrap();
+    let second: Vec<String> = serde_json::from_str(&second).unwrap();
+    let v7: Vec<String> = serde_json::from_str(&v7).unwrap();
+    let mut all = second.clone();
+    all.extend(first.iter().rev().cloned());
+    let (_, sorted) = post_json(&app, "/12/ulids/sort", json!(all)).await;
+    let (_, groups) = post_json(&app, "/12/ulids/group?by=hour", json!(all)).await;
+    let (_, decoded_v7) = post_json(&app, "/12/ulids/decode", json!(v7)).await;
+
+    // Assert
+    let mut expected = first.clone();
+    expected.extend(second.clone());
+    assert_eq!(sorted, json!(expected));
+    assert_eq!(
+        groups,
+        json!({"2023-12-24T10": first, "2023-12-24T11": second})
+    );
+    assert_eq!(&v7[0][14..15], "7");
+    assert_eq!(decoded_v7[0]["datetime"], json!("2023-12-24T11:00:00Z"));
+}
+
+#[tokio::test]
+async fn day12_ulid_stats_in_time_zone() {
+    // Arrange
+    let app = memory_app(Arc::new(ManualClock::new(Utc::now())));
+    let ulid = |datetime: &str| {
+        let datetime: chrono::DateTime<Utc> = datetime.parse().unwrap();
+        Ulid::from_parts(datetime.timestamp_millis() as u64, 42).to_string()
+    };
+    let request = json!({
+        "ulids": [
+            ulid("2024-01-01T00:00:00Z"),
+            ulid("2023-12-24T12:00:00Z"),
+            // already christmas day in Stockholm
+            ulid("2023-12-24T23:30:00Z"),
+        ],
+        "timezone": "Europe/Stockholm",
+        "buckets": ["year", "weekday"],
+        "predicates": [
+            {"name": "christmas eve", "month": 12, "day": 24},
+            {"name": "night", "hours": [22, 2]},
+            {"name": "weekend", "weekdays": ["Sat", "Sun"]}
+        ]
+    });
+
+    // Act
+    let (status, stats) = post_json(&app, "/12/ulids/stats", request).await;
+    let (bad_zone, _) = post_json(
+        &app,
+        "/12/ulids/stats",
+        json!({"ulids": [], "timezone": "Europe/Lapland"}),
+    )
+    .await;
+
+    // Assert
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(stats["count"], json!(3));
+    assert_eq!(stats["min"]["datetime"], json!("2023-12-24T13:00:00+01:00"));
+    assert_eq!(
+        stats["median"]["datetime"],
+        json!("2023-12-25T00:30:00+01:00")
+    );
+    assert_eq!(stats["max"]["datetime"], json!("2024-01-01T01:00:00+01:00"));
+    assert_eq!(
+        stats["buckets"],
+        json!({"year": {"2023": 2, "2024": 1}, "weekday": {"Sun": 1, "Mon": 2}})
+    );
+    assert_eq!(
+        stats["predicates"],
+        json!({"christmas eve": 1, "night": 2, "weekend": 1})
+    );
+    assert_eq!(bad_zone, StatusCode::BAD_REQUEST);
+}
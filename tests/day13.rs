@@ -0,0 +1,339 @@
+use axum::{
+    body::Body,
+    http::{header, Method, Request, StatusCode},
+    Router,
+};
+use cch23_challenge::handlers::day13::router;
+use http_body_util::BodyExt;
+use serde_json::{json, Value};
+use sqlx::PgPool;
+use tower::ServiceExt; // for `call`, `oneshot`, and `ready`
+
+/// The router over a pool that never connects, enough for requests that are
+/// rejected before reaching the database.
+fn offline_app() -> Router {
+    router(PgPool::connect_lazy("postgres://localhost/unused").unwrap())
+}
+
+async fn send(app: &Router, method: Method, uri: &str, body: Value) -> (StatusCode, Value) {
+    let response = app
+        .clone()
+        .oneshot(
+            Request::builder()
+                .method(method)
+                .uri(uri)
+                .header(header::CONTENT_TYPE, "application/json")
+                .body(Body::from(body.to_string()))
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+    let status = response.status();
+    let body = response.into_body().collect().await.unwrap().to_bytes();
+    (status, serde_json::from_slice(&body).unwrap_or(Value::Null))
+}
+
+#[tokio::test]
+async fn day13_orders_rejects_invalid_fields_with_422() {
+    // Arrange
+    let app = offline_app();
+    let orders = json!([
+        {"id": 1, "region_id": 1, "gift_name": "Toy Train", "quantity": 5},
+        {"id": 2, "region_id": 1, "gift_name": "x".repeat(51), "quantity": -1},
+    ]);
+
+    // Act
+    let (created, created_body) = send(&app, Method::POST, "/13/orders", orders).await;
+    let (patched, patched_body) =
+        send(&app, Method::PATCH, "/13/orders/1", json!({"quantity": -3})).await;
+    let (listed, listed_body) = send(&app, Method::GET, "/13/orders?limit=0", Value::Null).await;
+    let (ranked, ranked_body) =
+        send(&app, Method::GET, "/13/orders/popular?top=0", Value::Null).await;
+
+    // Assert
+    assert_eq!(created, StatusCode::UNPROCESSABLE_ENTITY);
+    assert_eq!(
+        created_body,
+        json!({"errors": [
+            {"index": 1, "field": "gift_name", "message": "must be at most 50 characters"},
+            {"index": 1, "field": "quantity", "message": "must not be negative"},
+        ]})
+    );
+    assert_eq!(patched, StatusCode::UNPROCESSABLE_ENTITY);
+    assert_eq!(patched_body["errors"][0]["field"], "quantity");
+    assert_eq!(listed, StatusCode::UNPROCESSABLE_ENTITY);
+    assert_eq!(listed_body["errors"][0]["field"], "limit");
+    assert_eq!(ranked, StatusCode::UNPROCESSABLE_ENTITY);
+    assert_eq!(ranked_body["errors"][0]["field"], "top");
+}
+
+#[tokio::test]
+async fn day13_orders_import_dry_run_reports_line_errors() {
+    // Arrange
+    let app = offline_app();
+    let csv = "id,region_id,gift_name,quantity\n1,1,Toy Train,5\n2,1,Doll,-3\n";
+
+    // Act
+    let response = app
+        .oneshot(
+            Request::builder()
+                .method(Method::POST)
+                .uri("/13/orders/import?dry_run=true")
+                .header(header::CONTENT_TYPE, "text/csv")
+                .body(Body::from(csv))
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+    let status = response.status();
+    let body = response.into_body().collect().await.unwrap().to_bytes();
+
+    // Assert
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(
+        serde_json::from_slice::<Value>(&body).unwrap(),
+        json!({"valid": 1, "invalid": 1, "errors": [
+            {"line": 3, "field": "quantity", "message": "must not be negative"},
+        ]})
+    );
+}
+
+#[tokio::test]
+async fn day13_sql_rejects_statements_that_are_not_a_single_select() {
+    // Arrange
+    let app = offline_app();
+
+    // Act
+    let (deleted, deleted_body) = send(
+        &app,
+        Method::POST,
+        "/13/sql",
+        json!({"query": "delete from orders"}),
+    )
+    .await;
+    let (chained, _) = send(
+        &app,
+        Method::POST,
+        "/13/sql",
+        json!({"query": "select 1; drop table orders"}),
+    )
+    .await;
+    let (locked, _) = send(
+        &app,
+        Method::POST,
+        "/13/sql",
+        json!({"query": "select * from orders for update", "limit": 0}),
+    )
+    .await;
+
+    // Assert
+    assert_eq!(deleted, StatusCode::UNPROCESSABLE_ENTITY);
+    assert_eq!(
+        deleted_body,
+        json!({"errors": [{"field": "query", "message": "must be a single SELECT statement"}]})
+    );
+    assert_eq!(chained, StatusCode::UNPROCESSABLE_ENTITY);
+    assert_eq!(locked, StatusCode::UNPROCESSABLE_ENTITY);
+}
+
+#[sqlx::test]
+async fn day13_reset_keeps_regions_and_gifts(pool: PgPool) {
+    // Arrange
+    let app = router(pool.clone());
+    send(
+        &app,
+        Method::POST,
+        "/13/orders",
+        json!([{"id": 1, "region_id": 2, "gift_name": "Toy Train", "quantity": 5}]),
+    )
+    .await;
+
+    // Act
+    let (status, _) = send(&app, Method::POST, "/13/reset", Value::Null).await;
+    let (_, total) = send(&app, Method::GET, "/13/orders/total", Value::Null).await;
+    let regions: Vec<i32> = sqlx::query_scalar("select id from regions")
+        .fetch_all(&pool)
+        .await
+        .unwrap();
+    let gifts: Vec<String> = sqlx::query_scalar("select name::text from gifts")
+        .fetch_all(&pool)
+        .await
+        .unwrap();
+
+    // Assert
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(total, json!({"total": 0}));
+    assert_eq!(regions, [2]);
+    assert_eq!(gifts, ["Toy Train"]);
+}
+
+#[sqlx::test]
+async fn day13_orders_are_inserted_in_one_batch_per_conflict_mode(pool: PgPool) {
+    // Arrange
+    let app = router(pool.clone());
+    let order = |id: i32, gift_name: &str, quantity: i32| json!({"id": id, "region_id": 1, "gift_name": gift_name, "quantity": quantity});
+    send(
+        &app,
+        Method::POST,
+        "/13/orders",
+        json!([order(1, "Toy Train", 5), order(2, "Doll", 2)]),
+    )
+    .await;
+
+    // Act
+    let (conflict, _) = send(
+        &app,
+        Method::POST,
+        "/13/orders",
+        json!([order(3, "Robot", 8), order(2, "Doll", 9)]),
+    )
+    .await;
+    let (_, ignored) = send(
+        &app,
+        Method::POST,
+        "/13/orders?on_conflict=ignore",
+        json!([order(2, "Doll", 9), order(3, "Robot", 1)]),
+    )
+    .await;
+    let (_, upserted) = send(
+        &app,
+        Method::POST,
+        "/13/orders?on_conflict=upsert",
+        json!([
+            order(4, "Doll", 1),
+            order(1, "Robot", 6),
+            order(4, "Doll", 7)
+        ]),
+    )
+    .await;
+    let rows: Vec<(i32, String, i32)> = sqlx::query_as(
+        r#"
+        select o.id, g.name::text, o.quantity
+        from orders o
+        inner join gifts g on g.id = o.gift_id
+        order by o.id
+        "#,
+    )
+    .fetch_all(&pool)
+    .await
+    .unwrap();
+
+    // Assert
+    // the failed batch left no order 3 behind for the ignoring one to skip
+    assert_eq!(conflict, StatusCode::CONFLICT);
+    assert_eq!(ignored, json!({"inserted": 1, "updated": 0, "skipped": 1}));
+    assert_eq!(upserted, json!({"inserted": 1, "updated": 1, "skipped": 1}));
+    assert_eq!(
+        rows,
+        [
+            (1, "Robot".to_string(), 6),
+            (2, "Doll".to_string(), 2),
+            (3, "Robot".to_string(), 1),
+            (4, "Doll".to_string(), 7),
+        ]
+    );
+}
+
+#[sqlx::test]
+async fn day13_popular_ranks_ties_together(pool: PgPool) {
+    // Arrange
+    let app = router(pool.clone());
+    let (_, nothing) = send(&app, Method::GET, "/13/orders/popular", Value::Null).await;
+    send(
+        &app,
+        Method::POST,
+        "/13/orders",
+        json!([
+            {"id": 1, "region_id": 1, "gift_name": "Toy Train", "quantity": 3},
+            {"id": 2, "region_id": 2, "gift_name": "Toy Train", "quantity": 2},
+            {"id": 3, "region_id": 1, "gift_name": "Doll", "quantity": 5},
+            {"id": 4, "region_id": 2, "gift_name": "Robot", "quantity": 4},
+            {"id": 5, "region_id": 1, "gift_name": "Kite", "quantity": 1},
+        ]),
+    )
+    .await;
+    sqlx::query("update orders set created_at = '2023-01-01T00:00:00Z' where id = 3")
+        .execute(&pool)
+        .await
+        .unwrap();
+
+    // Act
+    let (_, legacy) = send(&app, Method::GET, "/13/orders/popular", Value::Null).await;
+    let (_, top2) = send(&app, Method::GET, "/13/orders/popular?top=2", Value::Null).await;
+    let (_, top3) = send(&app, Method::GET, "/13/orders/popular?top=3", Value::Null).await;
+    let (_, region) = send(
+        &app,
+        Method::GET,
+        "/13/orders/popular?region=2",
+        Value::Null,
+    )
+    .await;
+    let (_, since) = send(
+        &app,
+        Method::GET,
+        "/13/orders/popular?since=2023-06-01T00:00:00Z",
+        Value::Null,
+    )
+    .await;
+
+    // Assert
+    assert_eq!(nothing, json!({"popular": null}));
+    assert_eq!(legacy, json!({"popular": "Doll"}));
+    // the tie takes ranks 1 and 2, so the next gift is third
+    let tie = json!({"rank": 1, "quantity": 5, "gifts": ["Doll", "Toy Train"]});
+    assert_eq!(top2, json!({"ranking": [tie]}));
+    assert_eq!(
+        top3,
+        json!({"ranking": [tie, {"rank": 3, "quantity": 4, "gifts": ["Robot"]}]})
+    );
+    assert_eq!(
+        region,
+        json!({"ranking": [{"rank": 1, "quantity": 4, "gifts": ["Robot"]}]})
+    );
+    assert_eq!(
+        since,
+        json!({"ranking": [{"rank": 1, "quantity": 5, "gifts": ["Toy Train"]}]})
+    );
+}
+
+#[sqlx::test]
+async fn day13_sql_runs_as_a_reader_of_the_tables(pool: PgPool) {
+    // Arrange
+    let app = router(pool);
+    // the reset recreates the tables the reader was granted
+    send(&app, Method::POST, "/13/reset", Value::Null).await;
+    send(
+        &app,
+        Method::POST,
+        "/13/orders",
+        json!([{"id": 1, "region_id": 2, "gift_name": "Toy Train", "quantity": 5}]),
+    )
+    .await;
+    let sql = |query: &str| json!({"query": query});
+
+    // Act
+    let (_, who) = send(
+        &app,
+        Method::POST,
+        "/13/sql",
+        sql("select current_user::text"),
+    )
+    .await;
+    let (_, orders) = send(&app, Method::POST, "/13/sql", sql("select id from orders")).await;
+    let (migrations, migrations_body) = send(
+        &app,
+        Method::POST,
+        "/13/sql",
+        sql("select version from _sqlx_migrations"),
+    )
+    .await;
+
+    // Assert
+    assert_eq!(who["rows"], json!([["sql_reader"]]));
+    assert_eq!(orders["rows"], json!([[1]]));
+    assert_eq!(migrations, StatusCode::UNPROCESSABLE_ENTITY);
+    assert_eq!(
+        migrations_body["errors"][0]["message"],
+        json!("permission denied for table _sqlx_migrations")
+    );
+}
@@ -0,0 +1,294 @@
+use axum::{
+    body::Body,
+    http::{header, Method, Request, StatusCode},
+    Router,
+};
+use cch23_challenge::handlers::day18::router;
+use http_body_util::BodyExt;
+use serde_json::{json, Value};
+use sqlx::PgPool;
+use tower::ServiceExt; // for `call`, `oneshot`, and `ready`
+
+async fn send(app: &Router, method: Method, uri: &str, body: Value) -> (StatusCode, Value) {
+    let response = app
+        .clone()
+        .oneshot(
+            Request::builder()
+                .method(method)
+                .uri(uri)
+                .header(header::CONTENT_TYPE, "application/json")
+                .body(Body::from(body.to_string()))
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+    let status = response.status();
+    let body = response.into_body().collect().await.unwrap().to_bytes();
+    (status, serde_json::from_slice(&body).unwrap_or(Value::Null))
+}
+
+/// Norway with Oslo and Bergen, Frogner in Oslo, and Sweden on its own.
+async fn nordic_app(pool: PgPool) -> Router {
+    let app = router(pool);
+    send(
+        &app,
+        Method::POST,
+        "/18/regions",
+        json!([
+            {"id": 1, "name": "Norway"},
+            {"id": 2, "name": "Oslo", "parent_id": 1},
+            {"id": 3, "name": "Bergen", "parent_id": 1},
+            {"id": 4, "name": "Sweden"},
+            {"id": 5, "name": "Frogner", "parent_id": 2},
+        ]),
+    )
+    .await;
+    send(
+        &app,
+        Method::POST,
+        "/18/orders",
+        json!([
+            {"id": 1, "region_id": 2, "gift_name": "Toy Train", "quantity": 3},
+            {"id": 2, "region_id": 5, "gift_name": "Doll", "quantity": 4},
+            {"id": 3, "region_id": 1, "gift_name": "Toy Train", "quantity": 1},
+            {"id": 4, "region_id": 4, "gift_name": "Doll", "quantity": 10},
+        ]),
+    )
+    .await;
+    app
+}
+
+#[sqlx::test]
+async fn day18_totals_roll_up_to_the_requested_level(pool: PgPool) {
+    // Arrange
+    let app = nordic_app(pool).await;
+
+    // Act
+    let (_, own) = send(&app, Method::GET, "/18/regions/total", Value::Null).await;
+    let (_, countries) = send(&app, Method::GET, "/18/regions/total?level=0", Value::Null).await;
+    let (_, states) = send(&app, Method::GET, "/18/regions/total?level=1", Value::Null).await;
+    let (_, cities) = send(&app, Method::GET, "/18/regions/total?level=2", Value::Null).await;
+
+    // Assert
+    assert_eq!(
+        own,
+        json!([
+            {"region": "Frogner", "total": 4},
+            {"region": "Norway", "total": 1},
+            {"region": "Oslo", "total": 3},
+            {"region": "Sweden", "total": 10},
+        ])
+    );
+    assert_eq!(
+        countries,
+        json!([{"region": "Norway", "total": 8}, {"region": "Sweden", "total": 10}])
+    );
+    // Bergen has no orders anywhere below it
+    assert_eq!(states, json!([{"region": "Oslo", "total": 7}]));
+    assert_eq!(cities, json!([{"region": "Frogner", "total": 4}]));
+}
+
+#[sqlx::test]
+async fn day18_region_tree_nests_regions_with_their_totals(pool: PgPool) {
+    // Arrange
+    let app = nordic_app(pool).await;
+    let frogner = json!({"id": 5, "name": "Frogner", "quantity": 4, "total": 4, "children": []});
+    let oslo = json!({"id": 2, "name": "Oslo", "quantity": 3, "total": 7, "children": [frogner]});
+
+    // Act
+    let (_, tree) = send(&app, Method::GET, "/18/regions/tree", Value::Null).await;
+    let (_, subtree) = send(&app, Method::GET, "/18/regions/tree?root=2", Value::Null).await;
+    let (cyclic, _) = send(
+        &app,
+        Method::POST,
+        "/18/regions?on_conflict=upsert",
+        json!([{"id": 1, "name": "Norway", "parent_id": 5}]),
+    )
+    .await;
+
+    // Assert
+    assert_eq!(
+        tree,
+        json!([
+            {"id": 1, "name": "Norway", "quantity": 1, "total": 8, "children": [
+                {"id": 3, "name": "Bergen", "quantity": 0, "total": 0, "children": []},
+                oslo,
+            ]},
+            {"id": 4, "name": "Sweden", "quantity": 10, "total": 10, "children": []},
+        ])
+    );
+    assert_eq!(subtree, json!([oslo]));
+    assert_eq!(cyclic, StatusCode::UNPROCESSABLE_ENTITY);
+}
+
+#[sqlx::test]
+async fn day18_regions_are_inserted_in_one_batch_per_conflict_mode(pool: PgPool) {
+    // Arrange
+    let app = router(pool.clone());
+    send(
+        &app,
+        Method::POST,
+        "/18/regions",
+        json!([{"id": 1, "name": "Norway"}]),
+    )
+    .await;
+    // region 3 is only known from its order until it is posted
+    send(
+        &app,
+        Method::POST,
+        "/18/orders",
+        json!([{"id": 1, "region_id": 3, "gift_name": "Doll", "quantity": 1}]),
+    )
+    .await;
+
+    // Act
+    let (conflict, _) = send(
+        &app,
+        Method::POST,
+        "/18/regions",
+        json!([{"id": 2, "name": "Sweden"}, {"id": 1, "name": "Noreg"}]),
+    )
+    .await;
+    let (_, ignored) = send(
+        &app,
+        Method::POST,
+        "/18/regions?on_conflict=ignore",
+        json!([{"id": 1, "name": "Noreg"}, {"id": 3, "name": "Finland"}]),
+    )
+    .await;
+    let (_, upserted) = send(
+        &app,
+        Method::POST,
+        "/18/regions?on_conflict=upsert",
+        json!([
+            {"id": 2, "name": "Sverige"},
+            {"id": 1, "name": "Noreg"},
+            {"id": 2, "name": "Sweden"},
+        ]),
+    )
+    .await;
+    let rows: Vec<(i32, String)> = sqlx::query_as("select id, name::text from regions order by id")
+        .fetch_all(&pool)
+        .await
+        .unwrap();
+
+    // Assert
+    assert_eq!(conflict, StatusCode::CONFLICT);
+    assert_eq!(ignored, json!({"inserted": 1, "updated": 0, "skipped": 1}));
+    assert_eq!(upserted, json!({"inserted": 1, "updated": 1, "skipped": 1}));
+    assert_eq!(
+        rows,
+        [
+            (1, "Noreg".to_string()),
+            (2, "Sweden".to_string()),
+            (3, "Finland".to_string()),
+        ]
+    );
+}
+
+#[sqlx::test]
+async fn day18_analytics_report_the_ordered_quantities(pool: PgPool) {
+    // Arrange
+    let app = router(pool);
+    send(
+        &app,
+        Method::POST,
+        "/18/regions",
+        json!([
+            {"id": 1, "name": "North"},
+            {"id": 2, "name": "South"},
+            {"id": 3, "name": "East"},
+        ]),
+    )
+    .await;
+    send(
+        &app,
+        Method::POST,
+        "/18/orders",
+        json!([
+            {"id": 1, "region_id": 1, "gift_name": "Doll", "quantity": 2},
+            {"id": 2, "region_id": 2, "gift_name": "Doll", "quantity": 3},
+            {"id": 3, "region_id": 1, "gift_name": "Kite", "quantity": 5},
+            {"id": 4, "region_id": 2, "gift_name": "Doll", "quantity": 10},
+        ]),
+    )
+    .await;
+    let get = |uri: &'static str| send(&app, Method::GET, uri, Value::Null);
+
+    // Act
+    let (_, share) = get("/18/analytics/share").await;
+    let (_, pivot) = get("/18/analytics/pivot").await;
+    let (_, empty) = get("/18/analytics/empty_regions").await;
+    let (_, running) = get("/18/analytics/running_totals").await;
+    let (_, south) = get("/18/analytics/running_totals?region=2").await;
+    let (_, percentiles) = get("/18/analytics/percentiles?p=0,0.25,0.5,0.75,1").await;
+    let (_, no_orders) = get("/18/analytics/percentiles?p=0.5&region=3").await;
+
+    // Assert
+    assert_eq!(
+        share,
+        json!([
+            {"region": "North", "total": 7, "share": 0.35},
+            {"region": "South", "total": 13, "share": 0.65},
+        ])
+    );
+    // East and the kites of South were never ordered but still get a cell
+    assert_eq!(
+        pivot,
+        json!({
+            "regions": ["East", "North", "South"],
+            "gifts": [
+                {"gift": "Doll", "quantities": [0, 2, 13], "total": 15},
+                {"gift": "Kite", "quantities": [0, 5, 0], "total": 5},
+            ]
+        })
+    );
+    assert_eq!(empty, json!([{"id": 3, "name": "East", "parent_id": null}]));
+    let running_totals = |totals: &Value| -> Vec<(i64, i64, i64)> {
+        totals
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|total| {
+                (
+                    total["order_id"].as_i64().unwrap(),
+                    total["running_total"].as_i64().unwrap(),
+                    total["region_running_total"].as_i64().unwrap(),
+                )
+            })
+            .collect()
+    };
+    assert_eq!(
+        running_totals(&running),
+        [(1, 2, 2), (2, 5, 3), (3, 10, 7), (4, 20, 13)]
+    );
+    assert_eq!(running_totals(&south), [(2, 3, 3), (4, 13, 13)]);
+    assert_eq!(running[1]["gift_name"], json!("Doll"));
+    // interpolated between the sizes 2, 3, 5 and 10
+    assert_eq!(
+        percentiles,
+        json!({
+            "orders": 4,
+            "min": 2,
+            "max": 10,
+            "mean": 5.0,
+            "percentiles": [
+                {"p": 0.0, "quantity": 2.0},
+                {"p": 0.25, "quantity": 2.75},
+                {"p": 0.5, "quantity": 4.0},
+                {"p": 0.75, "quantity": 6.25},
+                {"p": 1.0, "quantity": 10.0},
+            ]
+        })
+    );
+    assert_eq!(
+        no_orders,
+        json!({
+            "orders": 0,
+            "min": null,
+            "max": null,
+            "mean": null,
+            "percentiles": [{"p": 0.5, "quantity": null}]
+        })
+    );
+}
@@ -0,0 +1,175 @@
+use axum::{
+    body::Body,
+    http::{header, Method, Request, StatusCode},
+};
+use cch23_challenge::handlers::day6::router;
+use http_body_util::BodyExt;
+use serde_json::{json, Value};
+use tower::ServiceExt; // for `call`, `oneshot`, and `ready`
+
+#[tokio::test]
+async fn day6_elf_on_shelf() {
+    // Arrange
+    let app = router();
+
+    // Act
+    let response = app
+        .oneshot(
+            Request::builder()
+                .uri("/6")
+                .method(Method::POST)
+                .body(Body::from(
+                    "there is an elf on a shelf on an elf. there is also another shelf in Belfast.",
+                ))
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+
+    // Assert
+    assert_eq!(response.status(), StatusCode::OK);
+    let body = response.into_body().collect().await.unwrap().to_bytes();
+    let body: Value = serde_json::from_slice(&body).unwrap();
+    assert_eq!(
+        body,
+        json!({"elf": 5, "elf on a shelf": 1, "shelf with no elf on it": 1})
+    );
+}
+
+#[tokio::test]
+async fn day6_elf_on_shelf_per_line_normalized() {
+    // Arrange
+    let app = router();
+
+    // Act
+    let response = app
+        .oneshot(
+            Request::builder()
+                .uri("/6?normalize=nfkc&case_fold=true&per_line=true")
+                .method(Method::POST)
+                .body(Body::from("ＥＬＦ on a SHELF\nshelf"))
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+
+    // Assert
+    assert_eq!(response.status(), StatusCode::OK);
+    let body = response.into_body().collect().await.unwrap().to_bytes();
+    let body: Value = serde_json::from_slice(&body).unwrap();
+    assert_eq!(
+        body,
+        json!([
+            {"line": 1, "elf": 2, "elf on a shelf": 1, "shelf with no elf on it": 0},
+            {"line": 2, "elf": 1, "elf on a shelf": 0, "shelf with no elf on it": 1}
+        ])
+    );
+}
+
+#[tokio::test]
+async fn day6_count_patterns() {
+    // Arrange
+    let app = router();
+
+    // Act
+    let response = app
+        .oneshot(
+            Request::builder()
+                .uri("/6/count")
+                .method(Method::POST)
+                .header(header::CONTENT_TYPE, mime::APPLICATION_JSON.as_ref())
+                .body(Body::from(
+                    json!({
+                        "text": "Elf on a shelf, elfish aaa",
+                        "overlapping": true,
+                        "offsets": true,
+                        "patterns": [
+                            {"name": "elf", "pattern": "elf", "case_insensitive": true, "whole_word": true},
+                            {"name": "aa", "pattern": "aa"},
+                            {"name": "lookahead", "pattern": "(?i)elf(?= on)", "kind": "regex"}
+                        ]
+                    })
+                    .to_string(),
+                ))
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+
+    // Assert
+    assert_eq!(response.status(), StatusCode::OK);
+    let body = response.into_body().collect().await.unwrap().to_bytes();
+    let body: Value = serde_json::from_slice(&body).unwrap();
+    assert_eq!(
+        body,
+        json!({
+            "aa": {"count": 2, "offsets": [{"start": 23, "end": 25}, {"start": 24, "end": 26}]},
+            "elf": {"count": 1, "offsets": [{"start": 0, "end": 3}]},
+            "lookahead": {"count": 1, "offsets": [{"start": 0, "end": 3}]}
+        })
+    );
+}
+
+#[tokio::test]
+async fn day6_count_rejects_oversized_patterns() {
+    // Arrange
+    let app = router();
+
+    // Act
+    let response = app
+        .oneshot(
+            Request::builder()
+                .uri("/6/count")
+                .method(Method::POST)
+                .header(header::CONTENT_TYPE, mime::APPLICATION_JSON.as_ref())
+                .body(Body::from(
+                    json!({
+                        "text": "elf",
+                        "patterns": [{"name": "big", "pattern": "e".repeat(1000)}]
+                    })
+                    .to_string(),
+                ))
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+
+    // Assert
+    assert_eq!(response.status(), StatusCode::PAYLOAD_TOO_LARGE);
+}
+
+#[tokio::test]
+async fn day6_count_caps_overlapping_matches() {
+    // Arrange
+    let app = router();
+    let count = |text: String, overlapping: bool| {
+        app.clone().oneshot(
+            Request::builder()
+                .uri("/6/count")
+                .method(Method::POST)
+                .header(header::CONTENT_TYPE, mime::APPLICATION_JSON.as_ref())
+                .body(Body::from(
+                    json!({
+                        "text": text,
+                        "patterns": [{"name": "all", "pattern": "(?s).+", "kind": "regex"}],
+                        "overlapping": overlapping
+                    })
+                    .to_string(),
+                ))
+                .unwrap(),
+        )
+    };
+
+    // Act
+    let too_long = count("a".repeat(1024 * 1024), true).await.unwrap();
+    let too_many = count("a".repeat(20_000), true).await.unwrap();
+    let greedy = count("a".repeat(1024 * 1024), false).await.unwrap();
+
+    // Assert
+    assert_eq!(too_long.status(), StatusCode::PAYLOAD_TOO_LARGE);
+    assert_eq!(too_many.status(), StatusCode::UNPROCESSABLE_ENTITY);
+    assert_eq!(greedy.status(), StatusCode::OK);
+    let body = greedy.into_body().collect().await.unwrap().to_bytes();
+    let body: Value = serde_json::from_slice(&body).unwrap();
+    assert_eq!(body, json!({"all": {"count": 1}}));
+}
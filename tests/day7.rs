@@ -0,0 +1,307 @@
+use axum::{
+    body::Body,
+    http::{header, Method, Request, StatusCode},
+};
+use cch23_challenge::{
+    config::CookieFormat,
+    handlers::day7::{router, MissingCookieKeys, RecipeCookieCodec},
+};
+use http_body_util::BodyExt;
+use serde_json::{json, Value};
+use std::time::Duration;
+use tower::ServiceExt; // for `call`, `oneshot`, and `ready`
+
+const RECIPE: &str = r#"{"recipe":{"flour":10},"pantry":{"flour":45}}"#;
+
+async fn bake(codec: &RecipeCookieCodec, cookie: &str) -> (StatusCode, Option<String>, Value) {
+    let response = router(codec.clone())
+        .oneshot(
+            Request::builder()
+                .uri("/7/bake")
+                .header(header::COOKIE, format!("recipe={cookie}"))
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+    let status = response.status();
+    // keep only the cookie value out of the Set-Cookie header
+    let set_cookie = response.headers().get(header::SET_COOKIE).map(|v| {
+        let v = v.to_str().unwrap();
+        v["recipe=".len()..v.find(';').unwrap()].to_string()
+    });
+    let body = response.into_body().collect().await.unwrap().to_bytes();
+    let body = serde_json::from_slice(&body).unwrap_or(Value::Null);
+    (status, set_cookie, body)
+}
+
+#[tokio::test]
+async fn day7_bake_plain_unpadded_url_safe() {
+    // Arrange
+    let codec = RecipeCookieCodec::new(CookieFormat::Plain, &[]).unwrap();
+    // {"recipe":{"flour":95},"pantry":{"flour":385}} without the trailing padding
+    let cookie = "eyJyZWNpcGUiOnsiZmxvdXIiOjk1fSwicGFudHJ5Ijp7ImZsb3VyIjozODV9fQ";
+
+    // Act
+    let (status, set_cookie, body) = bake(&codec, cookie).await;
+
+    // Assert
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(body, json!({"cookies": 4, "pantry": {"flour": 5}}));
+    let set_cookie: Value =
+        serde_json::from_slice(&codec.decode(&set_cookie.unwrap()).unwrap()).unwrap();
+    assert_eq!(
+        set_cookie,
+        json!({"recipe": {"flour": 95}, "pantry": {"flour": 5}})
+    );
+}
+
+#[tokio::test]
+async fn day7_bake_signed_with_rotated_key() {
+    // Arrange
+    let old = RecipeCookieCodec::new(CookieFormat::Signed, &["old-key".to_string()]).unwrap();
+    let rotated = RecipeCookieCodec::new(
+        CookieFormat::Signed,
+        &["new-key".to_string(), "old-key".to_string()],
+    )
+    .unwrap();
+    let plain = RecipeCookieCodec::new(CookieFormat::Plain, &[]).unwrap();
+    let cookie = old.encode(RECIPE.as_bytes()).unwrap();
+
+    // Act
+    let (unsigned_status, _, _) = bake(&old, &plain.encode(RECIPE.as_bytes()).unwrap()).await;
+    let (status, set_cookie, body) = bake(&rotated, &cookie).await;
+    let set_cookie = set_cookie.unwrap();
+    let (tampered_status, _, _) = bake(&rotated, &format!("x{set_cookie}")).await;
+    let (old_key_status, _, _) = bake(&old, &set_cookie).await;
+
+    // Assert
+    assert_eq!(unsigned_status, StatusCode::BAD_REQUEST);
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(body, json!({"cookies": 4, "pantry": {"flour": 5}}));
+    assert_eq!(tampered_status, StatusCode::UNAUTHORIZED);
+    // the updated cookie is signed with the new key only
+    assert_eq!(old_key_status, StatusCode::UNAUTHORIZED);
+}
+
+#[tokio::test]
+async fn day7_bake_encrypted_round_trip() {
+    // Arrange
+    let codec = RecipeCookieCodec::new(CookieFormat::Encrypted, &["secret".to_string()]).unwrap();
+    let other = RecipeCookieCodec::new(CookieFormat::Encrypted, &["other".to_string()]).unwrap();
+    let cookie = codec.encode(RECIPE.as_bytes()).unwrap();
+
+    // Act
+    let (status, set_cookie, body) = bake(&codec, &cookie).await;
+    let (second_status, _, second_body) = bake(&codec, &set_cookie.unwrap()).await;
+    let (wrong_key_status, _, _) = bake(&other, &cookie).await;
+
+    // Assert
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(body, json!({"cookies": 4, "pantry": {"flour": 5}}));
+    assert_eq!(second_status, StatusCode::OK);
+    assert_eq!(second_body, json!({"cookies": 0, "pantry": {"flour": 5}}));
+    assert_eq!(wrong_key_status, StatusCode::UNAUTHORIZED);
+}
+
+#[tokio::test]
+async fn day7_bake_with_units_and_big_numbers() {
+    // Arrange
+    let codec = RecipeCookieCodec::new(CookieFormat::Plain, &[]).unwrap();
+    let recipe = json!({
+        "recipe": {"flour": "250 g", "sugar": "0.5 cups", "eggs": 1, "chips": "0 g"},
+        "pantry": {"flour": "1.1 kg", "sugar": "2 cups", "eggs": "100000000000000000000000", "chips": "3 pieces"}
+    });
+    let cookie = codec.encode(recipe.to_string().as_bytes()).unwrap();
+    let mismatch = codec
+        .encode(br#"{"recipe":{"flour":"1 cup"},"pantry":{"flour":"3 pieces"}}"#)
+        .unwrap();
+
+    // Act
+    let response = router(codec.clone())
+        .oneshot(
+            Request::builder()
+                .uri("/7/bake")
+                .header(header::COOKIE, format!("recipe={cookie}"))
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+    let (mismatch_status, _, _) = bake(&codec, &mismatch).await;
+
+    // Assert
+    assert_eq!(response.status(), StatusCode::OK);
+    let body = response.into_body().collect().await.unwrap().to_bytes();
+    let body = String::from_utf8_lossy(&body[..]);
+    // way past u64, but still exact
+    assert!(body.contains(r#""eggs":99999999999999999999996"#));
+    let body: Value = serde_json::from_str(&body).unwrap();
+    assert_eq!(body["cookies"], json!(4));
+    assert_eq!(body["pantry"]["flour"], json!("0.1 kg"));
+    assert_eq!(body["pantry"]["sugar"], json!("0 cups"));
+    assert_eq!(body["pantry"]["chips"], json!("3 pieces"));
+    assert_eq!(mismatch_status, StatusCode::BAD_REQUEST);
+}
+
+#[tokio::test]
+async fn day7_plan_finds_best_mix() {
+    // Arrange
+    let app = router(RecipeCookieCodec::new(CookieFormat::Plain, &[]).unwrap());
+
+    // Act
+    let response = app
+        .oneshot(
+            Request::builder()
+                .uri("/7/plan")
+                .method(Method::POST)
+                .header(header::CONTENT_TYPE, mime::APPLICATION_JSON.as_ref())
+                .body(Body::from(
+                    json!({
+                        "recipes": [
+                            {"name": "shortbread", "recipe": {"flour": 3, "sugar": 1}, "value": 4},
+                            {"name": "macaron", "recipe": {"flour": 2, "sugar": 2}, "value": 3}
+                        ],
+                        "pantry": {"flour": 10, "sugar": 6, "butter": 2},
+                        "target": {"shortbread": 5}
+                    })
+                    .to_string(),
+                ))
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+
+    // Assert
+    assert_eq!(response.status(), StatusCode::OK);
+    let body = response.into_body().collect().await.unwrap().to_bytes();
+    let body: Value = serde_json::from_slice(&body).unwrap();
+    // baking as many shortbreads as possible first would only be worth 12
+    assert_eq!(
+        body,
+        json!({
+            "value": 14,
+            "optimal": true,
+            "cookies": {"shortbread": 2, "macaron": 2},
+            "pantry": {"flour": 0, "sugar": 0, "butter": 2},
+            "shopping_list": {"flour": 5}
+        })
+    );
+}
+
+async fn plan(body: Value) -> (StatusCode, Value) {
+    let response = router(RecipeCookieCodec::new(CookieFormat::Plain, &[]).unwrap())
+        .oneshot(
+            Request::builder()
+                .uri("/7/plan")
+                .method(Method::POST)
+                .header(header::CONTENT_TYPE, mime::APPLICATION_JSON.as_ref())
+                .body(Body::from(body.to_string()))
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+    let status = response.status();
+    let body = response.into_body().collect().await.unwrap().to_bytes();
+    (status, serde_json::from_slice(&body).unwrap_or(Value::Null))
+}
+
+#[tokio::test]
+async fn day7_plan_agrees_with_bake_on_units() {
+    // Arrange
+    let codec = RecipeCookieCodec::new(CookieFormat::Plain, &[]).unwrap();
+    let recipe = json!({"flour": "100 g", "sugar": "0.5 cups"});
+    let pantry = json!({"flour": "1.05 kg", "sugar": "2 cups", "butter": "1 kg"});
+    let cookie = codec
+        .encode(
+            json!({"recipe": recipe, "pantry": pantry})
+                .to_string()
+                .as_bytes(),
+        )
+        .unwrap();
+
+    // Act
+    let (_, _, baked) = bake(&codec, &cookie).await;
+    let (status, planned) = plan(json!({
+        "recipes": [{"name": "plain", "recipe": recipe}],
+        "pantry": pantry,
+        "target": {"plain": 6}
+    }))
+    .await;
+    let (mismatch_status, _) = plan(json!({
+        "recipes": [{"name": "chunky", "recipe": {"chips": "2 pieces"}}],
+        "pantry": {"chips": "10 g"}
+    }))
+    .await;
+
+    // Assert
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(planned["cookies"]["plain"], baked["cookies"]);
+    assert_eq!(planned["pantry"], baked["pantry"]);
+    assert_eq!(
+        planned["pantry"],
+        json!({"flour": "0.65 kg", "sugar": "0 cups", "butter": "1 kg"})
+    );
+    assert_eq!(planned["shopping_list"], json!({"sugar": "1 cups"}));
+    assert_eq!(mismatch_status, StatusCode::BAD_REQUEST);
+}
+
+#[test]
+fn day7_signed_cookies_need_a_key() {
+    // Act
+    let codec = RecipeCookieCodec::new(CookieFormat::Signed, &[String::new()]);
+
+    // Assert
+    assert_eq!(codec.unwrap_err(), MissingCookieKeys(CookieFormat::Signed));
+}
+
+#[tokio::test]
+async fn day7_plan_gives_up_on_a_huge_pantry() {
+    // Arrange
+    let app = router(RecipeCookieCodec::new(CookieFormat::Plain, &[]).unwrap());
+    let request = Request::builder()
+        .uri("/7/plan")
+        .method(Method::POST)
+        .header(header::CONTENT_TYPE, mime::APPLICATION_JSON.as_ref())
+        .body(Body::from(
+            json!({
+                "recipes": [{"name": "plain", "recipe": {"flour": 1}, "value": 1}],
+                "pantry": {"flour": 1_000_000_000_000_000_000_usize},
+                "target": {}
+            })
+            .to_string(),
+        ))
+        .unwrap();
+
+    // Act
+    let response = tokio::time::timeout(Duration::from_secs(10), app.oneshot(request))
+        .await
+        .expect("the plan search should stop at its node budget")
+        .unwrap();
+
+    // Assert
+    assert_eq!(response.status(), StatusCode::OK);
+    let body = response.into_body().collect().await.unwrap().to_bytes();
+    let body: Value = serde_json::from_slice(&body).unwrap();
+    assert_eq!(body["optimal"], json!(false));
+    assert_eq!(
+        body["cookies"]["plain"],
+        json!(1_000_000_000_000_000_000_usize)
+    );
+}
+
+#[tokio::test]
+async fn day7_bake_recipe_without_needs_keeps_usize_max() {
+    // Arrange
+    let codec = RecipeCookieCodec::new(CookieFormat::Plain, &[]).unwrap();
+    let cookie = codec
+        .encode(br#"{"recipe":{"flour":0,"sugar":0},"pantry":{"flour":5}}"#)
+        .unwrap();
+
+    // Act
+    let (status, _, body) = bake(&codec, &cookie).await;
+
+    // Assert
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(body, json!({"cookies": usize::MAX, "pantry": {"flour": 5}}));
+}
@@ -0,0 +1,240 @@
+use std::{
+    path::Path,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use async_trait::async_trait;
+use axum::{
+    body::Body,
+    http::{header, Method, Request, StatusCode},
+    Router,
+};
+use cch23_challenge::{
+    clock::{ManualClock, SystemClock},
+    handlers::day8::{
+        pokedex_client, router, CachedPokedexClient, HttpPokedexClient, PokedexClient,
+        PokedexError, Pokemon,
+    },
+};
+use chrono::Utc;
+use http_body_util::BodyExt;
+use serde_json::{json, Value};
+use tower::ServiceExt; // for `call`, `oneshot`, and `ready`
+
+fn app() -> Router {
+    router(
+        pokedex_client(
+            "http://localhost:0",
+            Some(Path::new("tests/fixtures/pokedex")),
+            Duration::from_secs(60),
+            Arc::new(SystemClock),
+        )
+        .unwrap(),
+    )
+}
+
+async fn get(app: Router, uri: &str) -> (StatusCode, String) {
+    let response = app
+        .oneshot(Request::builder().uri(uri).body(Body::empty()).unwrap())
+        .await
+        .unwrap();
+    let status = response.status();
+    let body = response.into_body().collect().await.unwrap().to_bytes();
+    (status, String::from_utf8_lossy(&body[..]).to_string())
+}
+
+#[tokio::test]
+async fn day8_weight_from_fixtures() {
+    // Act
+    let (status, body) = get(app(), "/8/weight/25").await;
+    let (missing_status, _) = get(app(), "/8/weight/9999").await;
+
+    // Assert
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(body, "6");
+    assert_eq!(missing_status, StatusCode::NOT_FOUND);
+}
+
+#[test]
+fn day8_missing_fixtures_are_an_error() {
+    // Act
+    let client = pokedex_client(
+        "http://localhost:0",
+        Some(Path::new("tests/fixtures/missing")),
+        Duration::from_secs(60),
+        Arc::new(SystemClock),
+    );
+
+    // Assert
+    assert!(client.is_err());
+}
+
+#[tokio::test]
+async fn day8_drop_from_fixtures() {
+    // Act
+    let (status, body) = get(app(), "/8/drop/25").await;
+
+    // Assert
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(body, "84.10707461325713");
+}
+
+struct CountingPokedex(Arc<AtomicUsize>);
+
+#[async_trait]
+impl PokedexClient for CountingPokedex {
+    async fn pokemon(&self, id_or_name: &str) -> Result<Pokemon, PokedexError> {
+        self.0.fetch_add(1, Ordering::SeqCst);
+        Ok(Pokemon {
+            id: id_or_name.parse().map_err(|_| PokedexError::NotFound)?,
+            name: "missingno".to_string(),
+            weight: 100,
+        })
+    }
+}
+
+#[tokio::test]
+async fn day8_lookups_are_cached() {
+    // Arrange
+    let calls = Arc::new(AtomicUsize::new(0));
+    let clock = Arc::new(ManualClock::new(Utc::now()));
+    let pokedex = Arc::new(
+        CachedPokedexClient::new(CountingPokedex(calls.clone()), Duration::from_secs(60))
+            .with_clock(clock.clone()),
+    );
+
+    // Act
+    for _ in 0..3 {
+        let (status, body) = get(router(pokedex.clone()), "/8/weight/151").await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body, "10");
+    }
+    let cached_calls = calls.load(Ordering::SeqCst);
+    clock.advance(chrono::Duration::seconds(60)).unwrap();
+    get(router(pokedex.clone()), "/8/weight/151").await;
+
+    // Assert
+    assert_eq!(cached_calls, 1);
+    // the ttl is up, so it is fetched again
+    assert_eq!(calls.load(Ordering::SeqCst), 2);
+}
+
+#[tokio::test]
+async fn day8_drop_physics_endpoints() {
+    // Act
+    let (_, velocity) = get(app(), "/8/drop/25/velocity?planet=moon&height=20").await;
+    let (_, time) = get(app(), "/8/drop/25/time?gravity=10&height=5").await;
+    let (_, energy) = get(
+        app(),
+        "/8/drop/25/energy?gravity=10&height=5&units=imperial",
+    )
+    .await;
+    let (bad_status, _) = get(app(), "/8/drop/25/time?gravity=-1").await;
+
+    // Assert
+    let velocity: Value = serde_json::from_str(&velocity).unwrap();
+    assert_eq!(
+        velocity,
+        json!({"value": (2.0f64 * 1.62 * 20.0).sqrt(), "unit": "m/s"})
+    );
+    let time: Value = serde_json::from_str(&time).unwrap();
+    assert_eq!(time, json!({"value": 1.0, "unit": "s"}));
+    let energy: Value = serde_json::from_str(&energy).unwrap();
+    assert_eq!(energy["unit"], "ft·lbf");
+    assert!((energy["value"].as_f64().unwrap() - 300.0 * 0.737_562_149).abs() < 1e-9);
+    assert_eq!(bad_status, StatusCode::BAD_REQUEST);
+}
+
+#[tokio::test]
+async fn day8_drop_with_drag_matches_closed_form() {
+    // Arrange
+    let (mass, g, h, k) = (6.0f64, 9.81f64, 100.0f64, 0.5 * 1.2 * 1.0 * 0.3);
+    let terminal = (mass * g / k).sqrt();
+    let expected_velocity = terminal * (1.0 - (-2.0 * g * h / terminal.powi(2)).exp()).sqrt();
+    let expected_time = terminal / g * (g * h / terminal.powi(2)).exp().acosh();
+    let query = "gravity=9.81&height=100&drag_coefficient=1&area=0.3&air_density=1.2";
+
+    // Act
+    let (_, velocity) = get(app(), &format!("/8/drop/25/velocity?{query}")).await;
+    let (_, time) = get(app(), &format!("/8/drop/25/time?{query}")).await;
+
+    // Assert
+    let velocity: Value = serde_json::from_str(&velocity).unwrap();
+    let time: Value = serde_json::from_str(&time).unwrap();
+    assert!((velocity["value"].as_f64().unwrap() - expected_velocity).abs() < 1e-3);
+    assert!((time["value"].as_f64().unwrap() - expected_time).abs() < 1e-3);
+    // drag slows it down compared to the vacuum
+    assert!(expected_velocity < (2.0 * g * h).sqrt());
+}
+
+#[tokio::test]
+async fn day8_batch_weights() {
+    // Arrange
+    let app = app();
+
+    // Act
+    let response = app
+        .oneshot(
+            Request::builder()
+                .uri("/8/weights")
+                .method(Method::POST)
+                .header(header::CONTENT_TYPE, mime::APPLICATION_JSON.as_ref())
+                .body(Body::from(r#"["Pikachu", 1, "missingno", 25]"#))
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+
+    // Assert
+    assert_eq!(response.status(), StatusCode::OK);
+    let body = response.into_body().collect().await.unwrap().to_bytes();
+    let body: Value = serde_json::from_slice(&body).unwrap();
+    let pikachu = json!({"id": 25, "name": "pikachu", "weight": 6.0});
+    let bulbasaur = json!({"id": 1, "name": "bulbasaur", "weight": 6.9});
+    assert_eq!(
+        body,
+        json!({
+            "results": [
+                {"query": "Pikachu", "pokemon": pikachu},
+                {"query": 1, "pokemon": bulbasaur},
+                {"query": "missingno", "error": {"kind": "not_found", "message": "pokemon not found"}},
+                {"query": 25, "pokemon": pikachu},
+            ],
+            "total": 18.9,
+            "heaviest": bulbasaur,
+            "lightest": pikachu,
+        })
+    );
+}
+
+#[tokio::test]
+async fn day8_http_client_keeps_names_in_one_segment_and_does_not_retry_client_errors() {
+    // Arrange
+    let paths = Arc::new(std::sync::Mutex::new(vec![]));
+    let upstream = Router::new().fallback({
+        let paths = paths.clone();
+        move |uri: axum::http::Uri| async move {
+            paths.lock().unwrap().push(uri.to_string());
+            StatusCode::BAD_REQUEST
+        }
+    });
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let base_url = format!("http://{}/api/v2/", listener.local_addr().unwrap());
+    tokio::spawn(async move { axum::serve(listener, upstream).await.unwrap() });
+    let client = HttpPokedexClient::new(&base_url).unwrap();
+
+    // Act
+    let traversal = client.pokemon("../../x").await;
+    let query = client.pokemon("a?b=c").await;
+    let rejected = client.pokemon("pikachu").await;
+
+    // Assert
+    assert_eq!(traversal, Err(PokedexError::NotFound));
+    assert_eq!(query, Err(PokedexError::NotFound));
+    assert!(matches!(rejected, Err(PokedexError::Upstream(_))));
+    assert_eq!(*paths.lock().unwrap(), ["/api/v2/pokemon/pikachu"]);
+}
@@ -0,0 +1,170 @@
+use axum::{
+    body::Body,
+    http::{header, Method, Request, StatusCode},
+    Router,
+};
+use cch23_challenge::handlers::{day13, tenants};
+use http_body_util::BodyExt;
+use serde_json::{json, Value};
+use sqlx::PgPool;
+use tower::ServiceExt; // for `call`, `oneshot`, and `ready`
+
+fn app(pool: PgPool, admin_token: Option<&str>) -> Router {
+    tenants::router(pool.clone(), admin_token.map(str::to_string)).merge(day13::router(pool))
+}
+
+/// Sends `body` as `tenant` when given, authorized with `token` when given.
+async fn send(
+    app: &Router,
+    method: Method,
+    uri: &str,
+    tenant: Option<&str>,
+    token: Option<&str>,
+    body: Value,
+) -> (StatusCode, Value) {
+    let mut request = Request::builder()
+        .method(method)
+        .uri(uri)
+        .header(header::CONTENT_TYPE, "application/json");
+    if let Some(tenant) = tenant {
+        request = request.header(tenants::TENANT_HEADER, tenant);
+    }
+    if let Some(token) = token {
+        request = request.header(header::AUTHORIZATION, format!("Bearer {token}"));
+    }
+    let response = app
+        .clone()
+        .oneshot(request.body(Body::from(body.to_string())).unwrap())
+        .await
+        .unwrap();
+    let status = response.status();
+    let body = response.into_body().collect().await.unwrap().to_bytes();
+    (status, serde_json::from_slice(&body).unwrap_or(Value::Null))
+}
+
+#[tokio::test]
+async fn tenants_rejects_names_that_cant_be_a_schema() {
+    // Arrange
+    let app = app(
+        PgPool::connect_lazy("postgres://localhost/unused").unwrap(),
+        Some("elf"),
+    );
+
+    // Act
+    let (created, _) = send(
+        &app,
+        Method::POST,
+        "/tenants/Robert');drop",
+        None,
+        Some("elf"),
+        Value::Null,
+    )
+    .await;
+    let (reset, _) = send(
+        &app,
+        Method::POST,
+        "/13/reset",
+        Some("../public"),
+        None,
+        Value::Null,
+    )
+    .await;
+
+    // Assert
+    assert_eq!(created, StatusCode::BAD_REQUEST);
+    assert_eq!(reset, StatusCode::BAD_REQUEST);
+}
+
+#[tokio::test]
+async fn tenants_need_the_admin_token_to_create_or_delete() {
+    // Arrange
+    let pool = PgPool::connect_lazy("postgres://localhost/unused").unwrap();
+    let guarded = app(pool.clone(), Some("elf"));
+    let unconfigured = app(pool, None);
+
+    // Act
+    let (created, _) = send(
+        &guarded,
+        Method::POST,
+        "/tenants/north",
+        None,
+        None,
+        Value::Null,
+    )
+    .await;
+    let (deleted, _) = send(
+        &guarded,
+        Method::DELETE,
+        "/tenants/north",
+        None,
+        Some("grinch"),
+        Value::Null,
+    )
+    .await;
+    let (without_token, _) = send(
+        &unconfigured,
+        Method::POST,
+        "/tenants/north",
+        None,
+        Some("elf"),
+        Value::Null,
+    )
+    .await;
+
+    // Assert
+    assert_eq!(created, StatusCode::UNAUTHORIZED);
+    assert_eq!(deleted, StatusCode::UNAUTHORIZED);
+    assert_eq!(without_token, StatusCode::FORBIDDEN);
+}
+
+#[sqlx::test]
+async fn tenants_sql_only_reads_its_own_schema(pool: PgPool) {
+    // Arrange
+    let app = app(pool, Some("elf"));
+    for tenant in ["north", "south"] {
+        let uri = format!("/tenants/{tenant}");
+        send(&app, Method::POST, &uri, None, Some("elf"), Value::Null).await;
+    }
+    let order = json!([{"id": 1, "region_id": 1, "gift_name": "Doll", "quantity": 2}]);
+    send(&app, Method::POST, "/13/orders", Some("north"), None, order).await;
+    let sql = |tenant: &'static str, query: &str| {
+        send(
+            &app,
+            Method::POST,
+            "/13/sql",
+            Some(tenant),
+            None,
+            json!({"query": query}),
+        )
+    };
+
+    // Act
+    let (_, north) = sql("north", "select id from orders").await;
+    let (_, south) = sql("south", "select count(*) from orders").await;
+    let (other, other_body) = sql("south", "select * from tenant_north.orders").await;
+    let (shared, shared_body) = sql("south", "select * from public.orders").await;
+    let (deleted, _) = send(
+        &app,
+        Method::DELETE,
+        "/tenants/north",
+        None,
+        Some("elf"),
+        Value::Null,
+    )
+    .await;
+
+    // Assert
+    assert_eq!(north["rows"], json!([[1]]));
+    assert_eq!(south["rows"], json!([[0]]));
+    assert_eq!(other, StatusCode::UNPROCESSABLE_ENTITY);
+    assert_eq!(
+        other_body["errors"][0]["message"],
+        json!("permission denied for schema tenant_north")
+    );
+    assert_eq!(shared, StatusCode::UNPROCESSABLE_ENTITY);
+    assert_eq!(
+        shared_body["errors"][0]["message"],
+        json!("permission denied for table orders")
+    );
+    assert_eq!(deleted, StatusCode::NO_CONTENT);
+}